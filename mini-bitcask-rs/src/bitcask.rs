@@ -9,6 +9,15 @@ use std::{
 const KEY_VAL_HEADER_LEN: u32 = 4;
 const MERGE_FILE_EXT: &str = "merge";
 
+// 文件头：魔数 + 格式版本，v1 的文件没有文件头
+const MAGIC: &[u8; 4] = b"MBCK";
+const FILE_HEADER_LEN: u64 = MAGIC.len() as u64 + 1;
+const FORMAT_V1: u8 = 1;
+const FORMAT_V2: u8 = 2;
+
+// v2 记录头里 flags 的各个位，其余的位留给以后的扩展
+const FLAG_TOMBSTONE: u8 = 1;
+
 type KeyDir = std::collections::BTreeMap<Vec<u8>, (u64, u32)>;
 
 pub type Result<T> = std::result::Result<T, std::io::Error>;
@@ -154,6 +163,8 @@ impl<'a> DoubleEndedIterator for ScanIterator<'a> {
 struct Log {
     path: PathBuf,
     file: std::fs::File,
+    // 文件的格式版本，旧的 v1 文件继续按 v1 追加，merge 之后变成 v2
+    version: u8,
 }
 
 impl Log {
@@ -171,31 +182,67 @@ impl Log {
         // 加 exclusive lock 防止并发更新
         file.try_lock_exclusive()?;
 
-        Ok(Self { path, file })
+        let mut log = Self { path, file, version: FORMAT_V2 };
+        log.version = log.read_file_header()?;
+        Ok(log)
+    }
+
+    // 空文件写入 v2 的文件头，已有的文件根据文件头判断版本
+    fn read_file_header(&mut self) -> Result<u8> {
+        let file_len = self.file.metadata()?.len();
+        let mut header = [0u8; FILE_HEADER_LEN as usize];
+        let n = file_len.min(FILE_HEADER_LEN) as usize;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut header[..n])?;
+
+        // 写文件头的时候崩溃只会留下文件头的前一部分，当作空文件重新写；
+        // v1 的文件以 key 的长度开头，不会正好是魔数的前缀
+        let v2_header = [&MAGIC[..], &[FORMAT_V2]].concat();
+        if n < FILE_HEADER_LEN as usize && header[..n] == v2_header[..n] {
+            self.file.set_len(0)?;
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.write_all(&v2_header)?;
+            return Ok(FORMAT_V2);
+        }
+        if n < FILE_HEADER_LEN as usize || &header[..MAGIC.len()] != MAGIC {
+            return Ok(FORMAT_V1);
+        }
+        match header[MAGIC.len()] {
+            FORMAT_V2 => Ok(FORMAT_V2),
+            version => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported log format version {}", version),
+            )),
+        }
+    }
+
+    // 第一条记录的位置
+    fn data_start(&self) -> u64 {
+        match self.version {
+            FORMAT_V1 => 0,
+            _ => FILE_HEADER_LEN,
+        }
     }
 
     // 构建内存索引
     fn load_index(&mut self) -> Result<KeyDir> {
-        let mut len_buf = [0u8; KEY_VAL_HEADER_LEN as usize];
         let mut keydir = KeyDir::new();
+        let version = self.version;
+        let data_start = self.data_start();
         let file_len = self.file.metadata()?.len();
         let mut r = BufReader::new(&mut self.file);
-        let mut pos: u64 = r.seek(SeekFrom::Start(0))?;
+        let mut pos: u64 = r.seek(SeekFrom::Start(data_start))?;
 
         while pos < file_len {
             let read_one = || -> Result<(Vec<u8>, u64, Option<u32>)> {
-                // 读取 key 的长度
-                r.read_exact(&mut len_buf)?;
-                let key_len = u32::from_be_bytes(len_buf);
-                // 读取 value 的长度
-                r.read_exact(&mut len_buf)?;
-                let value_lent_or_tombstone = match i32::from_be_bytes(len_buf) {
-                    l if l >= 0 => Some(l as u32),
-                    _ => None,
+                // 读取记录头，得到 key 的长度、value 的长度和记录头的长度
+                let (key_len, value_lent_or_tombstone, header_len) = match version {
+                    FORMAT_V1 => read_header_v1(&mut r)?,
+                    _ => read_header_v2(&mut r)?,
                 };
 
                 // value 的位置
-                let value_pos = pos + KEY_VAL_HEADER_LEN as u64 * 2 + key_len as u64;
+                let value_pos = pos + header_len + key_len as u64;
 
                 // 读取 key 的内容
                 let mut key = vec![0; key_len as usize];
@@ -233,21 +280,40 @@ impl Log {
         Ok(value)
     }
 
+    // v1:
     // +-------------+-------------+----------------+----------------+
-    // | key len(4)    val len(4)     key(varint)       val(varint)  |
+    // | key len(4)    val len(4)     key              val           |
     // +-------------+-------------+----------------+----------------+
+    // 删除时 val len 为 -1
+    //
+    // v2:
+    // +----------+------------------+------------------+-------+-------+
+    // | flags(1)   key len(varint)    val len(varint)    key     val   |
+    // +----------+------------------+------------------+-------+-------+
+    // 删除时 flags 带 FLAG_TOMBSTONE，没有 val len 和 val
     fn write_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
-        let key_len = key.len() as u32;
-        let value_len = value.map_or(0, |v| v.len() as u32);
-        let value_len_or_tomestone = value.map_or(-1, |v| v.len() as i32);
+        let mut header = Vec::with_capacity(KEY_VAL_HEADER_LEN as usize * 2);
+        match self.version {
+            FORMAT_V1 => {
+                header.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                header.extend_from_slice(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+            }
+            _ => {
+                header.push(if value.is_none() { FLAG_TOMBSTONE } else { 0 });
+                write_varint(&mut header, key.len() as u64);
+                if let Some(value) = value {
+                    write_varint(&mut header, value.len() as u64);
+                }
+            }
+        }
 
         // 总共占据的长度
-        let len = KEY_VAL_HEADER_LEN * 2 + key_len + value_len;
+        let value_len = value.map_or(0, |v| v.len() as u32);
+        let len = header.len() as u32 + key.len() as u32 + value_len;
 
         let offset = self.file.seek(SeekFrom::End(0))?;
         let mut w = BufWriter::with_capacity(len as usize, &mut self.file);
-        w.write_all(&key_len.to_be_bytes())?;
-        w.write_all(&value_len_or_tomestone.to_be_bytes())?;
+        w.write_all(&header)?;
         w.write_all(key)?;
         if let Some(value) = value {
             w.write_all(value)?;
@@ -258,9 +324,71 @@ impl Log {
     }
 }
 
+// 返回 (key 的长度, value 的长度或者删除, 记录头的长度)
+fn read_header_v1(r: &mut impl Read) -> Result<(u32, Option<u32>, u64)> {
+    let mut len_buf = [0u8; KEY_VAL_HEADER_LEN as usize];
+    // 读取 key 的长度
+    r.read_exact(&mut len_buf)?;
+    let key_len = u32::from_be_bytes(len_buf);
+    // 读取 value 的长度
+    r.read_exact(&mut len_buf)?;
+    let value_len = match i32::from_be_bytes(len_buf) {
+        l if l >= 0 => Some(l as u32),
+        _ => None,
+    };
+    Ok((key_len, value_len, KEY_VAL_HEADER_LEN as u64 * 2))
+}
+
+fn read_header_v2(r: &mut impl Read) -> Result<(u32, Option<u32>, u64)> {
+    let mut flags = [0u8; 1];
+    r.read_exact(&mut flags)?;
+    if flags[0] & !FLAG_TOMBSTONE != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unknown record flags {:#04x}", flags[0]),
+        ));
+    }
+    let (key_len, key_len_size) = read_varint(r)?;
+    let (value_len, value_len_size) = match flags[0] & FLAG_TOMBSTONE {
+        0 => {
+            let (len, size) = read_varint(r)?;
+            (Some(to_u32(len)?), size)
+        }
+        _ => (None, 0),
+    };
+    Ok((to_u32(key_len)?, value_len, 1 + key_len_size + value_len_size))
+}
+
+fn to_u32(n: u64) -> Result<u32> {
+    u32::try_from(n).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("length {} too large", n)))
+}
+
+// LEB128：每个字节低 7 位是数据，最高位表示后面还有没有
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+// 返回 (值, 占用的字节数)
+fn read_varint(r: &mut impl Read) -> Result<(u64, u64)> {
+    let mut n = 0u64;
+    let mut byte = [0u8; 1];
+    for i in 0..10 {
+        r.read_exact(&mut byte)?;
+        n |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok((n, i + 1));
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "varint too long"))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Log, MiniBitcask, Result};
+    use super::{read_varint, write_varint, Log, MiniBitcask, Result, FORMAT_V2};
     use std::ops::Bound;
 
     #[test]
@@ -311,6 +439,96 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_log_format_v2() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("sqldb-disk-engine-log-test3")
+            .join("log");
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        let mut log = Log::new(path.clone())?;
+        // 文件头 5 字节，小 key 的记录头只要 3 字节
+        let (offset, len) = log.write_entry(b"a", Some(b"val1"))?;
+        assert_eq!((offset, len), (5, 3 + 1 + 4));
+        let (_, len) = log.write_entry(b"b", None)?;
+        assert_eq!(len, 2 + 1);
+        // 超过 127 字节的长度需要两个字节
+        log.write_entry(b"c", Some(&[7; 300]))?;
+        drop(log);
+
+        let mut log = Log::new(path.clone())?;
+        let keydir = log.load_index()?;
+        assert_eq!(2, keydir.len());
+        let (value_pos, value_len) = keydir[&b"c".to_vec()];
+        assert_eq!(log.read_value(value_pos, value_len)?, vec![7; 300]);
+
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            assert_eq!(read_varint(&mut buf.as_slice())?, (n, buf.len() as u64));
+        }
+
+        // 写文件头的时候崩溃，只留下一部分文件头，重新打开时当作空文件
+        drop(log);
+        for torn in [&b"M"[..], b"MBC", b"MBCK"] {
+            std::fs::write(&path, torn)?;
+            let mut log = Log::new(path.clone())?;
+            assert_eq!(log.version, FORMAT_V2);
+            assert!(log.load_index()?.is_empty());
+            let (offset, _) = log.write_entry(b"a", Some(b"val1"))?;
+            assert_eq!(offset, 5);
+        }
+
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_format_v1() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("sqldb-disk-engine-log-test4")
+            .join("log");
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        // 旧版本写的文件：没有文件头，长度是两个 4 字节的大端整数
+        let mut v1 = Vec::new();
+        for (key, value) in [(&b"a"[..], Some(&b"val1"[..])), (b"b", Some(b"val2")), (b"a", None)] {
+            v1.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            v1.extend_from_slice(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+            v1.extend_from_slice(key);
+            v1.extend_from_slice(value.unwrap_or_default());
+        }
+        std::fs::write(&path, &v1)?;
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"a")?, None);
+        assert_eq!(eng.get(b"b")?, Some(b"val2".to_vec()));
+        // 继续按 v1 追加
+        eng.set(b"c", b"val3".to_vec())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), v1.len() as u64 + 8 + 1 + 4);
+
+        // merge 之后是 v2
+        eng.merge()?;
+        assert_eq!(&std::fs::read(&path)?[..5], b"MBCK\x02");
+        drop(eng);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"b")?, Some(b"val2".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"val3".to_vec()));
+
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        Ok(())
+    }
+
     // 测试点读的情况
     #[test]
     fn test_point_opt() -> Result<()> {
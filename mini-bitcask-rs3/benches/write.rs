@@ -0,0 +1,34 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mini_bitcask_rs3::bitcask::{MiniBitcask, Options};
+use mini_bitcask_rs3::log::SyncPolicy;
+
+const KEYS: usize = 1000;
+
+fn bench_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_1000x100b");
+    for (name, sync_policy) in [("buffered", SyncPolicy::Buffered), ("write_through", SyncPolicy::WriteThrough)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let dir = tempfile::tempdir().unwrap();
+                    let options = Options { sync_policy, ..Default::default() };
+                    let db = MiniBitcask::open(dir.path().join("log"), options).unwrap();
+                    (dir, db)
+                },
+                |(dir, mut db)| {
+                    for i in 0..KEYS {
+                        db.set(format!("key{}", i).as_bytes(), vec![b'v'; 100]).unwrap();
+                    }
+                    // 一起算上最后落盘的时间
+                    db.sync().unwrap();
+                    (dir, db)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_set);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+// 根据 src/ffi.rs 生成 C 头文件，写到 OUT_DIR 里，不改动源码目录
+// 提交的 include/bitcask.h 由 tests/ffi.rs 检查是否和生成的一致
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).expect("invalid cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_crate(&dir)
+        .with_config(config)
+        .generate()
+        .expect("unable to generate C bindings")
+        .write_to_file(format!("{}/bitcask.h", out_dir));
+}
@@ -0,0 +1,164 @@
+/*
+ * mini-bitcask 的 C 接口
+ *
+ * 所有权：
+ * - bitcask_get 返回的 value 归调用方，用 bitcask_free_buffer 释放；
+ * - bitcask_iter_next 返回的 key 和 value 归迭代器，下一次 bitcask_iter_next 或 bitcask_iter_free 之后失效；
+ * - bitcask_last_error 返回的字符串归库所有，同一个线程上下一次调用 bitcask_* 之后失效。
+ */
+
+#ifndef MINI_BITCASK_H
+#define MINI_BITCASK_H
+
+/* 由 cbindgen 根据 src/ffi.rs 生成，不要手改 */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * 返回值，BITCASK_STATUS_ERROR 时用 `bitcask_last_error` 取错误信息
+ */
+typedef enum BitcaskStatus {
+  BITCASK_STATUS_OK = 0,
+  /**
+   * key 不存在，或者迭代器已经到头
+   */
+  BITCASK_STATUS_NOT_FOUND = 1,
+  /**
+   * 空指针、路径不是 UTF-8 之类的参数错误
+   */
+  BITCASK_STATUS_INVALID_ARGUMENT = 2,
+  BITCASK_STATUS_ERROR = 3,
+} BitcaskStatus;
+
+/**
+ * 打开的数据库，只能通过指针使用
+ */
+typedef struct Bitcask Bitcask;
+
+/**
+ * `bitcask_iter` 返回的迭代器
+ */
+typedef struct BitcaskIter BitcaskIter;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * 打开 path 上的数据库，成功时把句柄写到 out
+ *
+ * # Safety
+ * path 是以 \0 结尾的字符串，out 指向可写的指针。
+ */
+enum BitcaskStatus bitcask_open(const char *path, struct Bitcask **out);
+
+/**
+ * 刷盘并关闭数据库，之后 db 不能再用，刷盘失败时也会关闭
+ *
+ * # Safety
+ * db 是 `bitcask_open` 返回的句柄，或者空指针。
+ */
+enum BitcaskStatus bitcask_close(struct Bitcask *db);
+
+/**
+ * 写入 key，value_len 为 0 时 value 可以是空指针
+ *
+ * # Safety
+ * db 是打开的句柄，key、value 分别指向 key_len、value_len 个字节。
+ */
+enum BitcaskStatus bitcask_put(struct Bitcask *db,
+                               const uint8_t *key,
+                               size_t key_len,
+                               const uint8_t *value,
+                               size_t value_len);
+
+/**
+ * 读取 key，存在时把 value 写到 value_out / value_len_out 并返回 BITCASK_STATUS_OK，
+ * 不存在时返回 BITCASK_STATUS_NOT_FOUND
+ *
+ * value 归调用方，用 `bitcask_free_buffer` 释放。
+ *
+ * # Safety
+ * db 是打开的句柄，key 指向 key_len 个字节，value_out 和 value_len_out 可写。
+ */
+enum BitcaskStatus bitcask_get(struct Bitcask *db,
+                               const uint8_t *key,
+                               size_t key_len,
+                               uint8_t **value_out,
+                               size_t *value_len_out);
+
+/**
+ * 释放 `bitcask_get` 返回的 value，len 要和返回的长度一致
+ *
+ * # Safety
+ * data 和 len 是 `bitcask_get` 返回的，并且没有释放过；data 为空指针时什么都不做。
+ */
+void bitcask_free_buffer(uint8_t *data,
+                         size_t len);
+
+/**
+ * 删除 key，key 不存在时也返回 BITCASK_STATUS_OK
+ *
+ * # Safety
+ * db 是打开的句柄，key 指向 key_len 个字节。
+ */
+enum BitcaskStatus bitcask_delete(struct Bitcask *db, const uint8_t *key, size_t key_len);
+
+/**
+ * 压缩日志
+ *
+ * # Safety
+ * db 是打开的句柄。
+ */
+enum BitcaskStatus bitcask_merge(struct Bitcask *db);
+
+/**
+ * 按 key 的顺序遍历以 prefix 开头的 key，prefix_len 为 0 时遍历所有 key
+ *
+ * 迭代器是创建时的快照，用 `bitcask_iter_free` 释放。创建时复制 prefix 范围内的索引项（key 和位置），
+ * 代价和范围里 key 的数量成正比，prefix 为空时是整个索引。
+ *
+ * # Safety
+ * db 是打开的句柄，prefix 指向 prefix_len 个字节，out 可写。
+ */
+enum BitcaskStatus bitcask_iter(struct Bitcask *db,
+                                const uint8_t *prefix,
+                                size_t prefix_len,
+                                struct BitcaskIter **out);
+
+/**
+ * 取下一个 key 和 value，没有了返回 BITCASK_STATUS_NOT_FOUND
+ *
+ * 返回的 key 和 value 归迭代器，下一次调用之前有效。
+ *
+ * # Safety
+ * iter 是 `bitcask_iter` 返回的迭代器，四个输出参数都可写。
+ */
+enum BitcaskStatus bitcask_iter_next(struct BitcaskIter *iter,
+                                     const uint8_t **key_out,
+                                     size_t *key_len_out,
+                                     const uint8_t **value_out,
+                                     size_t *value_len_out);
+
+/**
+ * 释放迭代器
+ *
+ * # Safety
+ * iter 是 `bitcask_iter` 返回的迭代器并且没有释放过，或者空指针。
+ */
+void bitcask_iter_free(struct BitcaskIter *iter);
+
+/**
+ * 当前线程上最近一次调用失败的原因，成功时返回空指针
+ */
+const char *bitcask_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MINI_BITCASK_H */
@@ -0,0 +1,24 @@
+use mini_bitcask_rs3::convert::{convert, Format};
+use std::path::PathBuf;
+
+// 在各个 crate 的磁盘格式之间转换，只保留还有效的 key
+//   convert [--from <format>] <src> <dst> <format>
+// format 是 v1（rs）、v2、rs2 或 rs3，不指定 --from 时根据源文件的内容判断。
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut from = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--from") {
+        if i + 1 >= args.len() {
+            anyhow::bail!("missing format after --from");
+        }
+        from = Some(args.remove(i + 1).parse::<Format>()?);
+        args.remove(i);
+    }
+    let [src, dst, format] = <[String; 3]>::try_from(args)
+        .map_err(|_| anyhow::anyhow!("usage: convert [--from <format>] <src> <dst> <v1|v2|rs2|rs3>"))?;
+
+    let count = convert(&PathBuf::from(src), from, &PathBuf::from(&dst), format.parse()?)?;
+    println!("converted {} keys to {}", count, dst);
+    Ok(())
+}
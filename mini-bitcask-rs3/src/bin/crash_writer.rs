@@ -0,0 +1,50 @@
+use mini_bitcask_rs3::bitcask::MiniBitcask;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+// 崩溃测试用的写入进程：按种子生成随机的写入，每一步在 ack 文件里记下
+//   begin <op>   开始执行
+//   ok           已经执行并且 sync 完成
+// 测试用 SIGKILL 杀掉这个进程之后，根据 ack 文件检查数据。
+// op 是 `set <key> <value>`、`del <key>` 或者 `merge`。
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = PathBuf::from(args.next().expect("missing path argument"));
+    let ack_path = PathBuf::from(args.next().expect("missing ack path argument"));
+    let seed: u64 = args.next().expect("missing seed argument").parse()?;
+
+    let mut db = MiniBitcask::new(path)?;
+    let mut ack = OpenOptions::new().create(true).append(true).open(ack_path)?;
+    let mut rng = seed.max(1);
+    loop {
+        let op = match next(&mut rng) % 100 {
+            0..=1 => "merge".to_string(),
+            2..=21 => format!("del key{}", next(&mut rng) % 64),
+            _ => {
+                let len = next(&mut rng) as usize % 4096 + 1;
+                let value = (0..len).map(|_| (b'a' + (next(&mut rng) % 26) as u8) as char).collect::<String>();
+                format!("set key{} {}", next(&mut rng) % 64, value)
+            }
+        };
+        // 整行一次写进去，进程被杀的时候不会留下半行
+        ack.write_all(format!("begin {}\n", op).as_bytes())?;
+        let mut parts = op.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("set"), Some(key), Some(value)) => db.set(key.as_bytes(), value.as_bytes().to_vec())?,
+            (Some("del"), Some(key), None) => db.delete(key.as_bytes())?,
+            _ => db.merge()?,
+        }
+        db.sync()?;
+        ack.write_all(b"ok\n")?;
+    }
+}
+
+fn next(state: &mut u64) -> u64 {
+    // xorshift64
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
@@ -6,7 +6,7 @@ fn main() {
     let path = std::env::args().nth(1).expect("missing path argument");
     let path = PathBuf::from(path);
 
-    let file = match OpenOptions::new().read(true).write(true).create(true).open(&path) {
+    let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
         Ok(f) => f,
         Err(e) => {
             eprintln!("lock_probe: failed to open file: {}", e);
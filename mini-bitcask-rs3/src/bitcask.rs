@@ -1,40 +1,298 @@
-use crate::log::Log;
+use crate::compression::Compression;
+use crate::crypto::Encryption;
+use crate::history::{self, AsOf, History, Record, Retention, Version};
+use crate::index::{prefix_range, IndexIter, IndexType, KeyRange, Position};
+use crate::log::{now_millis, Log, LogIter, SyncPolicy, FLAG_BATCH, FLAG_MERGE_MARK, FLAG_META, FLAG_OPERAND, VALUE_FLAGS};
+use crate::merge_operator::{push_operand, resolve, MergeOperator, PendingOperands};
+use crate::namespace::{self, Namespace, CATALOG_ID, ESCAPED_ID, NAMESPACE_PREFIX};
 use crate::log::KeyDir;
+use crate::lru::LruCache;
+use crate::snapshot::Snapshot;
+use crate::structures::{self, STRUCTURES_ID};
+use crate::storage::{FsStorage, Storage};
+use crate::watch::{Event, Watchers};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::ops::RangeBounds;
 use std::path::PathBuf;
-use anyhow::Result;
-use std::collections::btree_map;
-use std::ops::Bound;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use anyhow::{bail, Result};
+use ::log::{debug, error};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub index_type: IndexType,
+    /// 每个 watch 订阅者最多缓存多少个事件
+    pub watch_buffer: usize,
+    /// merge_value 用的合并函数，没有配置时不能调用 merge_value
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// 加密新写入的文件，已经加密的文件必须提供同一个密钥才能打开
+    pub encryption: Option<Encryption>,
+    /// 日志文件的存储后端，默认是真实的文件系统
+    pub storage: Arc<dyn Storage>,
+    /// 写入什么时候落到文件里，默认每条记录都直接写到文件，`SyncPolicy::Buffered` 需要显式打开
+    pub sync_policy: SyncPolicy,
+    /// 写缓冲区的大小
+    pub write_buffer_size: usize,
+    /// 读缓存最多缓存多少字节的 value，0 表示不缓存
+    pub read_cache_size: usize,
+    /// 开启历史版本：记录每个 key 的旧版本和写入时间，merge 按 retention 保留旧版本
+    pub history: Option<Retention>,
+    /// 新写入的 value 用什么压缩，压缩和不压缩的记录可以混在一个文件里
+    pub compression: Compression,
+    /// 小于这个大小的 value 不压缩
+    pub compression_threshold: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            index_type: IndexType::default(),
+            watch_buffer: 1024,
+            merge_operator: None,
+            encryption: None,
+            storage: Arc::new(FsStorage),
+            sync_policy: SyncPolicy::default(),
+            write_buffer_size: 64 * 1024,
+            read_cache_size: 0,
+            history: None,
+            compression: Compression::None,
+            compression_threshold: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub keys: usize,
+    /// 索引占用的内存（估算值）
+    pub index_bytes: usize,
+    /// 读缓存的命中和未命中次数，没有开启读缓存时都是 0
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// 打开以来（merge 之后是 merge 以来）写入的 value 压缩前和压缩后的总字节数
+    pub value_bytes: u64,
+    pub stored_value_bytes: u64,
+}
+
+impl Stats {
+    pub fn index_bytes_per_key(&self) -> usize {
+        self.index_bytes.checked_div(self.keys).unwrap_or(0)
+    }
+
+    /// 压缩后和压缩前的大小之比，越小压缩效果越好，没有写入时是 1
+    pub fn compression_ratio(&self) -> f64 {
+        match self.value_bytes {
+            0 => 1.0,
+            n => self.stored_value_bytes as f64 / n as f64,
+        }
+    }
+}
+
+/// 按 value 在文件里的位置缓存读出来（已经解密）的值
+///
+/// 日志只追加，同一个位置的内容不会变，只有 merge 和 truncate 之后位置会被重新使用。
+#[derive(Debug)]
+struct ReadCache {
+    lru: Option<LruCache<u64, Vec<u8>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lru: (capacity > 0).then(|| LruCache::new(capacity)),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// 记一次命中或者未命中，没有开启缓存时返回 None 不计数
+    fn lookup(&mut self, value_pos: u64) -> Option<Vec<u8>> {
+        let lru = self.lru.as_mut()?;
+        let value = lru.get(&value_pos);
+        match value {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        value
+    }
+
+    fn insert(&mut self, value_pos: u64, value: &[u8]) {
+        if let Some(lru) = &mut self.lru {
+            lru.insert(value_pos, value.to_vec(), value.len());
+        }
+    }
+
+    fn read(&mut self, log: &mut Log, pos: Position) -> Result<Vec<u8>> {
+        let value_pos = pos.0;
+        if let Some(value) = self.lookup(value_pos) {
+            return Ok(value);
+        }
+        let value = log.read_value(pos)?;
+        self.insert(value_pos, &value);
+        Ok(value)
+    }
+
+    /// key 被覆盖或者删除之后，旧的值不会再被读到
+    fn invalidate(&mut self, pos: Option<Position>) {
+        if let (Some(lru), Some((value_pos, ..))) = (&mut self.lru, pos) {
+            lru.remove(&value_pos);
+        }
+    }
+
+    fn clear(&mut self) {
+        if let Some(lru) = &mut self.lru {
+            lru.clear();
+        }
+    }
+}
+
 pub struct MiniBitcask {
     log: Log,
     index: KeyDir, // key -> (value_pos, value_len)
+    pending: PendingOperands,
+    options: Options,
+    watchers: Watchers,
+    /// merge 的次数，merge 之后日志里的位置都会变
+    pub(crate) merges: u64,
+    /// namespace 的 name -> id
+    namespaces: HashMap<String, u32>,
+    cache: ReadCache,
+    /// 开启历史版本时每个 key 的所有版本
+    history: Option<History>,
+    /// 已经调用过 close，drop 时不用再刷盘
+    closed: bool,
 }
 
 impl Drop for MiniBitcask {
     fn drop(&mut self) {
-        println!("drop bitcask");
-        if let Err(e) = self.flush() {
-            eprintln!("error flushing bitcask: {}", e);
+        if self.closed {
+            return;
+        }
+        // drop 里没法返回错误，想知道数据有没有写进去要调用 close
+        if let Err(e) = self.sync() {
+            error!("error flushing bitcask, call close() to handle it: {}", e);
         }
     }
 }
 
 impl MiniBitcask {
     pub fn new(path: PathBuf) -> Result<Self> {
-        let mut log = Log::new(path)?;
-        let index = log.load_index()?;
-        Ok(Self { log, index })
+        Self::open(path, Options::default())
     }
-    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        let (offset, len) = self.log.write_entry(key, Some(&value))?;
-        let value_len = value.len() as u32;
-        println!("[set] offset: {}, value_len: {}", offset, value_len);
-        self.index.insert(key.to_vec(), (offset+len as u64 -value_len as u64, value_len));
+
+    pub fn open(path: PathBuf, options: Options) -> Result<Self> {
+        let mut log = Log::open(options.storage.as_ref(), path)?;
+        log.sync_policy = options.sync_policy;
+        log.write_buffer_size = options.write_buffer_size;
+        log.timestamps = options.history.is_some();
+        log.compression = options.compression;
+        log.compression_threshold = options.compression_threshold;
+        log.init_encryption(options.encryption.as_ref())?;
+        let mut index = options.index_type.open_indexer(&log.path)?;
+        let mut pending = PendingOperands::new();
+        // 磁盘索引上次 close 时落了盘，只用读 checkpoint 之后的日志
+        let checkpoint = index.take_checkpoint();
+        let resumed = match &checkpoint {
+            Some(checkpoint) => log.resume_index(index.as_mut(), &mut pending, checkpoint)?,
+            None => false,
+        };
+        if !resumed {
+            if checkpoint.is_some() {
+                debug!("disk index does not match the log, rebuilding it");
+                drop(index);
+                index = options.index_type.new_indexer(&log.path)?;
+            }
+            log.load_index(index.as_mut(), &mut pending)?;
+        }
+        // 上次没有正常关闭，末尾之前的记录也可能坏了，先全部读一遍
+        if !log.clean {
+            log.validate()?;
+        }
+        let history = match options.history {
+            Some(_) => Some(History::load(&mut log)?),
+            None => None,
+        };
+        let watchers = Watchers::new(options.watch_buffer);
+        let cache = ReadCache::new(options.read_cache_size);
+        let mut db = Self {
+            log,
+            index,
+            pending,
+            options,
+            watchers,
+            merges: 0,
+            namespaces: HashMap::new(),
+            cache,
+            history,
+            closed: false,
+        };
+        db.migrate_reserved_keys()?;
+        db.load_namespaces()?;
+        Ok(db)
+    }
+
+    /// 以前默认 namespace 里可以直接写以保留前缀开头的 key，打开时把它们挪到转义之后的位置
+    ///
+    /// 目录里没有 id 计数器说明这个库还没有创建过 namespace，保留前缀下除了数据结构和已经转义的 key
+    /// 都是这样的旧 key。挪完之后写上计数器，以后不会再挪；中途崩溃的话下次打开接着挪。
+    fn migrate_reserved_keys(&mut self) -> Result<()> {
+        let counter_key = namespace::key_prefix(CATALOG_ID);
+        if self.index.get(&counter_key)?.is_some() {
+            return Ok(());
+        }
+        let mut legacy = Vec::new();
+        for item in self.index.range(namespace::reserved_range()) {
+            let (key, _) = item?;
+            if !matches!(namespace::namespace_id(&key), Some(ESCAPED_ID | STRUCTURES_ID)) {
+                legacy.push(key);
+            }
+        }
+        if legacy.is_empty() {
+            return Ok(());
+        }
+        for key in &legacy {
+            let value = self.get_raw(key)?.unwrap_or_default();
+            self.set_raw(&namespace::encode_key(ESCAPED_ID, key), value)?;
+            self.delete_raw(key)?;
+        }
+        self.set_raw(&counter_key, (CATALOG_ID + 1).to_be_bytes().to_vec())?;
+        debug!("migrated {} keys with the reserved prefix", legacy.len());
         Ok(())
     }
+
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.set_raw(&namespace::escape_key(key), value)
+    }
     pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        match self.index.get(key) {
-            Some((offset, len)) => {
-                let value = self.log.read_value(*offset, *len)?;
+        self.get_raw(&namespace::escape_key(key))
+    }
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.delete_raw(&namespace::escape_key(key))
+    }
+
+    pub(crate) fn set_raw(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let pos = self.log.write_entry(key, Some(&value))?;
+        debug!("[set] value_pos: {}, value_len: {}", pos.0, pos.1);
+        let prev = self.index.put(key.to_vec(), pos)?;
+        self.invalidate(key, prev);
+        self.record_version(key, Some(pos), false);
+        self.notify_put(key, &value);
+        Ok(())
+    }
+    pub(crate) fn get_raw(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key)? {
+            Some(pos) => {
+                let value = resolve(
+                    self.pending.get(key),
+                    self.options.merge_operator.as_deref(),
+                    key,
+                    pos,
+                    |pos| self.cache.read(&mut self.log, pos),
+                )?;
                 Ok(Some(value))
             }
             None => {
@@ -42,65 +300,751 @@ impl MiniBitcask {
             }
         }
     }
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.log.write_entry(key, None)?;
-        self.index.remove(key);
+    /// 从 reader 里读 len 个字节作为 key 的值，不需要把整个 value 放进内存
+    ///
+    /// value 按块带上 CRC32，读的时候逐块校验。reader 提前结束时返回错误，不会留下写了一半的记录。
+    pub fn set_from_reader(&mut self, key: &[u8], mut reader: impl Read, len: u64) -> Result<()> {
+        let user_key = key;
+        let key = &*namespace::escape_key(user_key);
+        let pos = self.log.write_entry_from_reader(key, &mut reader, len)?;
+        let prev = self.index.put(key.to_vec(), pos)?;
+        self.invalidate(key, prev);
+        self.record_version(key, Some(pos), false);
+        // 有人订阅时才需要把值读回来
+        if self.watchers.is_watched(user_key) {
+            let value = self.log.read_value(pos)?;
+            self.watchers.notify_put(user_key, &value);
+        } else {
+            self.watchers.wake();
+        }
         Ok(())
     }
-    
-    fn flush(&mut self) -> Result<()> {
-        Ok(self.log.file.sync_all()?)
+
+    /// 把 key 的值写到 writer，返回写了多少字节，key 不存在时返回 None
+    pub fn get_to_writer(&mut self, key: &[u8], mut writer: impl Write) -> Result<Option<u64>> {
+        self.get_range_to(key, 0, u64::MAX, &mut writer)
+    }
+
+    /// 读 key 的值里 [offset, offset + len) 这一段，超出 value 末尾的部分忽略
+    pub fn get_range(&mut self, key: &[u8], offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(self.get_range_to(key, offset, len, &mut value)?.map(|_| value))
+    }
+
+    fn get_range_to(&mut self, key: &[u8], offset: u64, len: u64, writer: &mut dyn Write) -> Result<Option<u64>> {
+        let key = &*namespace::escape_key(key);
+        let Some(pos) = self.index.get(key)? else {
+            return Ok(None);
+        };
+        if self.pending.contains_key(key) {
+            // operand 要合并之后才知道值，只能整个读出来
+            let value = self.get_raw(key)?.unwrap_or_default();
+            let start = offset.min(value.len() as u64) as usize;
+            let end = offset.saturating_add(len).min(value.len() as u64) as usize;
+            writer.write_all(&value[start..end])?;
+            return Ok(Some((end - start) as u64));
+        }
+        Ok(Some(self.log.read_value_to(pos, offset, len, writer)?))
+    }
+
+    /// 一次读多个 key，结果和 keys 的顺序一致
+    ///
+    /// 先查完索引，再按文件里的位置从小到大读，挨得近的 value 合并成一次读。
+    pub fn multi_get(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut results = vec![None; keys.len()];
+        let mut to_read = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = &*namespace::escape_key(key);
+            let Some(pos) = self.index.get(key)? else {
+                continue;
+            };
+            if self.pending.contains_key(key) {
+                // 有 operand 的 key 要读好几个位置再合并，单独读
+                results[i] = self.get_raw(key)?;
+            } else if let Some(value) = self.cache.lookup(pos.0) {
+                results[i] = Some(value);
+            } else {
+                to_read.push((i, pos));
+            }
+        }
+        let positions = to_read.iter().map(|&(_, pos)| pos).collect::<Vec<_>>();
+        let values = self.log.read_values(&positions)?;
+        for ((i, (value_pos, ..)), value) in to_read.into_iter().zip(values) {
+            self.cache.insert(value_pos, &value);
+            results[i] = Some(value);
+        }
+        Ok(results)
+    }
+
+    pub(crate) fn delete_raw(&mut self, key: &[u8]) -> Result<()> {
+        let (value_pos, ..) = self.log.write_entry(key, None)?;
+        let prev = self.index.delete(key)?;
+        self.invalidate(key, prev);
+        self.record_version_at(key, value_pos, None, false);
+        self.notify_delete(key);
+        Ok(())
+    }
+
+    /// 一组写入，要么全部生效要么全部不生效，value 为 None 的是删除；数据结构的成员和元数据一起写
+    pub(crate) fn write_batch(&mut self, batch: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<()> {
+        let first_seq = self.log.next_seq;
+        let entries = batch.iter().map(|(key, value)| (key.as_slice(), value.as_deref())).collect::<Vec<_>>();
+        let positions = self.log.write_batch(&entries)?;
+        let timestamp = self.log.last_write().1;
+        for (i, ((key, value), pos)) in batch.into_iter().zip(positions).enumerate() {
+            let prev = match value {
+                Some(_) => self.index.put(key.clone(), pos)?,
+                None => self.index.delete(&key)?,
+            };
+            self.invalidate(&key, prev);
+            if let Some(history) = &mut self.history {
+                let seq = first_seq + i as u64;
+                history.push(&key, Record { seq, timestamp, value_pos: pos.0, pos: value.is_some().then_some(pos), operand: false });
+            }
+            match value {
+                Some(value) => self.notify_put(&key, &value),
+                None => self.notify_delete(&key),
+            }
+        }
+        Ok(())
+    }
+
+    /// 只追加一条 operand，不读原来的值，get/scan 的时候用 merge operator 合并
+    pub fn merge_value(&mut self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        if self.options.merge_operator.is_none() {
+            bail!("merge_value requires a merge operator in Options");
+        }
+        let key = &*namespace::escape_key(key);
+        let seq = self.log.next_seq;
+        self.write_operand(key, &operand, seq)?;
+        self.notify_merge(key, &operand);
+        Ok(())
+    }
+
+    /// 默认 namespace 的订阅者只看到自己的 key，其他 namespace 和数据结构内部的写入只唤醒复制
+    fn notify_put(&mut self, key: &[u8], value: &[u8]) {
+        match namespace::unescape_key(key) {
+            Some(key) => self.watchers.notify_put(key, value),
+            None => self.watchers.wake(),
+        }
+    }
+
+    fn notify_delete(&mut self, key: &[u8]) {
+        match namespace::unescape_key(key) {
+            Some(key) => self.watchers.notify_delete(key),
+            None => self.watchers.wake(),
+        }
+    }
+
+    fn notify_merge(&mut self, key: &[u8], operand: &[u8]) {
+        match namespace::unescape_key(key) {
+            Some(key) => self.watchers.notify_merge(key, operand),
+            None => self.watchers.wake(),
+        }
+    }
+
+    /// key 的旧值（包括还没合并的 operand）不会再被读到
+    fn invalidate(&mut self, key: &[u8], prev: Option<Position>) {
+        self.cache.invalidate(prev);
+        if let Some(pending) = self.pending.remove(key) {
+            self.cache.invalidate(pending.base);
+            for pos in pending.operands {
+                self.cache.invalidate(Some(pos));
+            }
+        }
+    }
+
+    /// 开启历史版本时记下刚写入的记录
+    fn record_version(&mut self, key: &[u8], pos: Option<Position>, operand: bool) {
+        if let Some((value_pos, ..)) = pos {
+            self.record_version_at(key, value_pos, pos, operand);
+        }
+    }
+
+    fn record_version_at(&mut self, key: &[u8], value_pos: u64, pos: Option<Position>, operand: bool) {
+        if let Some(history) = &mut self.history {
+            let (seq, timestamp) = self.log.last_write();
+            history.push(key, Record { seq, timestamp, value_pos, pos, operand });
+        }
+    }
+
+    fn history(&self) -> Result<&History> {
+        match &self.history {
+            Some(history) => Ok(history),
+            None => bail!("history is not enabled, set Options::history to keep old versions"),
+        }
+    }
+
+    /// key 还保留着的所有版本，从旧到新，最后一个是当前的值
+    pub fn get_versions(&mut self, key: &[u8]) -> Result<Vec<Version>> {
+        let key = &*namespace::escape_key(key);
+        let records = self.history()?.records(key).to_vec();
+        let operator = self.options.merge_operator.clone();
+        (0..records.len())
+            .map(|i| {
+                let value = history::value_at(&records, i, operator.as_deref(), key, |pos| self.log.read_value(pos))?;
+                Ok(Version {
+                    seq: records[i].seq,
+                    timestamp: records[i].timestamp.map(history::to_system_time),
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// key 在某个 seq 或者某个时间点的值，那时还不存在、已经删除或者那个版本已经被 merge 掉时返回 None
+    pub fn get_as_of(&mut self, key: &[u8], as_of: AsOf) -> Result<Option<Vec<u8>>> {
+        let key = &*namespace::escape_key(key);
+        let records = self.history()?.records(key);
+        let found = match as_of {
+            AsOf::Seq(seq) => records.iter().rposition(|r| r.seq <= seq),
+            AsOf::Time(time) => {
+                let millis = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+                // 没有写入时间的旧记录当作比任何时间都早
+                records.iter().rposition(|r| r.timestamp.is_none_or(|t| t <= millis))
+            }
+        };
+        let Some(i) = found else {
+            return Ok(None);
+        };
+        let records = records.to_vec();
+        let operator = self.options.merge_operator.clone();
+        history::value_at(&records, i, operator.as_deref(), key, |pos| self.log.read_value(pos))
+    }
+
+    fn write_operand(&mut self, key: &[u8], operand: &[u8], seq: u64) -> Result<()> {
+        let pos = self.log.write_entry_with(key, Some(operand), seq, FLAG_OPERAND)?;
+        let prev = self.index.put(key.to_vec(), pos)?;
+        push_operand(&mut self.pending, key, prev, pos);
+        self.record_version(key, Some(pos), true);
+        Ok(())
     }
 
-    pub fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> ScanIter<'_> {
+    /// 当前值等于 expected 时才写入 new，None 表示不存在/删除，返回条件是否成立
+    ///
+    /// 所有写入都经过 &mut self，检查和写入之间不会插进别的写。
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<Vec<u8>>) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            // 本来就不存在的 key 不用再写一条删除
+            None if expected.is_some() => self.delete(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// key 不存在时才写入，返回是否写入了
+    pub fn put_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// 把 value 当作 8 字节大端的 i64 加上 delta，不存在的 key 从 0 开始，返回新值
+    pub fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let current = match self.get(key)? {
+            Some(value) => match <[u8; 8]>::try_from(value.as_slice()) {
+                Ok(bytes) => i64::from_be_bytes(bytes),
+                Err(_) => bail!("value of key {:?} is not an i64 counter", key),
+            },
+            None => 0,
+        };
+        let Some(new) = current.checked_add(delta) else {
+            bail!("counter {:?} overflow: {} + {}", key, current, delta);
+        };
+        self.set(key, new.to_be_bytes().to_vec())?;
+        Ok(new)
+    }
+
+    /// 订阅 key 以 prefix 开头的修改，事件在写入成功之后发出
+    ///
+    /// 订阅者处理不过来时不会阻塞写入，多出来的事件会被丢弃，之后收到一个 `Event::Overflow`。
+    pub fn watch(&mut self, prefix: &[u8]) -> Receiver<Event> {
+        self.watchers.subscribe(prefix)
+    }
+
+    /// 有新的写入时收到通知，不复制 key 和 value，用于复制这类只需要被唤醒的场景
+    pub(crate) fn watch_writes(&mut self) -> Receiver<()> {
+        self.watchers.subscribe_wakeup()
+    }
+
+    /// 默认 namespace 的统计，各个 namespace 的见 `Namespace::stats`
+    pub fn stats(&self) -> Stats {
+        let total = self.index_stats();
+        let keys = total.keys - self.count_range(namespace::reserved_range()) + self.count_range(namespace::id_range(ESCAPED_ID));
+        Stats {
+            keys,
+            index_bytes: (total.index_bytes * keys).checked_div(total.keys).unwrap_or(0),
+            ..total
+        }
+    }
+
+    /// 整个索引的统计，包括所有 namespace
+    pub fn index_stats(&self) -> Stats {
+        Stats {
+            keys: self.index.len(),
+            index_bytes: self.index.memory_usage(),
+            cache_hits: self.cache.hits,
+            cache_misses: self.cache.misses,
+            value_bytes: self.log.value_bytes().0,
+            stored_value_bytes: self.log.value_bytes().1,
+        }
+    }
+
+    pub(crate) fn count_range(&self, range: KeyRange) -> usize {
+        self.index.range(range).count()
+    }
+
+    /// 打开名为 name 的 namespace，不存在时创建
+    pub fn namespace(&mut self, name: &str) -> Result<Namespace<'_>> {
+        if name.is_empty() {
+            bail!("namespace name must not be empty");
+        }
+        let id = match self.namespaces.get(name) {
+            Some(id) => *id,
+            None => {
+                // 目录里空 name 存的是下一个 id，删掉的 id 不会再被用到
+                let counter_key = namespace::key_prefix(CATALOG_ID);
+                let id = match self.get_raw(&counter_key)? {
+                    Some(value) => u32::from_be_bytes(value.as_slice().try_into()?),
+                    None => CATALOG_ID + 1,
+                };
+                // 最后两个 id 留给转义的 key 和数据结构
+                if id >= ESCAPED_ID {
+                    bail!("too many namespaces");
+                }
+                let next = id + 1;
+                self.set_raw(&counter_key, next.to_be_bytes().to_vec())?;
+                self.set_raw(&namespace::encode_key(CATALOG_ID, name.as_bytes()), id.to_be_bytes().to_vec())?;
+                self.namespaces.insert(name.to_string(), id);
+                id
+            }
+        };
+        Ok(Namespace { db: self, name: name.to_string(), id })
+    }
+
+    /// 删除整个 namespace，只写一条目录的删除记录，数据在下次 merge 时清理
+    pub fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        if self.namespaces.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.delete_raw(&namespace::encode_key(CATALOG_ID, name.as_bytes()))?;
+        Ok(true)
+    }
+
+    pub fn namespace_names(&self) -> Vec<String> {
+        let mut names = self.namespaces.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn load_namespaces(&mut self) -> Result<()> {
+        let prefix_len = namespace::key_prefix(CATALOG_ID).len();
+        let mut namespaces = HashMap::new();
+        for item in self.scan_raw(namespace::id_range(CATALOG_ID), false) {
+            let (key, value) = item?;
+            if key.len() == prefix_len {
+                continue;
+            }
+            let name = String::from_utf8(key[prefix_len..].to_vec())?;
+            namespaces.insert(name, u32::from_be_bytes(value.as_slice().try_into()?));
+        }
+        self.namespaces = namespaces;
+        Ok(())
+    }
+
+    /// 把已经写入的数据刷到磁盘，返回之后即使断电也不会丢
+    pub fn sync(&mut self) -> Result<()> {
+        self.log.sync()
+    }
+
+    /// 刷盘并写上正常关闭的标记，出错时返回错误而不是像 drop 那样只打日志。
+    /// 文件锁在最后一个引用日志文件的 snapshot 也释放之后才会放开
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.log.close()?;
+        let checkpoint = self.log.checkpoint(&self.pending)?;
+        self.index.persist(&checkpoint)?;
+        self.options.storage.sync_dir(self.log.dir())
+    }
+
+    pub fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> ScanIter<'_> {
+        self.scan_raw(namespace::escape_range(range), true)
+    }
+
+    /// 以 prefix 开头的所有 key，组合 key 的前缀可以用 `keycode::prefix` 构造
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> ScanIter<'_> {
+        self.scan_raw(prefix_range(&namespace::escape_key(prefix)), true)
+    }
+
+    /// hide_namespaces 为 true 时跳过 namespace 里的 key，转义过的 key 还原回去
+    pub(crate) fn scan_raw(&mut self, range: KeyRange, hide_namespaces: bool) -> ScanIter<'_> {
         ScanIter {
             inner: self.index.range(range),
             log: &mut self.log,
+            pending: &self.pending,
+            operator: self.options.merge_operator.as_deref(),
+            hide_namespaces,
         }
     }
+    /// 当前数据的只读快照，不借用 self，持有期间可以继续写入
+    ///
+    /// 创建时会复制一份索引（key 和位置，不含 value），代价和 key 的数量成正比。
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        self.snapshot_raw((std::ops::Bound::Unbounded, std::ops::Bound::Unbounded), true)
+    }
+
+    /// 只复制 range 里的索引项；hide_namespaces 为 false 时快照里也有 namespace 和数据结构的 key，复制给 follower 时用
+    pub(crate) fn snapshot_raw(&mut self, range: KeyRange, hide_namespaces: bool) -> Result<Snapshot> {
+        // 快照和日志共用同一个文件句柄，读都是按位置读的，缓冲区里的记录要先写到文件
+        self.log.flush()?;
+        let reader = self.log.file.clone();
+        let mut entries = self.index.range(range).collect::<Result<Vec<_>>>()?;
+        // 只复制有 operand 的 key，compaction 之后这部分会清空
+        let mut pending = self.pending.clone();
+        if hide_namespaces {
+            // 快照里直接放默认 namespace 看到的 key，转义前后顺序不变
+            entries.retain(|(key, _)| namespace::unescape_key(key).is_some());
+            entries.iter_mut().for_each(|(key, _)| unescape_in_place(key));
+            pending = pending
+                .into_iter()
+                .filter_map(|(key, p)| namespace::unescape_key(&key).map(|key| (key.to_vec(), p)))
+                .collect();
+        }
+        Ok(Snapshot::new(
+            reader,
+            self.log.cipher.clone(),
+            entries,
+            pending,
+            self.options.merge_operator.clone(),
+            self.last_seq(),
+        ))
+    }
+
     pub fn merge(&mut self) -> Result<()> {
-        let mut merge_path = self.log.path.clone();
-        merge_path.set_extension("merge");
+        // unix 上 rename 之后旧文件还能通过打开的句柄读到，其他平台上不能替换快照还在读的文件
+        if !cfg!(unix) && Arc::strong_count(&self.log.file) > 1 {
+            bail!("cannot merge while snapshots are alive");
+        }
+        let mut new_log = self.create_log("merge")?;
+        let mut new_index = self.options.index_type.new_indexer(&new_log.path)?;
+        // 先把有 operand 的 key 合并成完整的值，写在最后一个 operand 的位置
+        let mut folded = HashMap::new();
+        // 开启历史版本时按 retention 保留旧版本，留下的 operand 版本都合并成完整的值
+        let retained = match (&self.history, self.options.history) {
+            (Some(history), Some(retention)) => {
+                let retained = history.retained(retention, now_millis());
+                for (key, records) in &history.keys {
+                    for (i, record) in records.iter().enumerate() {
+                        let Some(pos) = record.pos.filter(|_| record.operand && retained.contains(&record.value_pos)) else {
+                            continue;
+                        };
+                        let value = history::value_at(records, i, self.options.merge_operator.as_deref(), key, |pos| {
+                            self.log.read_value(pos)
+                        })?;
+                        folded.insert(pos, value.unwrap_or_default());
+                    }
+                }
+                Some(retained)
+            }
+            _ => {
+                for (key, pending) in &self.pending {
+                    let Some(pos) = pending.operands.last() else {
+                        continue;
+                    };
+                    let value = resolve(Some(pending), self.options.merge_operator.as_deref(), key, *pos, |pos| {
+                        self.log.read_value(pos)
+                    })?;
+                    folded.insert(*pos, value);
+                }
+                None
+            }
+        };
+        // 已经删除的 namespace 的数据在这里丢掉
+        let live = self.namespaces.values().copied().chain([CATALOG_ID, ESCAPED_ID, STRUCTURES_ID]).collect::<HashSet<_>>();
+        // 已经删除的数据结构的成员也在这里丢掉
+        let structures = structures::live_versions(self)?;
+        // 顺序扫描旧文件，只保留索引还指向的记录（或者 retention 要留下的旧版本），seq 和写入时间不变
+        for item in self.log.iter(true)? {
+            let (entry, value) = item?;
+            if entry.flags & (FLAG_META | FLAG_MERGE_MARK) != 0 {
+                continue;
+            }
+            if namespace::namespace_id(&entry.key).is_some_and(|id| !live.contains(&id)) {
+                continue;
+            }
+            if structures::is_stale(&structures, &entry.key) {
+                continue;
+            }
+            let keep = match (&retained, entry.value_len) {
+                (Some(retained), _) => retained.contains(&entry.value_pos),
+                (None, Some(value_len)) => self.index.get(&entry.key)? == Some((entry.value_pos, value_len, entry.flags & VALUE_FLAGS)),
+                (None, None) => false,
+            };
+            if !keep {
+                continue;
+            }
+            // 一组记录里只留下了一部分，复制过去之后各自独立
+            let flags = entry.flags & !(FLAG_OPERAND | FLAG_BATCH);
+            match (entry.value_len, value) {
+                (Some(value_len), Some(value)) => {
+                    let value = folded.remove(&(entry.value_pos, value_len, entry.flags & VALUE_FLAGS)).unwrap_or(value);
+                    let pos = new_log.write_entry_at(&entry.key, Some(&value), entry.seq, flags, entry.timestamp)?;
+                    new_index.put(entry.key, pos)?;
+                }
+                // 只有保留历史版本时才会留下删除记录
+                _ => {
+                    new_log.write_entry_at(&entry.key, None, entry.seq, flags, entry.timestamp)?;
+                    new_index.delete(&entry.key)?;
+                }
+            }
+        }
+        // 记下被压缩掉的历史到哪个 seq 为止
+        let compacted_seq = self.log.next_seq - 1;
+        new_log.write_entry_with(&[], None, compacted_seq, FLAG_MERGE_MARK)?;
+        new_log.compacted_seq = compacted_seq;
+        self.replace_log(new_log, new_index)?;
+        self.pending.clear();
+        // 新文件里的位置和原来的不一样
+        self.cache.clear();
+        if self.history.is_some() {
+            self.history = Some(History::load(&mut self.log)?);
+        }
+        self.merges += 1;
+        Ok(())
+    }
+
+    /// merge 的同时换成新的密钥，None 表示之后写明文，也可以用来加密原来的明文数据
+    pub fn merge_with_encryption(&mut self, encryption: Option<Encryption>) -> Result<()> {
+        let old = std::mem::replace(&mut self.options.encryption, encryption);
+        let result = self.merge();
+        if result.is_err() {
+            self.options.encryption = old;
+        }
+        result
+    }
+
+    /// 最后一条写入的 seq
+    pub fn last_seq(&self) -> u64 {
+        self.log.next_seq - 1
+    }
+
+    /// merge 压缩掉的历史到哪个 seq 为止
+    pub fn compacted_seq(&self) -> u64 {
+        self.log.compacted_seq
+    }
+
+    /// 按顺序返回 seq 大于 `seq` 的所有修改
+    ///
+    /// 只能读到还没有被 merge 掉的部分，`seq` 比 merge 的位置还早时返回错误。
+    pub fn changes_since(&mut self, seq: u64) -> Result<ChangeIter<'_>> {
+        self.changes_from(seq, 0)
+    }
 
-        let mut new_log = Log::new(merge_path)?;
-        let mut new_index = KeyDir::new();
-        for (key, (value_pos, value_len)) in self.index.iter() {
-            let value = self.log.read_value(*value_pos, *value_len)?;
-            let (offset, len) = new_log.write_entry(key, Some(&value))?;
-            new_index.insert(key.clone(), (offset + len as u64 - *value_len as u64, *value_len));
+    /// 和 changes_since 一样，但是从日志的 pos 位置开始读，pos 要是之前 ChangeIter::position 返回的值
+    pub(crate) fn changes_from(&mut self, seq: u64, pos: u64) -> Result<ChangeIter<'_>> {
+        if seq < self.log.compacted_seq {
+            bail!(
+                "changes since seq {} have been compacted by merge, the oldest available seq is {}",
+                seq,
+                self.log.compacted_seq
+            );
+        }
+        Ok(ChangeIter {
+            inner: self.log.iter_from(pos, seq, true)?,
+            since: seq,
+        })
+    }
+
+    /// 按 change 原来的 seq 写入，用于 follower 回放 primary 的修改
+    pub(crate) fn apply(&mut self, change: &Change) -> Result<()> {
+        match change {
+            Change::Put { seq, key, value } => {
+                let pos = self.log.write_entry_with(key, Some(value), *seq, 0)?;
+                let prev = self.index.put(key.clone(), pos)?;
+                self.invalidate(key, prev);
+                self.record_version(key, Some(pos), false);
+                self.notify_put(key, value);
+            }
+            Change::Delete { seq, key } => {
+                let (value_pos, ..) = self.log.write_entry_with(key, None, *seq, 0)?;
+                let prev = self.index.delete(key)?;
+                self.invalidate(key, prev);
+                self.record_version_at(key, value_pos, None, false);
+                self.notify_delete(key);
+            }
+            Change::Merge { seq, key, operand } => {
+                self.write_operand(key, operand, *seq)?;
+                self.notify_merge(key, operand);
+            }
         }
-        std::fs::rename(new_log.path, self.log.path.clone())?;
+        // primary 上创建或者删除了 namespace
+        if namespace::namespace_id(change.key()) == Some(CATALOG_ID) {
+            self.load_namespaces()?;
+        }
+        Ok(())
+    }
+
+    /// 在日志旁边创建一个空的新日志，扩展名是 extension，按当前的设置写入
+    fn create_log(&self, extension: &str) -> Result<Log> {
+        let mut path = self.log.path.clone();
+        path.set_extension(extension);
+        let mut log = Log::open(self.options.storage.as_ref(), path)?;
+        // 上次中途失败留下的文件
+        log.truncate()?;
+        log.sync_policy = self.options.sync_policy;
+        log.write_buffer_size = self.options.write_buffer_size;
+        log.timestamps = self.log.timestamps;
+        log.compression = self.options.compression;
+        log.compression_threshold = self.options.compression_threshold;
+        log.init_encryption(self.options.encryption.as_ref())?;
+        Ok(log)
+    }
+
+    /// 用 create_log 创建的新日志和它的索引替换当前的，快照手里的句柄还指向旧文件
+    fn replace_log(&mut self, mut new_log: Log, mut new_index: KeyDir) -> Result<()> {
+        // 先落盘再替换，否则掉电之后可能留下一个不完整的日志
+        new_log.sync()?;
+        self.options.storage.rename(&new_log.path, &self.log.path)?;
         new_log.path = self.log.path.clone();
         self.log = new_log;
+        // 索引还没有 persist 过，挪到一半掉电的话下次打开会重建
+        new_index.rename(&self.log.path)?;
         self.index = new_index;
         Ok(())
     }
 
+    /// 清空所有数据，follower 重新同步快照之前调用
+    ///
+    /// 换成一个新的空文件而不是截断原来的文件，已有的快照还能读原来的数据。
+    pub(crate) fn truncate(&mut self) -> Result<()> {
+        if !cfg!(unix) && Arc::strong_count(&self.log.file) > 1 {
+            bail!("cannot truncate while snapshots are alive");
+        }
+        let new_log = self.create_log("truncate")?;
+        let new_index = self.options.index_type.new_indexer(&new_log.path)?;
+        self.replace_log(new_log, new_index)?;
+        self.pending.clear();
+        self.namespaces.clear();
+        self.cache.clear();
+        if self.history.is_some() {
+            self.history = Some(History::default());
+        }
+        self.merges += 1;
+        Ok(())
+    }
+
+    /// 快照已经完整写入，之后从 seq 继续同步，更早的历史视为已经被 merge 掉
+    pub(crate) fn finish_snapshot(&mut self, seq: u64) -> Result<()> {
+        self.log.write_entry_with(&[], None, seq, FLAG_MERGE_MARK)?;
+        self.log.compacted_seq = seq;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Put { seq: u64, key: Vec<u8>, value: Vec<u8> },
+    Delete { seq: u64, key: Vec<u8> },
+    /// merge_value 写入的 operand
+    Merge { seq: u64, key: Vec<u8>, operand: Vec<u8> },
+}
+
+impl Change {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Change::Put { seq, .. } | Change::Delete { seq, .. } | Change::Merge { seq, .. } => *seq,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Change::Put { key, .. } | Change::Delete { key, .. } | Change::Merge { key, .. } => key,
+        }
+    }
+}
+
+pub struct ChangeIter<'a> {
+    inner: LogIter<'a>,
+    since: u64,
+}
+
+impl ChangeIter<'_> {
+    /// 已经读到的日志位置，可以传给 changes_from 接着读
+    pub(crate) fn position(&self) -> u64 {
+        self.inner.position()
+    }
+}
+
+impl Iterator for ChangeIter<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for item in self.inner.by_ref() {
+            let (entry, value) = match item {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            if entry.seq <= self.since || entry.flags & (FLAG_MERGE_MARK | FLAG_META) != 0 {
+                continue;
+            }
+            return Some(Ok(match value {
+                Some(operand) if entry.flags & FLAG_OPERAND != 0 => Change::Merge { seq: entry.seq, key: entry.key, operand },
+                Some(value) => Change::Put { seq: entry.seq, key: entry.key, value },
+                None => Change::Delete { seq: entry.seq, key: entry.key },
+            }));
+        }
+        None
+    }
+}
+
+/// 转义过的 key 去掉前面的 namespace 前缀，其他 key 不变
+fn unescape_in_place(key: &mut Vec<u8>) {
+    if namespace::namespace_id(key) == Some(ESCAPED_ID) {
+        key.drain(..NAMESPACE_PREFIX.len() + 4);
+    }
 }
 
 pub struct ScanIter<'a> {
-    inner: btree_map::Range<'a, Vec<u8>, (u64, u32)>,
+    inner: IndexIter<'a>,
     log: &'a mut Log,
+    pending: &'a PendingOperands,
+    operator: Option<&'a dyn MergeOperator>,
+    hide_namespaces: bool,
 }
 
 impl<'a> ScanIter<'a> {
-    fn map(&mut self, item: (&Vec<u8>, &(u64, u32))) -> <Self as Iterator>::Item {
-        let (key, (offset, len)) = item;
-        let value = self.log.read_value(*offset, *len)?;
-        Ok((key.clone(), value))
+    fn hidden(hide_namespaces: bool, item: &Result<(Vec<u8>, Position)>) -> bool {
+        hide_namespaces && matches!(item, Ok((key, _)) if namespace::unescape_key(key).is_none())
+    }
+
+    fn map(&mut self, item: Result<(Vec<u8>, Position)>) -> <Self as Iterator>::Item {
+        let (mut key, pos) = item?;
+        let value = resolve(self.pending.get(&key), self.operator, &key, pos, |pos| {
+            self.log.read_value(pos)
+        })?;
+        if self.hide_namespaces {
+            unescape_in_place(&mut key);
+        }
+        Ok((key, value))
     }
 }
 
 impl<'a> Iterator for ScanIter<'a> {
     type Item = Result<(Vec<u8>, Vec<u8>)>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|item| self.map(item))
+        let hide = self.hide_namespaces;
+        let item = self.inner.by_ref().find(|item| !Self::hidden(hide, item))?;
+        Some(self.map(item))
     }
 }
 
 impl<'a> DoubleEndedIterator for ScanIter<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.inner.next_back().map(|item| self.map(item))
+        let hide = self.hide_namespaces;
+        let item = self.inner.by_ref().rfind(|item| !Self::hidden(hide, item))?;
+        Some(self.map(item))
     }
 }
 
@@ -108,6 +1052,10 @@ impl<'a> DoubleEndedIterator for ScanIter<'a> {
 mod tests {
 
     use super::*;
+    use crate::disk_index::{self, DiskIndexOptions};
+    use crate::storage::{FaultyStorage, Faults, MemoryStorage};
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
     use std::sync::{Arc, Mutex};
     use std::thread;
 
@@ -242,6 +1190,194 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_index_types() -> Result<()> {
+        let disk = IndexType::Disk(DiskIndexOptions {
+            page_size: 64,
+            cache_pages: 2,
+            memtable_keys: 2,
+            fanout: 2,
+        });
+        for index_type in [IndexType::BTree, IndexType::Hash, IndexType::SkipList, IndexType::Compact, disk] {
+            let tmp_dir = tempfile::TempDir::new_in(".")?;
+            let path = tmp_dir.path().join("test.db");
+            let options = Options { index_type, ..Default::default() };
+            let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+            eng.set(b"b", b"value2".to_vec())?;
+            eng.set(b"a", b"value1".to_vec())?;
+            eng.set(b"c", b"value3".to_vec())?;
+            eng.delete(b"b")?;
+            eng.merge()?;
+            drop(eng);
+
+            let mut eng = MiniBitcask::open(path, options)?;
+            assert_eq!(eng.get(b"a")?, Some(b"value1".to_vec()));
+            assert_eq!(eng.get(b"b")?, None);
+            let keys = eng
+                .scan(..)
+                .map(|r| r.map(|(k, _)| k))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(keys, [b"a".to_vec(), b"c".to_vec()]);
+
+            let stats = eng.stats();
+            assert_eq!(stats.keys, 2);
+            assert!(stats.index_bytes_per_key() > 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_index_reopen() -> Result<()> {
+        use crate::merge_operator::AppendOperator;
+
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let path = tmp_dir.path().join("test.db");
+        let manifest = disk_index::index_dir(&path).join("MANIFEST");
+        let options = Options {
+            index_type: IndexType::Disk(DiskIndexOptions {
+                page_size: 64,
+                cache_pages: 2,
+                memtable_keys: 4,
+                fanout: 2,
+            }),
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Default::default()
+        };
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        for i in 0..50u8 {
+            eng.set(format!("key{:02}", i).as_bytes(), vec![i])?;
+        }
+        eng.delete(b"key07")?;
+        eng.merge_value(b"list", b"a".to_vec())?;
+        eng.merge_value(b"list", b"b".to_vec())?;
+        eng.close()?;
+        assert!(manifest.exists());
+
+        // 从 checkpoint 接着读，索引没有重建，operand 也恢复了
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert!(manifest.exists());
+        assert_eq!(eng.get(b"key08")?, Some(vec![8]));
+        assert_eq!(eng.get(b"key07")?, None);
+        assert_eq!(eng.get(b"list")?, Some(b"ab".to_vec()));
+        assert_eq!(eng.stats().keys, 50);
+        // 修改之后 MANIFEST 就作废了，没有 close 的话下次从头重建
+        eng.set(b"key07", vec![70])?;
+        assert!(!manifest.exists());
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.get(b"key07")?, Some(vec![70]));
+        eng.close()?;
+
+        // 别的索引只在后面追加了记录，checkpoint 还能用，原来的文件都留着
+        let run_files = || -> Result<Vec<_>> {
+            let mut names = std::fs::read_dir(disk_index::index_dir(&path))?
+                .map(|e| e.map(|e| e.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+        let persisted = run_files()?;
+        let btree = Options { index_type: IndexType::BTree, ..options.clone() };
+        let mut eng = MiniBitcask::open(path.clone(), btree.clone())?;
+        eng.set(b"key99", vec![99])?;
+        eng.close()?;
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.get(b"key99")?, Some(vec![99]));
+        assert_eq!(run_files()?, persisted.into_iter().filter(|n| n != "MANIFEST").collect::<Vec<_>>());
+        eng.close()?;
+
+        // merge 换掉了日志，checkpoint 对不上，从头重建
+        let mut eng = MiniBitcask::open(path.clone(), btree)?;
+        eng.delete(b"key00")?;
+        eng.merge()?;
+        eng.close()?;
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert!(!manifest.exists());
+        assert_eq!(eng.get(b"key00")?, None);
+        assert_eq!(eng.get(b"list")?, Some(b"ab".to_vec()));
+        assert_eq!(eng.stats().keys, 51);
+
+        // 磁盘索引自己 merge：新的索引跟着日志换过去
+        eng.merge()?;
+        eng.close()?;
+        assert!(!disk_index::index_dir(&path.with_extension("merge")).exists());
+        let mut eng = MiniBitcask::open(path, options)?;
+        assert!(manifest.exists());
+        assert_eq!(eng.get(b"key01")?, Some(vec![1]));
+        assert_eq!(eng.get(b"key99")?, Some(vec![99]));
+        assert_eq!(eng.stats().keys, 51);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let path = tmp_dir.path().join("test.db");
+        let mut eng = MiniBitcask::new(path)?;
+        let rx = eng.watch(b"config/");
+
+        eng.set(b"config/timeout", b"30".to_vec())?;
+        eng.set(b"data/1", b"x".to_vec())?;
+        eng.delete(b"config/timeout")?;
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                Event::Put { key: b"config/timeout".to_vec(), value: b"30".to_vec() },
+                Event::Delete { key: b"config/timeout".to_vec() },
+            ]
+        );
+
+        // 订阅者在别的线程里消费
+        let handle = std::thread::spawn(move || rx.recv());
+        eng.set(b"config/retry", b"3".to_vec())?;
+        assert_eq!(
+            handle.join().unwrap()?,
+            Event::Put { key: b"config/retry".to_vec(), value: b"3".to_vec() }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_since() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let path = tmp_dir.path().join("test.db");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        eng.delete(b"a")?;
+        assert_eq!(eng.last_seq(), 3);
+
+        let changes = eng.changes_since(1)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            changes,
+            [
+                Change::Put { seq: 2, key: b"b".to_vec(), value: b"2".to_vec() },
+                Change::Delete { seq: 3, key: b"a".to_vec() },
+            ]
+        );
+
+        // merge 之后 seq 保留，之前的历史不能再读
+        eng.merge()?;
+        eng.set(b"c", b"3".to_vec())?;
+        assert_eq!(eng.last_seq(), 4);
+        assert!(eng.changes_since(2).is_err());
+        let changes = eng.changes_since(3)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(changes, [Change::Put { seq: 4, key: b"c".to_vec(), value: b"3".to_vec() }]);
+
+        // 重新打开之后 seq 接着增长
+        drop(eng);
+        let mut eng = MiniBitcask::new(path)?;
+        assert_eq!(eng.last_seq(), 4);
+        assert!(eng.changes_since(0).is_err());
+        eng.set(b"d", b"4".to_vec())?;
+        let seqs = eng.changes_since(3)?.map(|c| c.map(|c| c.seq())).collect::<Result<Vec<_>>>()?;
+        assert_eq!(seqs, [4, 5]);
+        assert_eq!(eng.get(b"b")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
     // 测试扫描
     #[test]
     fn test_scan() -> Result<()> {
@@ -280,7 +1416,7 @@ mod tests {
         let (key5, _) = iter2.next_back().expect("no value founded")?;
         assert_eq!(key5, b"meeae".to_vec());
 
-        path.parent().map(|p| std::fs::remove_dir_all(p));
+        path.parent().map(std::fs::remove_dir_all);
         Ok(())
     }
     #[test]
@@ -313,7 +1449,837 @@ mod tests {
         let val = eng.get(b"c")?;
         assert_eq!(b"value3".to_vec(), val.unwrap());
 
-        path.parent().map(|p| std::fs::remove_dir_all(p));
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-snapshot-test")
+            .join("log");
+        path.parent().map(std::fs::remove_dir_all);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        eng.set(b"c", b"3".to_vec())?;
+
+        let snap = eng.snapshot()?;
+        assert_eq!(snap.seq(), 3);
+
+        // 快照之后的写入、删除和 merge 都看不到
+        eng.set(b"a", b"10".to_vec())?;
+        eng.delete(b"b")?;
+        eng.set(b"d", b"4".to_vec())?;
+        eng.merge()?;
+        eng.set(b"e", b"5".to_vec())?;
+
+        assert_eq!(snap.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(snap.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(snap.get(b"d")?, None);
+        assert_eq!(
+            snap.iter().collect::<Result<Vec<_>>>()?,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec()),
+            ]
+        );
+        let (key, _) = snap.scan(b"b".to_vec()..).next_back().expect("no value founded")?;
+        assert_eq!(key, b"c".to_vec());
+
+        // 迭代器是 owned 的，可以放到别的线程里读，同时继续写
+        let iter = eng.snapshot()?.iter();
+        let handle = std::thread::spawn(move || iter.map(|item| item.map(|(k, _)| k)).collect::<Result<Vec<_>>>());
+        for i in 0..100u8 {
+            eng.set(&[b'x', i], vec![i])?;
+        }
+        assert_eq!(
+            handle.join().unwrap()?,
+            vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        assert_eq!(eng.get(b"a")?, Some(b"10".to_vec()));
+
+        // follower 重新同步时清空数据，之前的快照照样能读完
+        let snap = eng.snapshot()?;
+        eng.truncate()?;
+        eng.set(b"z", b"26".to_vec())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, vec![(b"z".to_vec(), b"26".to_vec())]);
+        assert_eq!(snap.iter().count(), 104);
+        assert_eq!(snap.get(b"a")?, Some(b"10".to_vec()));
+        drop(snap);
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.scan(..).count(), 1);
+        drop(eng);
+
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_writes() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-cas-test")
+            .join("log");
+        path.parent().map(std::fs::remove_dir_all);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert!(eng.put_if_absent(b"a", b"1".to_vec())?);
+        assert!(!eng.put_if_absent(b"a", b"2".to_vec())?);
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+
+        assert!(!eng.compare_and_swap(b"a", Some(b"2"), Some(b"3".to_vec()))?);
+        assert!(eng.compare_and_swap(b"a", Some(b"1"), Some(b"3".to_vec()))?);
+        assert_eq!(eng.get(b"a")?, Some(b"3".to_vec()));
+        assert!(eng.compare_and_swap(b"a", Some(b"3"), None)?);
+        assert_eq!(eng.get(b"a")?, None);
+        assert!(!eng.compare_and_swap(b"a", Some(b"3"), None)?);
+        assert!(eng.compare_and_swap(b"a", None, None)?);
+
+        assert_eq!(eng.increment(b"n", 5)?, 5);
+        assert_eq!(eng.increment(b"n", -7)?, -2);
+        assert_eq!(eng.get(b"n")?, Some((-2i64).to_be_bytes().to_vec()));
+        assert!(eng.increment(b"n", i64::MIN).is_err());
+        eng.set(b"s", b"abc".to_vec())?;
+        assert!(eng.increment(b"s", 1).is_err());
+
+        // 多个线程同时加，一个也不能丢
+        let eng = std::sync::Arc::new(std::sync::Mutex::new(eng));
+        let handles = (0..4)
+            .map(|_| {
+                let eng = eng.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        eng.lock().unwrap().increment(b"c", 1)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(eng.lock().unwrap().increment(b"c", 0)?, 200);
+
+        drop(eng);
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_operator() -> Result<()> {
+        use crate::merge_operator::{AddOperator, AppendOperator};
+
+        let path = std::env::temp_dir()
+            .join("minibitcask-merge-operator-test")
+            .join("log");
+        path.parent().map(std::fs::remove_dir_all);
+        let options = Options {
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Default::default()
+        };
+
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.set(b"list", b"a".to_vec())?;
+        eng.merge_value(b"list", b",b".to_vec())?;
+        eng.merge_value(b"list", b",c".to_vec())?;
+        eng.merge_value(b"new", b"x".to_vec())?;
+        assert_eq!(eng.get(b"list")?, Some(b"a,b,c".to_vec()));
+        assert_eq!(eng.get(b"new")?, Some(b"x".to_vec()));
+        let snap = eng.snapshot()?;
+
+        // 删除之后重新从空值开始合并
+        eng.delete(b"new")?;
+        eng.merge_value(b"new", b"y".to_vec())?;
+        assert_eq!(
+            eng.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"list".to_vec(), b"a,b,c".to_vec()), (b"new".to_vec(), b"y".to_vec())]
+        );
+        assert_eq!(snap.get(b"new")?, Some(b"x".to_vec()));
+
+        let changes = eng.changes_since(3)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(changes[0], Change::Merge { seq: 4, key: b"new".to_vec(), operand: b"x".to_vec() });
+
+        // 重新打开之后 operand 还在，快照和日志共用一个文件句柄，也要先释放
+        drop(snap);
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.get(b"list")?, Some(b"a,b,c".to_vec()));
+
+        // compaction 把 operand 合并成一条完整的记录
+        let before = std::fs::metadata(&path)?.len();
+        eng.merge()?;
+        assert!(std::fs::metadata(&path)?.len() < before);
+        assert!(eng.pending.is_empty());
+        eng.merge_value(b"list", b",d".to_vec())?;
+        assert_eq!(eng.get(b"list")?, Some(b"a,b,c,d".to_vec()));
+        assert_eq!(eng.get(b"new")?, Some(b"y".to_vec()));
+        drop(eng);
+
+        // 没有配置 merge operator 时不能写，也读不出有 operand 的 key
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert!(eng.merge_value(b"list", b",e".to_vec()).is_err());
+        assert!(eng.get(b"list").is_err());
+        drop(eng);
+        path.parent().map(std::fs::remove_dir_all);
+
+        let mut eng = MiniBitcask::open(
+            path.clone(),
+            Options {
+                merge_operator: Some(Arc::new(AddOperator)),
+                ..Default::default()
+            },
+        )?;
+        for _ in 0..10 {
+            eng.merge_value(b"n", 2i64.to_be_bytes().to_vec())?;
+        }
+        assert_eq!(eng.increment(b"n", 1)?, 21);
+
+        drop(eng);
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+    #[test]
+    fn test_encryption() -> Result<()> {
+        use crate::crypto::EncryptionMode;
+
+        let path = std::env::temp_dir()
+            .join("minibitcask-encryption-test")
+            .join("log");
+        path.parent().map(std::fs::remove_dir_all);
+        let key1 = Encryption { key: [1; 32], mode: EncryptionMode::KeyAndValue };
+        let options = Options { encryption: Some(key1.clone()), ..Default::default() };
+
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.set(b"secret-key", b"secret-value".to_vec())?;
+        eng.set(b"other", b"plain?".to_vec())?;
+        eng.delete(b"other")?;
+        assert_eq!(eng.get(b"secret-key")?, Some(b"secret-value".to_vec()));
+        let snap = eng.snapshot()?;
+        assert_eq!(snap.get(b"secret-key")?, Some(b"secret-value".to_vec()));
+        drop(snap);
+        drop(eng);
+
+        // 文件里看不到明文
+        let raw = std::fs::read(&path)?;
+        let contains = |needle: &[u8]| raw.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"secret-key"));
+        assert!(!contains(b"secret-value"));
+
+        // 没有密钥或者密钥不对时给出明确的错误
+        let err = MiniBitcask::new(path.clone()).err().unwrap();
+        assert!(err.to_string().contains("encryption key is required"));
+        let key2 = Encryption { key: [2; 32], mode: EncryptionMode::ValueOnly };
+        let err = MiniBitcask::open(path.clone(), Options { encryption: Some(key2.clone()), ..Default::default() })
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "wrong encryption key");
+
+        // merge 的时候换密钥
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.changes_since(0)?.count(), 3);
+        eng.merge_with_encryption(Some(key2.clone()))?;
+        eng.set(b"after", b"rotate".to_vec())?;
+        drop(eng);
+        assert!(MiniBitcask::open(path.clone(), options.clone()).is_err());
+        let mut eng = MiniBitcask::open(path.clone(), Options { encryption: Some(key2), ..Default::default() })?;
+        assert_eq!(
+            eng.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"after".to_vec(), b"rotate".to_vec()), (b"secret-key".to_vec(), b"secret-value".to_vec())]
+        );
+        // value-only 模式下 key 是明文
+        let raw = std::fs::read(&path)?;
+        assert!(raw.windows(5).any(|w| w == b"after"));
+        assert!(!raw.windows(6).any(|w| w == b"rotate"));
+
+        // 解密回明文
+        eng.merge_with_encryption(None)?;
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"after")?, Some(b"rotate".to_vec()));
+
+        drop(eng);
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaces() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-namespace-test")
+            .join("log");
+        path.parent().map(std::fs::remove_dir_all);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"default".to_vec())?;
+        {
+            let mut users = eng.namespace("users")?;
+            users.set(b"a", b"alice".to_vec())?;
+            users.set(b"b", b"bob".to_vec())?;
+            assert_eq!(users.get(b"a")?, Some(b"alice".to_vec()));
+            assert_eq!(users.stats().keys, 2);
+        }
+        {
+            let mut orders = eng.namespace("orders")?;
+            assert_eq!(orders.get(b"a")?, None);
+            orders.set(b"a", b"order-1".to_vec())?;
+            orders.delete(b"a")?;
+            orders.set(b"z", b"order-2".to_vec())?;
+        }
+
+        // 默认 namespace 看不到其他 namespace 的 key
+        assert_eq!(eng.get(b"a")?, Some(b"default".to_vec()));
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, vec![(b"a".to_vec(), b"default".to_vec())]);
+        assert_eq!(eng.scan(..).rev().count(), 1);
+        assert_eq!(eng.stats().keys, 1);
+        assert_eq!(eng.snapshot()?.len(), 1);
+        assert_eq!(eng.namespace_names(), vec!["orders".to_string(), "users".to_string()]);
+
+        // 以保留前缀开头的 key 在默认 namespace 里照样能用，不会碰到 id 为 1 的 users
+        let events = eng.watch(b"");
+        let reserved = namespace::encode_key(1, b"a");
+        eng.set(&reserved, b"escaped".to_vec())?;
+        assert_eq!(eng.get(&reserved)?, Some(b"escaped".to_vec()));
+        assert_eq!(eng.namespace("users")?.get(b"a")?, Some(b"alice".to_vec()));
+        let keys = eng.scan(..).map(|item| item.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![b"a".to_vec(), reserved.clone()]);
+        assert_eq!(eng.scan_prefix(NAMESPACE_PREFIX).count(), 1);
+        assert_eq!(eng.snapshot()?.get(&reserved)?, Some(b"escaped".to_vec()));
+        assert_eq!(eng.stats().keys, 2);
+        // 订阅者只收到默认 namespace 的修改，key 是转义之前的
+        eng.namespace("orders")?.set(b"w", b"1".to_vec())?;
+        eng.delete(&reserved)?;
+        assert_eq!(events.try_recv(), Ok(Event::Put { key: reserved.clone(), value: b"escaped".to_vec() }));
+        assert_eq!(events.try_recv(), Ok(Event::Delete { key: reserved.clone() }));
+        assert!(events.try_recv().is_err());
+        assert_eq!(eng.get(&reserved)?, None);
+        eng.namespace("orders")?.delete(b"w")?;
+
+        // 重新打开之后 namespace 还在
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        let mut users = eng.namespace("users")?;
+        assert_eq!(
+            users.scan(b"b".to_vec()..).collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), b"bob".to_vec())]
+        );
+        assert_eq!(users.scan(..).next_back().expect("no value founded")?.0, b"b".to_vec());
+
+        // 删除 namespace 不需要遍历数据，merge 的时候才真正清理
+        assert!(eng.drop_namespace("users")?);
+        assert!(!eng.drop_namespace("users")?);
+        assert_eq!(eng.namespace("users")?.get(b"a")?, None);
+        eng.merge()?;
+        // 目录里的 id 计数、orders、重新创建的 users，再加上 orders 的一个 key
+        assert_eq!(eng.count_range(namespace::reserved_range()), 4);
+        assert_eq!(eng.namespace("orders")?.get(b"z")?, Some(b"order-2".to_vec()));
+        assert_eq!(eng.namespace("orders")?.stats().keys, 1);
+        assert_eq!(eng.namespace("users")?.stats().keys, 0);
+
+        drop(eng);
+        path.parent().map(std::fs::remove_dir_all);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_reserved_keys() -> Result<()> {
+        let path = PathBuf::from("db/log");
+        let options = Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() };
+        let legacy = namespace::encode_key(1, b"old");
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        // 以前的版本把这样的 key 原样写在保留前缀下面
+        eng.set_raw(&legacy, b"v".to_vec())?;
+        eng.set_raw(NAMESPACE_PREFIX, b"short".to_vec())?;
+        drop(eng);
+
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.get(&legacy)?, Some(b"v".to_vec()));
+        assert_eq!(eng.get(NAMESPACE_PREFIX)?, Some(b"short".to_vec()));
+        assert_eq!(eng.stats().keys, 2);
+        // 新建的 namespace 拿到 id 1 也看不到旧 key
+        assert_eq!(eng.namespace("users")?.get(b"old")?, None);
+        eng.delete(&legacy)?;
+        drop(eng);
+
+        // 只挪一次，之后写进 users 的 key 不会被当成旧 key
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.namespace("users")?.set(b"new", b"1".to_vec())?;
+        drop(eng);
+        let mut eng = MiniBitcask::open(path, options)?;
+        assert_eq!(eng.get(&legacy)?, None);
+        assert_eq!(eng.get(NAMESPACE_PREFIX)?, Some(b"short".to_vec()));
+        assert_eq!(eng.namespace("users")?.get(b"new")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    fn faulty_options() -> (FaultyStorage, Options) {
+        let storage = FaultyStorage::new(Arc::new(MemoryStorage::new()));
+        // 每条记录都直接写到文件，故障注入按字节数算才准
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            sync_policy: SyncPolicy::WriteThrough,
+            ..Default::default()
+        };
+        (storage, options)
+    }
+
+    /// 写入一批数据，遇到第一个失败就停下，返回写成功的结果
+    fn write_until_failure(eng: &mut MiniBitcask) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        let mut acked = BTreeMap::new();
+        for i in 0..40u32 {
+            let key = format!("key{}", i % 9).into_bytes();
+            let result = match i % 4 {
+                3 => eng.delete(&key).map(|_| None),
+                _ => {
+                    let value = format!("value{}", i).repeat(i as usize % 5 + 1).into_bytes();
+                    eng.set(&key, value.clone()).map(|_| Some(value))
+                }
+            };
+            match result {
+                Ok(value) => acked.insert(key, value),
+                Err(_) => break,
+            };
+        }
+        acked
+    }
+
+    fn check(eng: &mut MiniBitcask, expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<()> {
+        for (key, value) in expected {
+            assert_eq!(&eng.get(key)?, value, "key {:?}", String::from_utf8_lossy(key));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_under_faults() -> Result<()> {
+        let path = PathBuf::from("db/log");
+
+        let (storage, options) = faulty_options();
+        let mut eng = MiniBitcask::open(path.clone(), options)?;
+        write_until_failure(&mut eng);
+        let total = storage.faults().written;
+
+        // 在每个位置崩溃，重启之后写成功的数据都要在，写了一半的记录被丢掉
+        for crash_at in (0..total).step_by(13) {
+            let (storage, options) = faulty_options();
+            let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+            storage.faults().crash_after = Some(crash_at);
+            let acked = write_until_failure(&mut eng);
+            assert!(storage.faults().crashed);
+            drop(eng);
+
+            storage.recover();
+            let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+            check(&mut eng, &acked)?;
+            eng.set(b"after", b"crash".to_vec())?;
+            drop(eng);
+            let mut eng = MiniBitcask::open(path.clone(), options)?;
+            check(&mut eng, &acked)?;
+            assert_eq!(eng.get(b"after")?, Some(b"crash".to_vec()));
+        }
+
+        // 写失败和短写不影响之后的写入
+        let (storage, options) = faulty_options();
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        storage.faults().fail_writes = 1;
+        assert!(eng.set(b"a", b"2".to_vec()).is_err());
+        storage.faults().short_write = Some(5);
+        assert!(eng.set(b"a", b"3".to_vec()).is_err());
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        eng.set(b"b", b"4".to_vec())?;
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options)?;
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"4".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_under_faults() -> Result<()> {
+        let path = PathBuf::from("db/log");
+        let inject: [fn(&mut Faults); 5] = [
+            |f| f.fail_writes = 1,
+            |f| f.short_write = Some(3),
+            |f| f.fail_syncs = 1,
+            |f| f.fail_renames = 1,
+            |f| f.crash_after = Some(100),
+        ];
+        for inject in inject {
+            let (storage, options) = faulty_options();
+            let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+            let expected = write_until_failure(&mut eng);
+
+            inject(&mut storage.faults());
+            assert!(eng.merge().is_err());
+            let crashed = storage.faults().crashed;
+            if !crashed {
+                // merge 失败之后还是用原来的日志
+                check(&mut eng, &expected)?;
+                eng.merge()?;
+                check(&mut eng, &expected)?;
+            }
+            drop(eng);
+
+            storage.recover();
+            let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+            check(&mut eng, &expected)?;
+            // 崩溃时留下的 merge 文件不影响下一次 merge
+            eng.merge()?;
+            drop(eng);
+            let mut eng = MiniBitcask::open(path.clone(), options)?;
+            check(&mut eng, &expected)?;
+            assert_eq!(eng.stats().keys, expected.values().filter(|v| v.is_some()).count());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_buffer() -> Result<()> {
+        let path = PathBuf::from("db/log");
+        let (storage, options) = faulty_options();
+        let options = Options {
+            sync_policy: SyncPolicy::Buffered,
+            write_buffer_size: 256,
+            ..options
+        };
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        assert_eq!(storage.faults().written, 0);
+        // 读刚写的数据会先把缓冲区写到文件
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        let written = storage.faults().written;
+        assert!(written > 0);
+
+        // 缓冲区写满了也会写到文件
+        eng.set(b"c", vec![b'c'; 300])?;
+        assert!(storage.faults().written > written);
+
+        // 写文件失败时缓冲区保留，之后还能再写
+        eng.set(b"d", b"4".to_vec())?;
+        storage.faults().fail_writes = 1;
+        assert!(eng.sync().is_err());
+        eng.sync()?;
+        drop(eng);
+
+        let mut eng = MiniBitcask::open(path.clone(), options)?;
+        assert_eq!(eng.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(vec![b'c'; 300]));
+        assert_eq!(eng.get(b"d")?, Some(b"4".to_vec()));
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_read_cache() -> Result<()> {
+        use crate::merge_operator::AppendOperator;
+        let path = PathBuf::from("db/log");
+        let options = Options {
+            storage: Arc::new(MemoryStorage::new()),
+            read_cache_size: 16,
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Default::default()
+        };
+        let mut eng = MiniBitcask::open(path, options)?;
+        eng.set(b"a", b"1".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!((eng.stats().cache_hits, eng.stats().cache_misses), (1, 1));
+
+        // 覆盖、删除之后读到的是新的值
+        eng.set(b"a", b"2".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"2".to_vec()));
+        eng.merge_value(b"a", b"3".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"23".to_vec()));
+        eng.delete(b"a")?;
+        assert_eq!(eng.get(b"a")?, None);
+        // 合并 operand 的时候 2 是从缓存读的
+        assert_eq!((eng.stats().cache_hits, eng.stats().cache_misses), (2, 3));
+
+        // 超过容量的 value 不缓存
+        eng.set(b"big", vec![0; 17])?;
+        eng.get(b"big")?;
+        eng.get(b"big")?;
+        assert_eq!(eng.stats().cache_misses, 5);
+
+        // merge 之后位置都变了
+        eng.set(b"b", b"4".to_vec())?;
+        eng.get(b"b")?;
+        eng.merge()?;
+        assert_eq!(eng.get(b"b")?, Some(b"4".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"4".to_vec()));
+        assert_eq!((eng.stats().cache_hits, eng.stats().cache_misses), (3, 7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        use crate::crypto::EncryptionMode;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let path = PathBuf::from("db/log");
+        let json = |i: usize| format!(r#"{{"id":{},"name":"user","tags":["a","b","c"],"pad":"{}"}}"#, i, "x".repeat(200)).into_bytes();
+        let open = |compression, encryption| {
+            let options = Options {
+                storage: storage.clone(),
+                compression,
+                encryption,
+                merge_operator: Some(Arc::new(crate::merge_operator::AppendOperator)),
+                ..Default::default()
+            };
+            MiniBitcask::open(path.clone(), options)
+        };
+
+        let mut eng = open(Compression::Lz4, None)?;
+        eng.set(b"a", json(1))?;
+        // 小于阈值的不压缩
+        eng.set(b"small", b"{}".to_vec())?;
+        assert!(eng.stats().compression_ratio() < 0.5);
+        assert_eq!(eng.get(b"a")?, Some(json(1)));
+        assert_eq!(eng.get_range(b"a", 2, 5)?, Some(b"id\":1".to_vec()));
+        drop(eng);
+
+        // 换成 snappy 再写，之前 lz4 压缩的和没有压缩的都还能读
+        let mut eng = open(Compression::Snappy, None)?;
+        assert_eq!(eng.stats().compression_ratio(), 1.0);
+        eng.set(b"b", json(2))?;
+        eng.merge_value(b"b", json(3))?;
+        eng.set_from_reader(b"stream", &json(4)[..], json(4).len() as u64)?;
+        let snapshot = eng.snapshot()?;
+        drop(eng);
+
+        let mut eng = open(Compression::None, None)?;
+        eng.set(b"c", json(5))?;
+        let expected = [
+            (b"a".to_vec(), json(1)),
+            (b"b".to_vec(), [json(2), json(3)].concat()),
+            (b"c".to_vec(), json(5)),
+            (b"small".to_vec(), b"{}".to_vec()),
+            (b"stream".to_vec(), json(4)),
+        ];
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expected);
+        assert_eq!(snapshot.get(b"b")?, Some([json(2), json(3)].concat()));
+        assert_eq!(snapshot.iter().count(), 4);
+        let keys = expected.iter().map(|(k, _)| k.as_slice()).collect::<Vec<_>>();
+        let values = eng.multi_get(&keys)?;
+        assert_eq!(values, expected.iter().map(|(_, v)| Some(v.clone())).collect::<Vec<_>>());
+        assert_eq!(eng.changes_since(0)?.collect::<Result<Vec<_>>>()?.len(), 6);
+        drop(snapshot);
+        drop(eng);
+
+        // 先压缩再加密；merge 按新的设置重新压缩
+        let key = Encryption { key: [7; 32], mode: EncryptionMode::KeyAndValue };
+        let mut eng = open(Compression::Lz4, None)?;
+        eng.merge_with_encryption(Some(key.clone()))?;
+        assert!(eng.stats().compression_ratio() < 0.5);
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expected);
+        drop(eng);
+        let mut eng = open(Compression::None, Some(key))?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_get() -> Result<()> {
+        use crate::merge_operator::AppendOperator;
+        let options = Options {
+            storage: Arc::new(MemoryStorage::new()),
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Default::default()
+        };
+        let mut eng = MiniBitcask::open(PathBuf::from("db/log"), options)?;
+        for i in 0..100 {
+            eng.set(format!("key{:03}", i).as_bytes(), format!("value{}", i).into_bytes())?;
+        }
+        eng.delete(b"key050")?;
+        eng.merge_value(b"key010", b"+".to_vec())?;
+
+        let keys = [&b"key099"[..], b"key000", b"key050", b"missing", b"key010", b"key099"];
+        let values = eng.multi_get(&keys)?;
+        let expected = keys.iter().map(|key| eng.get(key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, expected);
+        assert_eq!(values[4], Some(b"value10+".to_vec()));
+        assert_eq!(values[2], None);
+        assert!(eng.multi_get(&[])?.is_empty());
+        assert_eq!(eng.multi_get(&[NAMESPACE_PREFIX])?, vec![None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming() -> Result<()> {
+        use crate::log::CHECKSUM_CHUNK;
+        let path = PathBuf::from("db/log");
+        let storage = MemoryStorage::new();
+        let options = Options { storage: Arc::new(storage.clone()), ..Default::default() };
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        let value = (0..CHECKSUM_CHUNK * 3 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        eng.set(b"small", b"1".to_vec())?;
+        eng.set_from_reader(b"big", value.as_slice(), value.len() as u64)?;
+        eng.set(b"after", b"2".to_vec())?;
+
+        let mut out = Vec::new();
+        assert_eq!(eng.get_to_writer(b"big", &mut out)?, Some(value.len() as u64));
+        assert_eq!(out, value);
+        assert_eq!(eng.get_to_writer(b"missing", &mut out)?, None);
+        assert_eq!(eng.get(b"big")?, Some(value.clone()));
+        // 跨块的一段
+        let start = CHECKSUM_CHUNK as u64 - 10;
+        assert_eq!(eng.get_range(b"big", start, 20)?, Some(value[start as usize..start as usize + 20].to_vec()));
+        assert_eq!(eng.get_range(b"big", value.len() as u64 - 5, 100)?, Some(value[value.len() - 5..].to_vec()));
+        assert_eq!(eng.get_range(b"small", 5, 1)?, Some(Vec::new()));
+
+        // reader 不够长，之前的数据不受影响
+        assert!(eng.set_from_reader(b"short", &b"abc"[..], 10).is_err());
+        assert_eq!(eng.get(b"short")?, None);
+        eng.set(b"small", b"3".to_vec())?;
+
+        // 重启之后和 merge 之后都还能校验
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.get_range(b"big", 0, 3)?, Some(value[..3].to_vec()));
+        eng.merge()?;
+        assert_eq!(eng.get(b"big")?, Some(value.clone()));
+        assert_eq!(eng.get(b"small")?, Some(b"3".to_vec()));
+        assert_eq!(eng.get(b"after")?, Some(b"2".to_vec()));
+
+        // 改掉第二块里的一个字节，读到那一块时报错，前面的块照样能读
+        let (value_pos, ..) = eng.index.get(b"big")?.unwrap();
+        eng.close()?;
+        let mut contents = storage.contents(&path).unwrap();
+        contents[value_pos as usize + CHECKSUM_CHUNK as usize + 1] ^= 1;
+        storage.set_contents(&path, contents);
+        let mut eng = MiniBitcask::open(path, options)?;
+        assert_eq!(eng.get_range(b"big", 0, 3)?, Some(value[..3].to_vec()));
+        let err = eng.get_range(b"big", CHECKSUM_CHUNK as u64, 3).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert!(eng.get(b"big").is_err());
+        // 快照和 multi_get 也走同样的校验
+        assert!(eng.multi_get(&[b"small", b"big"]).is_err());
+        let snapshot = eng.snapshot()?;
+        assert_eq!(snapshot.get(b"small")?, Some(b"3".to_vec()));
+        let err = snapshot.get(b"big").unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_close() -> Result<()> {
+        let path = PathBuf::from("db/log");
+        let storage = MemoryStorage::new();
+        let options = Options { storage: Arc::new(storage.clone()), compression: Compression::Lz4, ..Default::default() };
+        let value = b"abcd".repeat(100);
+
+        // 空库 close 不写标记
+        MiniBitcask::open(path.clone(), options.clone())?.close()?;
+        assert_eq!(storage.contents(&path).map(|c| c.len()), Some(0));
+
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.set(b"a", value.clone())?;
+        eng.set(b"b", b"1".to_vec())?;
+        let (value_pos, ..) = eng.index.get(b"a")?.unwrap();
+        eng.close()?;
+        let clean = storage.contents(&path).unwrap();
+
+        // 标记不会重复写，也不进索引
+        let eng = MiniBitcask::open(path.clone(), options.clone())?;
+        eng.close()?;
+        assert_eq!(storage.contents(&path).unwrap(), clean);
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(eng.scan(..).count(), 2);
+        assert_eq!(eng.get(b"a")?, Some(value.clone()));
+        // 写过之后只 drop，下次打开时要检查整个文件
+        eng.set(b"c", b"2".to_vec())?;
+        drop(eng);
+
+        let mut corrupted = storage.contents(&path).unwrap();
+        corrupted[value_pos as usize + 1] ^= 1;
+        storage.set_contents(&path, corrupted);
+        let err = MiniBitcask::open(path.clone(), options.clone()).err().unwrap();
+        assert!(err.to_string().contains("is corrupted at"), "{:#}", err);
+
+        // 正常关闭过的文件打开时不检查，坏的值读到时才报错
+        let mut corrupted = clean;
+        corrupted[value_pos as usize + 1] ^= 1;
+        storage.set_contents(&path, corrupted);
+        let mut eng = MiniBitcask::open(path, options)?;
+        assert_eq!(eng.get(b"b")?, Some(b"1".to_vec()));
+        assert!(eng.get(b"a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        use crate::merge_operator::AppendOperator;
+        use std::time::{Duration, SystemTime};
+        let path = PathBuf::from("db/log");
+        let options = Options {
+            storage: Arc::new(MemoryStorage::new()),
+            merge_operator: Some(Arc::new(AppendOperator)),
+            history: Some(Retention::Versions(3)),
+            ..Default::default()
+        };
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        let before = SystemTime::now() - Duration::from_secs(1);
+        eng.set(b"k", b"1".to_vec())?;
+        let seq1 = eng.last_seq();
+        eng.set(b"k", b"2".to_vec())?;
+        eng.merge_value(b"k", b"+".to_vec())?;
+        eng.delete(b"k")?;
+        let seq4 = eng.last_seq();
+        eng.set(b"k", b"5".to_vec())?;
+
+        let values = |versions: Vec<Version>| versions.into_iter().map(|v| v.value).collect::<Vec<_>>();
+        let all = [Some(b"1".to_vec()), Some(b"2".to_vec()), Some(b"2+".to_vec()), None, Some(b"5".to_vec())];
+        let versions = eng.get_versions(b"k")?;
+        assert!(versions.iter().all(|v| v.timestamp.is_some_and(|t| t > before)));
+        assert_eq!(values(versions), all);
+        assert_eq!(eng.get_as_of(b"k", AsOf::Seq(seq1))?, Some(b"1".to_vec()));
+        assert_eq!(eng.get_as_of(b"k", AsOf::Seq(seq1 + 2))?, Some(b"2+".to_vec()));
+        assert_eq!(eng.get_as_of(b"k", AsOf::Seq(seq4))?, None);
+        assert_eq!(eng.get_as_of(b"k", AsOf::Seq(0))?, None);
+        assert_eq!(eng.get_as_of(b"k", AsOf::Time(before))?, None);
+        assert_eq!(eng.get_as_of(b"k", AsOf::Time(SystemTime::now()))?, Some(b"5".to_vec()));
+
+        // 重启之后从日志里恢复，merge 之后只留最近 3 个版本，operand 版本变成完整的值
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(values(eng.get_versions(b"k")?), all);
+        eng.merge()?;
+        assert_eq!(values(eng.get_versions(b"k")?), all[2..]);
+        assert_eq!(eng.get_as_of(b"k", AsOf::Seq(seq1))?, None);
+        drop(eng);
+        let mut eng = MiniBitcask::open(path.clone(), options)?;
+        assert_eq!(values(eng.get_versions(b"k")?), all[2..]);
+        assert_eq!(eng.get(b"k")?, Some(b"5".to_vec()));
+
+        // 没有开启历史版本
+        drop(eng);
+        let mut eng = MiniBitcask::open(path, Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() })?;
+        assert!(eng.get_versions(b"k").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        use crate::keycode::{self, Value};
+        let mut eng = MiniBitcask::open(PathBuf::from("db/log"), Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() })?;
+        let key = |tenant: &str, user: i64, ts: u64| keycode::encode(&[tenant.into(), user.into(), ts.into()]);
+        for (tenant, user, ts) in [("t1", 256, 1), ("t1", -1, 2), ("t1", 2, 3), ("t10", 1, 4), ("t1", 2, 300)] {
+            eng.set(&key(tenant, user, ts), ts.to_be_bytes().to_vec())?;
+        }
+        eng.set(b"\xff\xff", b"x".to_vec())?;
+
+        let users = eng
+            .scan_prefix(&keycode::prefix(&["t1".into()]))
+            .map(|item| keycode::decode(&item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        let expected = [(-1, 2), (2, 3), (2, 300), (256, 1)]
+            .map(|(user, ts)| vec![Value::from("t1"), Value::from(user as i64), Value::from(ts as u64)]);
+        assert_eq!(users, expected);
+        assert_eq!(eng.scan_prefix(&keycode::prefix(&["t1".into(), 2i64.into()])).count(), 2);
+        assert_eq!(eng.scan_prefix(b"\xff").count(), 1);
+        assert_eq!(eng.scan_prefix(b"").count(), 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::log::{FLAG_LZ4, FLAG_SNAPPY};
+use anyhow::{anyhow, Result};
+
+/// 新写入的 value 用什么压缩，已有的记录按记录头里的 flag 解压，和这里的设置无关
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+}
+
+impl Compression {
+    /// 记录头里对应的 flag
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => FLAG_LZ4,
+            Compression::Snappy => FLAG_SNAPPY,
+        }
+    }
+
+    pub(crate) fn from_flags(flags: u8) -> Self {
+        if flags & FLAG_LZ4 != 0 {
+            Compression::Lz4
+        } else if flags & FLAG_SNAPPY != 0 {
+            Compression::Snappy
+        } else {
+            Compression::None
+        }
+    }
+
+    pub(crate) fn compress(self, value: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => value.to_vec(),
+            // 前面带上原始长度，解压时一次分配好
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value),
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(value)?,
+        })
+    }
+
+    pub(crate) fn decompress(self, value: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => value.to_vec(),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(value).map_err(|e| anyhow!("failed to decompress value: {}", e))?,
+            Compression::Snappy => snap::raw::Decoder::new().decompress_vec(value)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        let value = br#"{"name":"alice","tags":["a","b","c"],"bio":"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"}"#;
+        for codec in [Compression::None, Compression::Lz4, Compression::Snappy] {
+            let compressed = codec.compress(value)?;
+            assert_eq!(codec.decompress(&compressed)?, value);
+            assert_eq!(Compression::from_flags(codec.flag()), codec);
+            if codec != Compression::None {
+                assert!(compressed.len() < value.len());
+            }
+        }
+        assert!(Compression::Lz4.decompress(b"\xff\xff\xff\x7f garbage").is_err());
+        assert!(Compression::Snappy.decompress(b"\xff\xff\xff\x7f garbage").is_err());
+        Ok(())
+    }
+}
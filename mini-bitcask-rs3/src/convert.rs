@@ -0,0 +1,468 @@
+use crate::bitcask::{MiniBitcask, Options};
+use crate::index::{put_varint, read_varint, BTreeIndex, Indexer};
+use crate::log::Log;
+use crate::merge_operator::{resolve, PendingOperands};
+use crate::storage::ReadOnlyStorage;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::str::FromStr;
+
+// mini-bitcask-rs v2 的文件头：魔数 + 格式版本
+const MAGIC: &[u8; 4] = b"MBCK";
+const FILE_HEADER_LEN: u64 = MAGIC.len() as u64 + 1;
+const V2_FLAG_TOMBSTONE: u8 = 1;
+
+// rs3 扩展记录的 key 长度带这个位
+const EXTENDED_BIT: u32 = 1 << 31;
+
+// rs2 记录头里的 mark
+const RS2_PUT: u16 = 0;
+const RS2_DELETE: u16 = 1;
+
+/// 各个 crate 的磁盘格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// mini-bitcask-rs 的 v1，也是 rs3 以前的格式：key 长度 u32 + value 长度 i32（-1 表示删除）
+    V1,
+    /// mini-bitcask-rs 的 v2：文件头 "MBCK" + 版本号，记录头是 flags + varint 长度
+    V2,
+    /// mini-bitcask-rs2：key 长度 u32 + value 长度 u32 + mark u16，key 是字符串
+    Rs2,
+    /// mini-bitcask-rs3：带 seq 和 flags 的记录，只读地解析源文件，用 MiniBitcask 写目标文件
+    Rs3,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "v1" | "rs" => Format::V1,
+            "v2" => Format::V2,
+            "rs2" => Format::Rs2,
+            "rs3" => Format::Rs3,
+            _ => bail!("unknown format {:?}, expected one of v1, v2, rs2, rs3", s),
+        })
+    }
+}
+
+/// 根据文件内容判断格式
+///
+/// 有文件头的按版本号判断；没有文件头的 v1 和 rs2 各试着完整解析一遍，
+/// 两种都能解析时无法区分，要调用方指定。
+pub fn detect(path: &Path) -> Result<Format> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut head = [0; FILE_HEADER_LEN as usize];
+    let n = file.read(&mut head)?;
+    if n == 0 {
+        bail!("{} is empty, cannot detect its format", path.display());
+    }
+    if n == head.len() && head.starts_with(MAGIC) {
+        return match head[MAGIC.len()] {
+            2 => Ok(Format::V2),
+            version => bail!("unsupported format version {} in {}", version, path.display()),
+        };
+    }
+    if n >= 4 && u32::from_be_bytes(head[..4].try_into()?) & EXTENDED_BIT != 0 {
+        return Ok(Format::Rs3);
+    }
+    match (RawLog::load(path, Format::V1), RawLog::load(path, Format::Rs2)) {
+        (Ok(_), Ok(_)) => bail!("{} can be read as both v1 and rs2, specify the format", path.display()),
+        (Ok(_), Err(_)) => Ok(Format::V1),
+        (Err(_), Ok(_)) => Ok(Format::Rs2),
+        (Err(e), Err(_)) => Err(e.context(format!("{} is not in any known format", path.display()))),
+    }
+}
+
+/// 把 from 里还有效的 key 以 to_format 写到 to，from_format 为 None 时自动判断
+///
+/// 源文件只读，不会被修改。to 不能已经存在。写完之后重新读一遍 to，检查 key 的数量和源文件一致，返回 key 的数量。
+pub fn convert(from: &Path, from_format: Option<Format>, to: &Path, to_format: Format) -> Result<usize> {
+    convert_with(from, from_format, to, to_format, &Options::default())
+}
+
+/// 和 convert 一样，rs3 的文件用 options 里的 encryption 和 merge_operator 读写，其他选项不用
+pub fn convert_with(from: &Path, from_format: Option<Format>, to: &Path, to_format: Format, options: &Options) -> Result<usize> {
+    let options = Options {
+        encryption: options.encryption.clone(),
+        merge_operator: options.merge_operator.clone(),
+        ..Default::default()
+    };
+    let from_format = match from_format {
+        Some(format) => format,
+        None => detect(from)?,
+    };
+    if to.exists() {
+        bail!("{} already exists", to.display());
+    }
+
+    let mut source = Source::open(from, from_format, &options)?;
+    let result = copy(&mut source, to, to_format, &options);
+    drop(source);
+    if result.is_err() {
+        // to 是这次新建的，失败时删掉写了一半的文件
+        let _ = std::fs::remove_file(to);
+    }
+    result
+}
+
+fn copy(source: &mut Source, to: &Path, to_format: Format, options: &Options) -> Result<usize> {
+    let mut sink = Sink::create(to, to_format, options)?;
+    let mut count = 0;
+    source.for_each(|key, value| {
+        sink.put(key, value)?;
+        count += 1;
+        Ok(())
+    })?;
+    sink.finish()?;
+
+    let written = Source::open(to, to_format, options)?.len();
+    if written != count {
+        bail!("converted {} keys but {} contains {}", count, to.display(), written);
+    }
+    Ok(count)
+}
+
+/// 没有 seq 的简单格式：v1、v2 和 rs2，key -> (value_pos, value_len)
+struct RawLog {
+    file: File,
+    keys: BTreeMap<Vec<u8>, (u64, u32)>,
+}
+
+impl RawLog {
+    fn load(path: &Path, format: Format) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut r = BufReader::new(&mut file);
+        let mut pos = match format {
+            Format::V2 => {
+                let mut head = [0; FILE_HEADER_LEN as usize];
+                r.read_exact(&mut head)?;
+                if !head.starts_with(MAGIC) || head[MAGIC.len()] != 2 {
+                    bail!("{} is not a v2 log", path.display());
+                }
+                FILE_HEADER_LEN
+            }
+            _ => 0,
+        };
+
+        let mut keys = BTreeMap::new();
+        while pos < file_len {
+            let (key_len, value_len, header_len) = read_header(format, &mut r)
+                .with_context(|| format!("invalid record header at {} in {}", pos, path.display()))?;
+            let mut key = vec![0; key_len as usize];
+            let value_pos = pos + header_len + key_len as u64;
+            pos = value_pos + value_len.unwrap_or(0) as u64;
+            if pos > file_len {
+                bail!("truncated record at {} in {}", value_pos, path.display());
+            }
+            r.read_exact(&mut key)?;
+            if format == Format::Rs2 && std::str::from_utf8(&key).is_err() {
+                bail!("key at {} in {} is not UTF-8", value_pos, path.display());
+            }
+            match value_len {
+                Some(value_len) => {
+                    r.seek_relative(value_len as i64)?;
+                    keys.insert(key, (value_pos, value_len));
+                }
+                None => {
+                    keys.remove(&key);
+                }
+            }
+        }
+        Ok(Self { file, keys })
+    }
+}
+
+/// 返回 (key 的长度, value 的长度，删除时是 None, 记录头的长度)
+fn read_header(format: Format, r: &mut impl Read) -> Result<(u32, Option<u32>, u64)> {
+    let mut len_buf = [0; 4];
+    match format {
+        Format::V1 => {
+            r.read_exact(&mut len_buf)?;
+            let key_len = u32::from_be_bytes(len_buf);
+            if key_len & EXTENDED_BIT != 0 {
+                bail!("extended record, not a v1 log");
+            }
+            r.read_exact(&mut len_buf)?;
+            let value_len = match i32::from_be_bytes(len_buf) {
+                -1 => None,
+                v if v >= 0 => Some(v as u32),
+                v => bail!("invalid value length {}", v),
+            };
+            Ok((key_len, value_len, 8))
+        }
+        Format::V2 => {
+            let mut flags = [0; 1];
+            r.read_exact(&mut flags)?;
+            if flags[0] & !V2_FLAG_TOMBSTONE != 0 {
+                bail!("unknown record flags {:#04x}", flags[0]);
+            }
+            let (key_len, key_len_size) = read_varint(r)?;
+            let (value_len, value_len_size) = match flags[0] & V2_FLAG_TOMBSTONE {
+                0 => {
+                    let (len, size) = read_varint(r)?;
+                    (Some(u32::try_from(len)?), size)
+                }
+                _ => (None, 0),
+            };
+            Ok((u32::try_from(key_len)?, value_len, 1 + key_len_size + value_len_size))
+        }
+        Format::Rs2 => {
+            r.read_exact(&mut len_buf)?;
+            let key_len = u32::from_be_bytes(len_buf);
+            r.read_exact(&mut len_buf)?;
+            let value_len = u32::from_be_bytes(len_buf);
+            let mut mark = [0; 2];
+            r.read_exact(&mut mark)?;
+            // 删除记录的 value 长度是 0
+            let value_len = match u16::from_be_bytes(mark) {
+                RS2_PUT => Some(value_len),
+                RS2_DELETE => None,
+                mark => bail!("invalid mark {}", mark),
+            };
+            Ok((key_len, value_len, 10))
+        }
+        Format::Rs3 => unreachable!("rs3 logs are read with Rs3Log"),
+    }
+}
+
+/// 只读地解析 rs3 的日志，和 MiniBitcask::open 一样重建索引，但是不截断写了一半的尾部，也不写关闭标记
+struct Rs3Log {
+    log: Log,
+    index: BTreeIndex,
+    pending: PendingOperands,
+    options: Options,
+}
+
+impl Rs3Log {
+    fn load(path: &Path, options: &Options) -> Result<Self> {
+        let mut log = Log::open(&ReadOnlyStorage, path.to_path_buf())?;
+        let mut index = BTreeIndex::default();
+        let mut pending = PendingOperands::new();
+        if !log.file.is_empty()? {
+            log.init_encryption(options.encryption.as_ref())?;
+            log.scan_index(&mut index, &mut pending)?;
+        }
+        Ok(Self { log, index, pending, options: options.clone() })
+    }
+}
+
+enum Source {
+    Raw(RawLog),
+    Rs3(Box<Rs3Log>),
+}
+
+impl Source {
+    fn open(path: &Path, format: Format, options: &Options) -> Result<Self> {
+        Ok(match format {
+            Format::Rs3 => Source::Rs3(Box::new(Rs3Log::load(path, options)?)),
+            _ => Source::Raw(RawLog::load(path, format)?),
+        })
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Source::Raw(log) => log.keys.len(),
+            Source::Rs3(log) => log.index.len(),
+        }
+    }
+
+    /// 按 key 的顺序遍历所有还有效的 key，rs3 的 namespace 里的 key 原样带上
+    fn for_each(&mut self, mut f: impl FnMut(&[u8], &[u8]) -> Result<()>) -> Result<()> {
+        match self {
+            Source::Raw(log) => {
+                for (key, &(value_pos, value_len)) in &log.keys {
+                    let mut value = vec![0; value_len as usize];
+                    log.file.seek(SeekFrom::Start(value_pos))?;
+                    log.file.read_exact(&mut value)?;
+                    f(key, &value)?;
+                }
+            }
+            Source::Rs3(rs3) => {
+                let Rs3Log { log, index, pending, options } = rs3.as_mut();
+                for item in index.range((Bound::Unbounded, Bound::Unbounded)) {
+                    let (key, pos) = item?;
+                    let operator = options.merge_operator.as_deref();
+                    let value = resolve(pending.get(&key), operator, &key, pos, |pos| log.read_value(pos))?;
+                    f(&key, &value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Sink {
+    Raw(BufWriter<File>, Format),
+    Rs3(Box<MiniBitcask>),
+}
+
+impl Sink {
+    fn create(path: &Path, format: Format, options: &Options) -> Result<Self> {
+        if format == Format::Rs3 {
+            return Ok(Sink::Rs3(Box::new(MiniBitcask::open(path.to_path_buf(), options.clone())?)));
+        }
+        let file = File::options().write(true).create_new(true).open(path)?;
+        let mut w = BufWriter::new(file);
+        if format == Format::V2 {
+            w.write_all(MAGIC)?;
+            w.write_all(&[2])?;
+        }
+        Ok(Sink::Raw(w, format))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (w, format) = match self {
+            Sink::Rs3(db) => return db.set_raw(key, value.to_vec()),
+            Sink::Raw(w, format) => (w, *format),
+        };
+        let mut header = Vec::new();
+        match format {
+            Format::V1 => {
+                if key.len() as u64 >= EXTENDED_BIT as u64 || value.len() > i32::MAX as usize {
+                    bail!("key or value of {} bytes is too large for v1", key.len().max(value.len()));
+                }
+                header.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                header.extend_from_slice(&(value.len() as i32).to_be_bytes());
+            }
+            Format::V2 => {
+                header.push(0);
+                put_varint(&mut header, key.len() as u64);
+                put_varint(&mut header, value.len() as u64);
+            }
+            Format::Rs2 => {
+                if std::str::from_utf8(key).is_err() {
+                    bail!("key {:?} is not UTF-8, rs2 only supports string keys", key);
+                }
+                header.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                header.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                header.extend_from_slice(&RS2_PUT.to_be_bytes());
+            }
+            Format::Rs3 => unreachable!(),
+        }
+        w.write_all(&header)?;
+        w.write_all(key)?;
+        w.write_all(value)?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Sink::Raw(w, _) => {
+                let file = w.into_inner().map_err(|e| e.into_error())?;
+                file.sync_all()?;
+            }
+            Sink::Rs3(db) => db.close()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Encryption, EncryptionMode};
+    use crate::merge_operator::AppendOperator;
+    use std::sync::Arc;
+
+    const FORMATS: [Format; 4] = [Format::V1, Format::V2, Format::Rs2, Format::Rs3];
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let src = tmp_dir.path().join("src.db");
+        let mut db = MiniBitcask::new(src.clone())?;
+        db.set(b"a", b"1".to_vec())?;
+        db.set(b"b", vec![7; 300])?;
+        db.set(b"c", b"3".to_vec())?;
+        db.delete(b"c")?;
+        db.set(b"a", b"11".to_vec())?;
+        drop(db);
+        assert_eq!(detect(&src)?, Format::Rs3);
+
+        // rs3 -> 各种格式 -> rs3，每一步都自动判断源文件的格式
+        for (i, format) in FORMATS.into_iter().enumerate() {
+            let mid = tmp_dir.path().join(format!("mid{}", i));
+            let back = tmp_dir.path().join(format!("back{}", i));
+            assert_eq!(convert(&src, None, &mid, format)?, 2);
+            assert_eq!(detect(&mid)?, format);
+            assert_eq!(convert(&mid, None, &back, Format::Rs3)?, 2);
+
+            let mut db = MiniBitcask::new(back)?;
+            assert_eq!(db.get(b"a")?, Some(b"11".to_vec()));
+            assert_eq!(db.get(b"b")?, Some(vec![7; 300]));
+            assert_eq!(db.get(b"c")?, None);
+        }
+
+        // rs2 的删除记录：value 长度为 0，mark 为 1
+        let rs2 = tmp_dir.path().join("rs2.data");
+        let mut data = Vec::new();
+        for (key, value, mark) in [("k1", "v1", RS2_PUT), ("k2", "v2", RS2_PUT), ("k1", "", RS2_DELETE)] {
+            data.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            data.extend_from_slice(&mark.to_be_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value.as_bytes());
+        }
+        std::fs::write(&rs2, &data)?;
+        assert_eq!(detect(&rs2)?, Format::Rs2);
+        let v1 = tmp_dir.path().join("v1.db");
+        assert_eq!(convert(&rs2, None, &v1, Format::V1)?, 1);
+        assert_eq!(MiniBitcask::new(v1.clone())?.get(b"k2")?, Some(b"v2".to_vec()));
+        // 目标已经存在时拒绝
+        assert!(convert(&rs2, None, &v1, Format::V2).is_err());
+
+        // namespace 里的 key 原样保留；它们不是 UTF-8，写不进 rs2，失败时不留下文件
+        let ns = tmp_dir.path().join("ns.db");
+        MiniBitcask::new(ns.clone())?.namespace("t")?.set(b"x", b"y".to_vec())?;
+        let ns_v2 = tmp_dir.path().join("ns.v2");
+        let ns_back = tmp_dir.path().join("ns.back");
+        convert(&ns, None, &ns_v2, Format::V2)?;
+        convert(&ns_v2, None, &ns_back, Format::Rs3)?;
+        assert_eq!(MiniBitcask::new(ns_back)?.namespace("t")?.get(b"x")?, Some(b"y".to_vec()));
+        let ns_rs2 = tmp_dir.path().join("ns.rs2");
+        assert!(convert(&ns, None, &ns_rs2, Format::Rs2).is_err());
+        assert!(!ns_rs2.exists());
+
+        // 源文件只读：写了一半的尾部不截断，也不追加关闭标记
+        let torn = tmp_dir.path().join("torn.db");
+        let mut db = MiniBitcask::new(torn.clone())?;
+        db.set(b"k", b"v".to_vec())?;
+        drop(db);
+        let mut bytes = std::fs::read(&torn)?;
+        bytes.extend_from_slice(&[0, 0, 0]);
+        std::fs::write(&torn, &bytes)?;
+        assert_eq!(convert(&torn, None, &tmp_dir.path().join("torn.v2"), Format::V2)?, 1);
+        assert_eq!(std::fs::read(&torn)?, bytes);
+
+        // 有 operand 和加密的日志用调用方给的 merge operator 和密钥读
+        let encryption = Encryption { key: [3; 32], mode: EncryptionMode::KeyAndValue };
+        let options = Options {
+            encryption: Some(encryption),
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Default::default()
+        };
+        let enc = tmp_dir.path().join("enc.db");
+        let mut db = MiniBitcask::open(enc.clone(), options.clone())?;
+        db.set(b"k", b"a".to_vec())?;
+        db.merge_value(b"k", b"b".to_vec())?;
+        db.close()?;
+        let bytes = std::fs::read(&enc)?;
+        assert!(convert(&enc, None, &tmp_dir.path().join("enc.fail"), Format::V1).is_err());
+        let enc_back = tmp_dir.path().join("enc.back");
+        assert_eq!(convert_with(&enc, None, &enc_back, Format::Rs3, &options)?, 1);
+        assert_eq!(std::fs::read(&enc)?, bytes);
+        assert_eq!(MiniBitcask::open(enc_back, options)?.get(b"k")?, Some(b"ab".to_vec()));
+
+        // 以后的版本号要报错，不能当作别的格式读
+        let v3 = tmp_dir.path().join("v3.db");
+        std::fs::write(&v3, b"MBCK\x03")?;
+        assert!(detect(&v3).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::sync::Arc;
+
+/// 认证标签的长度
+pub const TAG_LEN: usize = 16;
+/// 每段密文前面随机 nonce 的长度
+pub const NONCE_LEN: usize = 24;
+/// 每段密文比明文多这么多字节
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+const PART_KEY: u8 = 0;
+const PART_VALUE: u8 = 1;
+const PART_CHECK: u8 = 2;
+const CHECK_PLAINTEXT: &[u8] = b"mini-bitcask";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionMode {
+    /// 只加密 value，key 保持明文
+    #[default]
+    ValueOnly,
+    /// key 和 value 都加密
+    KeyAndValue,
+}
+
+/// 调用方提供的 256 位密钥和加密方式，只影响新创建的文件，已有文件按文件里记录的方式读
+#[derive(Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub key: [u8; 32],
+    pub mode: EncryptionMode,
+}
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 不要把密钥打进日志里
+        f.debug_struct("Encryption").field("key", &"..").field("mode", &self.mode).finish()
+    }
+}
+
+fn random_file_id() -> Result<u64> {
+    let mut file_id = [0; 8];
+    getrandom::getrandom(&mut file_id).map_err(|e| anyhow!("failed to generate file id: {}", e))?;
+    Ok(u64::from_be_bytes(file_id))
+}
+
+/// 一个日志文件用的加解密器
+///
+/// 用 XChaCha20-Poly1305，每段密文用随机的 24 字节 nonce，存在密文前面。
+/// 写入失败回滚或者截掉损坏的尾部之后，同一个位置会再写一次，所以 nonce 不能由位置推出来；
+/// file_id(8) + 偏移(8) + 类型(1) 作为附加数据参与认证，密文挪到别的位置或者别的文件就解不开。
+#[derive(Clone)]
+pub struct Cipher {
+    aead: Arc<XChaCha20Poly1305>,
+    file_id: u64,
+    mode: EncryptionMode,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").field("file_id", &self.file_id).field("mode", &self.mode).finish()
+    }
+}
+
+impl Cipher {
+    /// 给新文件生成一个随机的 file_id
+    pub fn generate(encryption: &Encryption) -> Result<Self> {
+        Ok(Self::new(encryption, random_file_id()?, encryption.mode))
+    }
+
+    /// 同一个密钥换一个新的 file_id
+    pub fn regenerate(&self) -> Result<Self> {
+        Ok(Self { file_id: random_file_id()?, ..self.clone() })
+    }
+
+    fn new(encryption: &Encryption, file_id: u64, mode: EncryptionMode) -> Self {
+        Self {
+            aead: Arc::new(XChaCha20Poly1305::new(&encryption.key.into())),
+            file_id,
+            mode,
+        }
+    }
+
+    // +-------------+-------------+---------------------+
+    // | file_id(8)  | mode(1)     | check(12 + OVERHEAD)|
+    // +-------------+-------------+---------------------+
+    /// 写在文件开头的元信息，check 用来判断密钥对不对
+    pub fn encode_meta(&self) -> Result<Vec<u8>> {
+        let mut meta = self.file_id.to_be_bytes().to_vec();
+        meta.push(self.mode as u8);
+        meta.extend(self.seal(0, PART_CHECK, CHECK_PLAINTEXT)?);
+        Ok(meta)
+    }
+
+    pub fn decode_meta(encryption: &Encryption, meta: &[u8]) -> Result<Self> {
+        if meta.len() != 9 + CHECK_PLAINTEXT.len() + OVERHEAD {
+            bail!("corrupted encryption header");
+        }
+        let file_id = u64::from_be_bytes(meta[..8].try_into()?);
+        let mode = match meta[8] {
+            0 => EncryptionMode::ValueOnly,
+            1 => EncryptionMode::KeyAndValue,
+            other => bail!("unknown encryption mode {}", other),
+        };
+        let cipher = Self::new(encryption, file_id, mode);
+        match cipher.open(0, PART_CHECK, &meta[9..]) {
+            Ok(check) if check == CHECK_PLAINTEXT => Ok(cipher),
+            _ => bail!("wrong encryption key"),
+        }
+    }
+
+    fn aad(&self, pos: u64, part: u8) -> [u8; 17] {
+        let mut aad = [0; 17];
+        aad[..8].copy_from_slice(&self.file_id.to_be_bytes());
+        aad[8..16].copy_from_slice(&pos.to_be_bytes());
+        aad[16] = part;
+        aad
+    }
+
+    // +-----------+---------------------------+
+    // | nonce(24) | ciphertext + tag(16)      |
+    // +-----------+---------------------------+
+    fn seal(&self, pos: u64, part: u8, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("failed to generate nonce: {}", e))?;
+        let aad = self.aad(pos, part);
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+            .map_err(|_| anyhow!("failed to encrypt record at {}", pos))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, pos: u64, part: u8, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            bail!("failed to decrypt record at {}: ciphertext too short", pos);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = self.aad(pos, part);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| anyhow!("failed to decrypt record at {}: data corrupted or wrong key", pos))
+    }
+
+    /// offset 是记录的起始位置
+    pub fn encrypt_key(&self, offset: u64, key: &[u8]) -> Result<Vec<u8>> {
+        match self.mode {
+            EncryptionMode::ValueOnly => Ok(key.to_vec()),
+            EncryptionMode::KeyAndValue => self.seal(offset, PART_KEY, key),
+        }
+    }
+
+    pub fn decrypt_key(&self, offset: u64, key: Vec<u8>) -> Result<Vec<u8>> {
+        match self.mode {
+            EncryptionMode::ValueOnly => Ok(key),
+            EncryptionMode::KeyAndValue => self.open(offset, PART_KEY, &key),
+        }
+    }
+
+    /// value_pos 是 value 在文件里的位置，按位置随机读的时候也能算出 nonce
+    pub fn encrypt_value(&self, value_pos: u64, value: &[u8]) -> Result<Vec<u8>> {
+        self.seal(value_pos, PART_VALUE, value)
+    }
+
+    pub fn decrypt_value(&self, value_pos: u64, value: &[u8]) -> Result<Vec<u8>> {
+        self.open(value_pos, PART_VALUE, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher() -> Result<()> {
+        let encryption = Encryption { key: [7; 32], mode: EncryptionMode::KeyAndValue };
+        let cipher = Cipher::generate(&encryption)?;
+        let meta = cipher.encode_meta()?;
+
+        let reopened = Cipher::decode_meta(&encryption, &meta)?;
+        let key = cipher.encrypt_key(10, b"k")?;
+        assert_eq!(key.len(), 1 + OVERHEAD);
+        // 同一个位置再写一次，nonce 也不一样
+        assert_ne!(cipher.encrypt_key(10, b"k")?, key);
+        assert_eq!(reopened.decrypt_key(10, key.clone())?, b"k");
+        // 位置不对也解不开
+        assert!(reopened.decrypt_key(11, key.clone()).is_err());
+        assert!(reopened.decrypt_key(10, key[..OVERHEAD - 1].to_vec()).is_err());
+        let value = cipher.encrypt_value(20, b"v")?;
+        assert_eq!(reopened.decrypt_value(20, &value)?, b"v");
+
+        let wrong = Encryption { key: [8; 32], ..encryption };
+        let err = Cipher::decode_meta(&wrong, &meta).unwrap_err();
+        assert_eq!(err.to_string(), "wrong encryption key");
+        Ok(())
+    }
+}
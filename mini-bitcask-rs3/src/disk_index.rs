@@ -0,0 +1,838 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+
+use crate::index::{IndexIter, Indexer, KeyRange, Position};
+use crate::lru::LruCache;
+use crate::storage::{FsStorage, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskIndexOptions {
+    /// 每个页的目标大小（字节）
+    pub page_size: usize,
+    /// 页缓存最多缓存多少个页
+    pub cache_pages: usize,
+    /// 内存里最多攒多少个 key 再刷到磁盘
+    pub memtable_keys: usize,
+    /// 同一层的有序文件攒够这么多个就合并成下一层的一个
+    pub fanout: usize,
+}
+
+impl Default for DiskIndexOptions {
+    fn default() -> Self {
+        Self {
+            page_size: 4096,
+            cache_pages: 256,
+            memtable_keys: 64 * 1024,
+            fanout: 4,
+        }
+    }
+}
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const RUN_EXTENSION: &str = "run";
+/// key_len 的最高位表示删除，后面没有位置
+const TOMBSTONE_BIT: u32 = 1 << 31;
+/// 有序文件末尾：稀疏索引的位置(8) 稀疏索引的 crc(4)
+const TRAILER_LEN: u64 = 12;
+
+/// 日志 path 对应的磁盘索引目录：`<path>.index`
+pub fn index_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".index");
+    dir.into()
+}
+
+type Page = Arc<Vec<(Vec<u8>, Option<Position>)>>;
+
+/// 稀疏索引：每个页只在内存里记录第一个 key 和页在文件中的位置
+#[derive(Debug)]
+struct PageMeta {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// 一个不再修改的有序文件，写完之后只会被合并掉
+#[derive(Debug)]
+struct Run {
+    id: u64,
+    /// 合并过几次，flush 出来的是 0
+    tier: u32,
+    file: Mutex<File>,
+    pages: Vec<PageMeta>,
+}
+
+impl Run {
+    /// 包含 key 的页
+    fn page_of(&self, key: &[u8]) -> Option<usize> {
+        self.pages
+            .partition_point(|p| p.first_key.as_slice() <= key)
+            .checked_sub(1)
+    }
+}
+
+/// 磁盘索引
+///
+/// 最近的写入先放在内存的 memtable 里，攒够 `memtable_keys` 个之后写成一个新的有序文件（run），
+/// 删除也作为一条记录写进去。同一层的文件攒够 `fanout` 个就合并成下一层的一个文件，
+/// 每个 key 被重写的次数是层数，和索引的大小成对数关系。查找从新到旧依次看 memtable 和每个文件。
+///
+/// 文件按页组织，每条记录是 `key_len(4) key value_pos(8) value_len(4) flags(1)`，
+/// 删除记录的 key_len 带上 `TOMBSTONE_BIT`，没有后面的位置；页的稀疏索引写在文件末尾。
+/// `persist` 把 memtable 刷下去，再把文件列表和日志的 checkpoint 写进 MANIFEST，下次打开时
+/// 不用从日志重建；打开之后第一次修改前删掉 MANIFEST，没有 persist 就退出的话下次从头重建。
+pub struct DiskIndex {
+    dir: PathBuf,
+    options: DiskIndexOptions,
+    // None 表示删除
+    memtable: BTreeMap<Vec<u8>, Option<Position>>,
+    /// 从新到旧，层数不减，同一层的文件挨在一起
+    runs: Vec<Run>,
+    next_id: u64,
+    len: usize,
+    cache: Mutex<LruCache<(u64, u64), Page>>,
+    /// 打开时从 MANIFEST 读出来、还没有被取走的 checkpoint
+    checkpoint: Option<Vec<u8>>,
+    /// 磁盘上的 MANIFEST 和当前的索引一致
+    persisted: bool,
+}
+
+impl DiskIndex {
+    /// 空的索引，dir 里上次留下的文件都删掉
+    pub fn new(dir: &Path, options: DiskIndexOptions) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        clear_dir(dir)?;
+        Ok(Self::new_empty(dir, options))
+    }
+
+    /// 打开 dir 里上次 persist 的索引，没有或者读不出来的话和 new 一样从空的开始
+    pub fn open(dir: &Path, options: DiskIndexOptions) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut index = Self::new_empty(dir, options);
+        match index.load() {
+            Ok(true) => Ok(index),
+            Ok(false) => Self::new(dir, options),
+            Err(e) => {
+                warn!("disk index in {} is unreadable, rebuilding it: {:#}", dir.display(), e);
+                drop(index);
+                Self::new(dir, options)
+            }
+        }
+    }
+
+    fn new_empty(dir: &Path, options: DiskIndexOptions) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            options,
+            memtable: BTreeMap::new(),
+            runs: Vec::new(),
+            next_id: 1,
+            len: 0,
+            cache: Mutex::new(LruCache::new(options.cache_pages)),
+            checkpoint: None,
+            persisted: false,
+        }
+    }
+
+    /// 读 MANIFEST 和里面列出的文件，没有 MANIFEST 时返回 false
+    fn load(&mut self) -> Result<bool> {
+        let data = match std::fs::read(self.dir.join(MANIFEST)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let Some((mut buf, crc)) = data.split_last_chunk::<4>() else {
+            bail!("manifest is truncated");
+        };
+        if crc32fast::hash(buf) != u32::from_be_bytes(*crc) {
+            bail!("manifest checksum mismatch");
+        }
+        self.next_id = read_u64(&mut buf)?;
+        self.len = read_u64(&mut buf)? as usize;
+        for _ in 0..read_u32(&mut buf)? {
+            let id = read_u64(&mut buf)?;
+            let tier = read_u32(&mut buf)?;
+            self.runs.push(open_run(&self.dir, id, tier)?);
+        }
+        let mut checkpoint = vec![0; read_u32(&mut buf)? as usize];
+        buf.read_exact(&mut checkpoint)?;
+        self.checkpoint = Some(checkpoint);
+        // MANIFEST 里没有的文件是上次没有 persist 就退出时留下的
+        let live = self.runs.iter().map(|r| r.id).collect();
+        remove_files(&self.dir, &live)?;
+        self.persisted = true;
+        Ok(true)
+    }
+
+    fn read_page(&self, run: &Run, idx: usize) -> Result<Page> {
+        let meta = &run.pages[idx];
+        let mut cache = self.cache.lock().map_err(|_| anyhow!("page cache poisoned"))?;
+        if let Some(page) = cache.get(&(run.id, meta.offset)) {
+            return Ok(page);
+        }
+        let mut buf = vec![0; meta.len as usize];
+        {
+            let mut file = run.file.lock().map_err(|_| anyhow!("index file poisoned"))?;
+            file.seek(SeekFrom::Start(meta.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        let page = Arc::new(decode_page(&buf)?);
+        cache.insert((run.id, meta.offset), page.clone(), 1);
+        Ok(page)
+    }
+
+    /// key 在这个文件里的记录，Some(None) 表示删除
+    fn get_from_run(&self, run: &Run, key: &[u8]) -> Result<Option<Option<Position>>> {
+        let Some(idx) = run.page_of(key) else {
+            return Ok(None);
+        };
+        let page = self.read_page(run, idx)?;
+        Ok(page
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|i| page[i].1))
+    }
+
+    fn disk_range<'a>(&'a self, run: &'a Run, range: &KeyRange) -> DiskRange<'a> {
+        let start = match &range.0 {
+            Bound::Included(k) | Bound::Excluded(k) => run.page_of(k).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        let end = match &range.1 {
+            Bound::Included(k) => run.pages.partition_point(|p| p.first_key <= *k),
+            Bound::Excluded(k) => run.pages.partition_point(|p| p.first_key < *k),
+            Bound::Unbounded => run.pages.len(),
+        };
+        DiskRange {
+            index: self,
+            run,
+            next_page: start,
+            end_page: end.max(start),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    /// 归并 runs（从新到旧）在 range 里的记录，删除记录也留着
+    fn merge_runs<'a>(&'a self, runs: &'a [Run], range: &KeyRange) -> Box<dyn DoubleEndedIterator<Item = MergeItem> + 'a> {
+        let mut merged: Box<dyn DoubleEndedIterator<Item = MergeItem> + 'a> = Box::new(std::iter::empty());
+        for run in runs.iter().rev() {
+            let range = range.clone();
+            let disk = self
+                .disk_range(run, &range)
+                .filter(move |r| r.as_ref().map_or(true, |(k, _)| range.contains(k)));
+            merged = Box::new(MergeIter::new(disk, merged));
+        }
+        merged
+    }
+
+    /// 把 memtable 写成一个新的文件，再看要不要合并
+    fn flush(&mut self) -> Result<()> {
+        let memtable = std::mem::take(&mut self.memtable);
+        // 没有更旧的文件时删除记录不用留
+        let keep_tombstones = !self.runs.is_empty();
+        let id = self.next_id;
+        self.next_id += 1;
+        let run = write_run(&self.dir, id, 0, self.options.page_size, memtable.into_iter().map(Ok), keep_tombstones)?;
+        if let Some(run) = run {
+            self.runs.insert(0, run);
+        }
+        self.compact()
+    }
+
+    /// 最新一层的文件数到了 fanout 就合并成下一层的一个文件，一直往下直到某一层没有满
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            let Some(tier) = self.runs.first().map(|r| r.tier) else {
+                return Ok(());
+            };
+            let count = self.runs.iter().take_while(|r| r.tier == tier).count();
+            if count < self.options.fanout.max(2) {
+                return Ok(());
+            }
+            // 合并到最旧的文件时删除记录已经没有要遮住的了
+            let keep_tombstones = count < self.runs.len();
+            let id = self.next_id;
+            self.next_id += 1;
+            let all = (Bound::Unbounded, Bound::Unbounded);
+            let run = write_run(
+                &self.dir,
+                id,
+                tier + 1,
+                self.options.page_size,
+                self.merge_runs(&self.runs[..count], &all),
+                keep_tombstones,
+            )?;
+            for old in self.runs.splice(..count, run) {
+                std::fs::remove_file(run_path(&self.dir, old.id))?;
+            }
+        }
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable.len() >= self.options.memtable_keys {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// 第一次修改之前删掉 MANIFEST，之后没有 persist 就退出的话下次打开从头重建
+    fn invalidate(&mut self) -> Result<()> {
+        if self.persisted {
+            std::fs::remove_file(self.dir.join(MANIFEST))?;
+            FsStorage.sync_dir(&self.dir)?;
+            self.persisted = false;
+        }
+        Ok(())
+    }
+}
+
+impl Indexer for DiskIndex {
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>> {
+        self.invalidate()?;
+        let old = self.get(&key)?;
+        if old.is_none() {
+            self.len += 1;
+        }
+        self.memtable.insert(key, Some(pos));
+        self.maybe_flush()?;
+        Ok(old)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Position>> {
+        if let Some(pos) = self.memtable.get(key) {
+            return Ok(*pos);
+        }
+        for run in &self.runs {
+            if let Some(pos) = self.get_from_run(run, key)? {
+                return Ok(pos);
+            }
+        }
+        Ok(None)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            self.invalidate()?;
+            self.len -= 1;
+            self.memtable.insert(key.to_vec(), None);
+            self.maybe_flush()?;
+        }
+        Ok(old)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn range(&self, range: KeyRange) -> IndexIter<'_> {
+        let mem = self
+            .memtable
+            .range(range.clone())
+            .map(|(k, v)| Ok((k.clone(), *v)));
+        let disk = self.merge_runs(&self.runs, &range);
+        Box::new(MergeIter::new(mem, disk).filter_map(|r| match r {
+            Ok((key, Some(pos))) => Some(Ok((key, pos))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        }))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let memtable = self
+            .memtable
+            .keys()
+            .map(|k| k.capacity() + (size_of::<Vec<u8>>() + size_of::<Option<Position>>()) * 3 / 2)
+            .sum::<usize>();
+        let sparse = self
+            .runs
+            .iter()
+            .flat_map(|r| &r.pages)
+            .map(|p| p.first_key.capacity() + size_of::<PageMeta>())
+            .sum::<usize>();
+        let cache = self.cache.lock().map_or(0, |c| c.len()) * self.options.page_size;
+        memtable + sparse + cache
+    }
+
+    fn take_checkpoint(&mut self) -> Option<Vec<u8>> {
+        self.checkpoint.take()
+    }
+
+    fn persist(&mut self, checkpoint: &[u8]) -> Result<()> {
+        if !self.memtable.is_empty() {
+            self.flush()?;
+        }
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.next_id.to_be_bytes());
+        data.extend_from_slice(&(self.len as u64).to_be_bytes());
+        data.extend_from_slice(&(self.runs.len() as u32).to_be_bytes());
+        for run in &self.runs {
+            data.extend_from_slice(&run.id.to_be_bytes());
+            data.extend_from_slice(&run.tier.to_be_bytes());
+        }
+        data.extend_from_slice(&(checkpoint.len() as u32).to_be_bytes());
+        data.extend_from_slice(checkpoint);
+        data.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+        // 先写临时文件再 rename，MANIFEST 要么是旧的要么是完整的新的
+        let tmp = self.dir.join(MANIFEST_TMP);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, self.dir.join(MANIFEST))?;
+        FsStorage.sync_dir(&self.dir)?;
+        self.persisted = true;
+        Ok(())
+    }
+
+    fn rename(&mut self, path: &Path) -> Result<()> {
+        let dir = index_dir(path);
+        if dir == self.dir {
+            return Ok(());
+        }
+        // 目标上是被替换掉的日志的索引，rename 不能覆盖非空目录
+        if dir.exists() {
+            clear_dir(&dir)?;
+            std::fs::remove_dir(&dir)?;
+        }
+        // 打开的文件句柄不受目录 rename 的影响
+        std::fs::rename(&self.dir, &dir)?;
+        if let Some(parent) = dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+            FsStorage.sync_dir(parent)?;
+        }
+        self.dir = dir;
+        Ok(())
+    }
+}
+
+fn run_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, RUN_EXTENSION))
+}
+
+/// 删掉 dir 里索引的所有文件，其他文件不动
+fn clear_dir(dir: &Path) -> Result<()> {
+    remove_files(dir, &HashSet::new())?;
+    match std::fs::remove_file(dir.join(MANIFEST)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// 删掉 dir 里不在 live 里的有序文件和写了一半的 MANIFEST
+fn remove_files(dir: &Path, live: &HashSet<u64>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = match path.extension().and_then(|e| e.to_str()) {
+            Some(RUN_EXTENSION) => path
+                .file_stem()
+                .and_then(|s| s.to_str()?.parse::<u64>().ok())
+                .is_some_and(|id| !live.contains(&id)),
+            _ => path.file_name().is_some_and(|n| n == MANIFEST_TMP),
+        };
+        if stale {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// 把有序的 entries 写成一个新的有序文件，keep_tombstones 为 false 时丢掉删除记录；一条都没有的话返回 None
+fn write_run(
+    dir: &Path,
+    id: u64,
+    tier: u32,
+    page_size: usize,
+    entries: impl Iterator<Item = MergeItem>,
+    keep_tombstones: bool,
+) -> Result<Option<Run>> {
+    let path = run_path(dir, id);
+    let mut file = File::options().read(true).write(true).create(true).truncate(true).open(&path)?;
+    let mut pages = Vec::new();
+    {
+        let mut w = BufWriter::new(&mut file);
+        let mut page = Vec::with_capacity(page_size);
+        let mut first_key = Vec::new();
+        let mut offset = 0u64;
+        for item in entries {
+            let (key, pos) = item?;
+            if pos.is_none() && !keep_tombstones {
+                continue;
+            }
+            if page.is_empty() {
+                first_key = key.clone();
+            }
+            encode_entry(&mut page, &key, pos);
+            if page.len() >= page_size {
+                w.write_all(&page)?;
+                pages.push(PageMeta {
+                    first_key: std::mem::take(&mut first_key),
+                    offset,
+                    len: page.len() as u32,
+                });
+                offset += page.len() as u64;
+                page.clear();
+            }
+        }
+        if !page.is_empty() {
+            w.write_all(&page)?;
+            pages.push(PageMeta {
+                first_key,
+                offset,
+                len: page.len() as u32,
+            });
+            offset += page.len() as u64;
+        }
+        // 稀疏索引写在最后，打开时只用读文件末尾
+        let mut meta = Vec::new();
+        for p in &pages {
+            meta.extend_from_slice(&p.offset.to_be_bytes());
+            meta.extend_from_slice(&p.len.to_be_bytes());
+            meta.extend_from_slice(&(p.first_key.len() as u32).to_be_bytes());
+            meta.extend_from_slice(&p.first_key);
+        }
+        w.write_all(&meta)?;
+        w.write_all(&offset.to_be_bytes())?;
+        w.write_all(&crc32fast::hash(&meta).to_be_bytes())?;
+        w.flush()?;
+    }
+    if pages.is_empty() {
+        drop(file);
+        std::fs::remove_file(&path)?;
+        return Ok(None);
+    }
+    file.sync_all()?;
+    Ok(Some(Run {
+        id,
+        tier,
+        file: Mutex::new(file),
+        pages,
+    }))
+}
+
+/// 打开 write_run 写的文件，读出末尾的稀疏索引
+fn open_run(dir: &Path, id: u64, tier: u32) -> Result<Run> {
+    let mut file = File::open(run_path(dir, id))?;
+    let len = file.metadata()?.len();
+    if len < TRAILER_LEN {
+        bail!("index file {} is truncated", id);
+    }
+    let mut trailer = [0; TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+    file.read_exact(&mut trailer)?;
+    let mut buf = &trailer[..];
+    let meta_offset = read_u64(&mut buf)?;
+    let crc = read_u32(&mut buf)?;
+    if meta_offset > len - TRAILER_LEN {
+        bail!("index file {} is corrupted", id);
+    }
+    let mut meta = vec![0; (len - TRAILER_LEN - meta_offset) as usize];
+    file.seek(SeekFrom::Start(meta_offset))?;
+    file.read_exact(&mut meta)?;
+    if crc32fast::hash(&meta) != crc {
+        bail!("index file {} checksum mismatch", id);
+    }
+    let mut buf = &meta[..];
+    let mut pages = Vec::new();
+    while !buf.is_empty() {
+        let offset = read_u64(&mut buf)?;
+        let len = read_u32(&mut buf)?;
+        let mut first_key = vec![0; read_u32(&mut buf)? as usize];
+        buf.read_exact(&mut first_key)?;
+        pages.push(PageMeta { first_key, offset, len });
+    }
+    Ok(Run {
+        id,
+        tier,
+        file: Mutex::new(file),
+        pages,
+    })
+}
+
+fn encode_entry(page: &mut Vec<u8>, key: &[u8], pos: Option<Position>) {
+    match pos {
+        Some((value_pos, value_len, flags)) => {
+            page.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            page.extend_from_slice(key);
+            page.extend_from_slice(&value_pos.to_be_bytes());
+            page.extend_from_slice(&value_len.to_be_bytes());
+            page.push(flags);
+        }
+        None => {
+            page.extend_from_slice(&(key.len() as u32 | TOMBSTONE_BIT).to_be_bytes());
+            page.extend_from_slice(key);
+        }
+    }
+}
+
+fn decode_page(mut buf: &[u8]) -> Result<Vec<(Vec<u8>, Option<Position>)>> {
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let key_len = read_u32(&mut buf)?;
+        let mut key = vec![0; (key_len & !TOMBSTONE_BIT) as usize];
+        buf.read_exact(&mut key)?;
+        if key_len & TOMBSTONE_BIT != 0 {
+            entries.push((key, None));
+            continue;
+        }
+        let value_pos = read_u64(&mut buf)?;
+        let value_len = read_u32(&mut buf)?;
+        let mut flags = [0; 1];
+        buf.read_exact(&mut flags)?;
+        entries.push((key, Some((value_pos, value_len, flags[0]))));
+    }
+    Ok(entries)
+}
+
+fn read_u32(buf: &mut &[u8]) -> Result<u32> {
+    let mut b = [0; 4];
+    buf.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut b = [0; 8];
+    buf.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+/// 按顺序遍历 [next_page, end_page) 这些页，两头都可以取
+struct DiskRange<'a> {
+    index: &'a DiskIndex,
+    run: &'a Run,
+    next_page: usize,
+    end_page: usize,
+    front: VecDeque<(Vec<u8>, Option<Position>)>,
+    back: VecDeque<(Vec<u8>, Option<Position>)>,
+}
+
+impl DiskRange<'_> {
+    fn load(&mut self, idx: usize) -> Result<VecDeque<(Vec<u8>, Option<Position>)>> {
+        match self.index.read_page(self.run, idx) {
+            Ok(page) => Ok(page.iter().cloned().collect()),
+            Err(e) => {
+                // 出错之后不再继续
+                self.next_page = self.end_page;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Iterator for DiskRange<'_> {
+    type Item = MergeItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.front.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.next_page < self.end_page {
+                self.front = match self.load(self.next_page) {
+                    Ok(page) => page,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.next_page += 1;
+                continue;
+            }
+            return self.back.pop_front().map(Ok);
+        }
+    }
+}
+
+impl DoubleEndedIterator for DiskRange<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back.pop_back() {
+                return Some(Ok(entry));
+            }
+            if self.next_page < self.end_page {
+                self.back = match self.load(self.end_page - 1) {
+                    Ok(page) => page,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.end_page -= 1;
+                continue;
+            }
+            return self.front.pop_back().map(Ok);
+        }
+    }
+}
+
+type MergeItem = Result<(Vec<u8>, Option<Position>)>;
+
+/// 归并两个有序的迭代器，key 相同时以 a（更新的数据）为准
+struct MergeIter<A, B> {
+    a: A,
+    b: B,
+    a_front: Option<MergeItem>,
+    b_front: Option<MergeItem>,
+    a_back: Option<MergeItem>,
+    b_back: Option<MergeItem>,
+}
+
+impl<A, B> MergeIter<A, B> {
+    fn new(a: A, b: B) -> Self {
+        Self {
+            a,
+            b,
+            a_front: None,
+            b_front: None,
+            a_back: None,
+            b_back: None,
+        }
+    }
+}
+
+impl<A, B> Iterator for MergeIter<A, B>
+where
+    A: Iterator<Item = MergeItem>,
+    B: Iterator<Item = MergeItem>,
+{
+    type Item = MergeItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.a_front.is_none() {
+            self.a_front = self.a.next().or_else(|| self.a_back.take());
+        }
+        if self.b_front.is_none() {
+            self.b_front = self.b.next().or_else(|| self.b_back.take());
+        }
+        match (&self.a_front, &self.b_front) {
+            (Some(Ok((ka, _))), Some(Ok((kb, _)))) => match ka.cmp(kb) {
+                Ordering::Less => self.a_front.take(),
+                Ordering::Greater => self.b_front.take(),
+                Ordering::Equal => {
+                    self.b_front = None;
+                    self.a_front.take()
+                }
+            },
+            (Some(Err(_)), _) | (Some(_), None) => self.a_front.take(),
+            _ => self.b_front.take(),
+        }
+    }
+}
+
+impl<A, B> DoubleEndedIterator for MergeIter<A, B>
+where
+    A: DoubleEndedIterator<Item = MergeItem>,
+    B: DoubleEndedIterator<Item = MergeItem>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.a_back.is_none() {
+            self.a_back = self.a.next_back().or_else(|| self.a_front.take());
+        }
+        if self.b_back.is_none() {
+            self.b_back = self.b.next_back().or_else(|| self.b_front.take());
+        }
+        match (&self.a_back, &self.b_back) {
+            (Some(Ok((ka, _))), Some(Ok((kb, _)))) => match ka.cmp(kb) {
+                Ordering::Greater => self.a_back.take(),
+                Ordering::Less => self.b_back.take(),
+                Ordering::Equal => {
+                    self.b_back = None;
+                    self.a_back.take()
+                }
+            },
+            (Some(Err(_)), _) | (Some(_), None) => self.a_back.take(),
+            _ => self.b_back.take(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_index_bounded_memory() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let options = DiskIndexOptions {
+            page_size: 256,
+            cache_pages: 8,
+            memtable_keys: 500,
+            ..Default::default()
+        };
+        let mut index = DiskIndex::new(tmp_dir.path(), options)?;
+        for i in 0..5000u32 {
+            index.put(format!("key{:06}", i).into_bytes(), (i as u64, i, i as u8))?;
+        }
+        for i in (0..5000u32).step_by(3) {
+            index.delete(format!("key{:06}", i).as_bytes())?;
+        }
+        assert_eq!(index.len(), 3333);
+        for i in 0..5000u32 {
+            let expected = if i % 3 == 0 { None } else { Some((i as u64, i, i as u8)) };
+            assert_eq!(index.get(format!("key{:06}", i).as_bytes())?, expected);
+        }
+        // 内存里只有 memtable、稀疏索引和有限的页缓存
+        assert!(index.memtable.len() < options.memtable_keys);
+        assert!(index.cache.lock().unwrap().len() <= options.cache_pages);
+        assert!(index.memory_usage() < 3333 * 16, "{}", index.memory_usage());
+
+        let keys = index
+            .range((Bound::Included(b"key004990".to_vec()), Bound::Unbounded))
+            .map(|r| r.map(|(k, _)| String::from_utf8(k).unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, ["key004990", "key004991", "key004993", "key004994", "key004996", "key004997", "key004999"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_index_compaction_and_reopen() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let options = DiskIndexOptions {
+            page_size: 256,
+            cache_pages: 8,
+            memtable_keys: 100,
+            fanout: 3,
+        };
+        let mut index = DiskIndex::new(tmp_dir.path(), options)?;
+        for round in 0..3u32 {
+            for i in 0..1000u32 {
+                index.put(format!("key{:06}", i).into_bytes(), (i as u64, round, 0))?;
+            }
+        }
+        for i in (0..1000u32).step_by(2) {
+            index.delete(format!("key{:06}", i).as_bytes())?;
+        }
+        // 30 次 flush 合并之后只剩每层不到 fanout 个文件
+        assert!(index.runs.len() <= 6, "{}", index.runs.len());
+        assert!(index.runs.windows(2).all(|w| w[0].tier <= w[1].tier));
+        // 最旧的文件里没有删除记录
+        let oldest = index.runs.last().unwrap();
+        let entries = index.disk_range(oldest, &(Bound::Unbounded, Bound::Unbounded)).collect::<Result<Vec<_>>>()?;
+        assert!(entries.iter().all(|(_, pos)| pos.is_some()));
+        let files = std::fs::read_dir(tmp_dir.path())?.count();
+        assert_eq!(files, index.runs.len());
+
+        index.persist(b"checkpoint")?;
+        drop(index);
+        let mut index = DiskIndex::open(tmp_dir.path(), options)?;
+        assert_eq!(index.take_checkpoint(), Some(b"checkpoint".to_vec()));
+        assert_eq!(index.len(), 500);
+        for i in 0..1000u32 {
+            let expected = (i % 2 == 1).then_some((i as u64, 2, 0));
+            assert_eq!(index.get(format!("key{:06}", i).as_bytes())?, expected);
+        }
+        assert_eq!(index.range((Bound::Unbounded, Bound::Unbounded)).count(), 500);
+
+        // 修改之后没有 persist，下次打开是空的
+        index.put(b"new".to_vec(), (1, 1, 0))?;
+        drop(index);
+        let mut index = DiskIndex::open(tmp_dir.path(), options)?;
+        assert_eq!(index.take_checkpoint(), None);
+        assert!(index.is_empty());
+        assert_eq!(std::fs::read_dir(tmp_dir.path())?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! C 接口，头文件是 `include/bitcask.h`，由 build.rs 用 cbindgen 生成
+//!
+//! 所有权：
+//! - `bitcask_get` 返回的 value 归调用方，用完调用 `bitcask_free_buffer` 释放；
+//! - `bitcask_iter_next` 返回的 key 和 value 归迭代器，下一次 `bitcask_iter_next`
+//!   或 `bitcask_iter_free` 之后失效，不要释放；
+//! - `bitcask_last_error` 返回的字符串归库所有，同一个线程上下一次调用 `bitcask_*` 之后失效；
+//! - 传进来的 key、value 只在调用期间使用，调用返回之后调用方可以释放。
+//!
+//! 一个 Bitcask 不能同时在多个线程里用，迭代器是快照，持有期间可以继续读写。
+
+use crate::bitcask::{MiniBitcask, Options};
+use crate::index::prefix_range;
+use crate::namespace;
+use crate::snapshot::SnapshotIter;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+/// 返回值，BITCASK_STATUS_ERROR 时用 `bitcask_last_error` 取错误信息
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitcaskStatus {
+    Ok = 0,
+    /// key 不存在，或者迭代器已经到头
+    NotFound = 1,
+    /// 空指针、路径不是 UTF-8 之类的参数错误
+    InvalidArgument = 2,
+    Error = 3,
+}
+
+/// 打开的数据库，只能通过指针使用
+pub struct Bitcask {
+    db: MiniBitcask,
+}
+
+/// `bitcask_iter` 返回的迭代器
+pub struct BitcaskIter {
+    inner: SnapshotIter,
+    // 最近一次返回的 key 和 value
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // 错误信息里不会有 \0，万一有就截断
+    let message = CString::new(message).unwrap_or_else(|e| {
+        let end = e.nul_position();
+        CString::new(&e.into_vec()[..end]).unwrap_or_default()
+    });
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// 执行 f，把错误和 panic 转成状态码，不让 panic 跨过 C 的边界
+fn call(f: impl FnOnce() -> Result<BitcaskStatus>) -> BitcaskStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            BitcaskStatus::Error
+        }
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(format!("panic: {}", message));
+            BitcaskStatus::Error
+        }
+    }
+}
+
+fn invalid(message: &str) -> BitcaskStatus {
+    set_last_error(message.to_string());
+    BitcaskStatus::InvalidArgument
+}
+
+/// len 为 0 时允许 data 为空指针
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (_, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(unsafe { std::slice::from_raw_parts(data, len) }),
+    }
+}
+
+/// 打开 path 上的数据库，成功时把句柄写到 out
+///
+/// # Safety
+/// path 是以 \0 结尾的字符串，out 指向可写的指针。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_open(path: *const c_char, out: *mut *mut Bitcask) -> BitcaskStatus {
+    if path.is_null() || out.is_null() {
+        return invalid("path and out must not be null");
+    }
+    let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+        return invalid("path is not valid UTF-8");
+    };
+    call(|| {
+        let db = MiniBitcask::open(PathBuf::from(path), Options::default())?;
+        unsafe { *out = Box::into_raw(Box::new(Bitcask { db })) };
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 刷盘并关闭数据库，之后 db 不能再用，刷盘失败时也会关闭
+///
+/// # Safety
+/// db 是 `bitcask_open` 返回的句柄，或者空指针。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_close(db: *mut Bitcask) -> BitcaskStatus {
+    if db.is_null() {
+        return BitcaskStatus::Ok;
+    }
+    let db = unsafe { Box::from_raw(db) };
+    call(move || {
+        db.db.close()?;
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 写入 key，value_len 为 0 时 value 可以是空指针
+///
+/// # Safety
+/// db 是打开的句柄，key、value 分别指向 key_len、value_len 个字节。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_put(
+    db: *mut Bitcask,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> BitcaskStatus {
+    let (Some(db), Some(key), Some(value)) = (unsafe { db.as_mut() }, unsafe { bytes(key, key_len) }, unsafe { bytes(value, value_len) }) else {
+        return invalid("db, key and value must not be null");
+    };
+    call(|| {
+        db.db.set(key, value.to_vec())?;
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 读取 key，存在时把 value 写到 value_out / value_len_out 并返回 BITCASK_STATUS_OK，
+/// 不存在时返回 BITCASK_STATUS_NOT_FOUND
+///
+/// value 归调用方，用 `bitcask_free_buffer` 释放。
+///
+/// # Safety
+/// db 是打开的句柄，key 指向 key_len 个字节，value_out 和 value_len_out 可写。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_get(
+    db: *mut Bitcask,
+    key: *const u8,
+    key_len: usize,
+    value_out: *mut *mut u8,
+    value_len_out: *mut usize,
+) -> BitcaskStatus {
+    let (Some(db), Some(key)) = (unsafe { db.as_mut() }, unsafe { bytes(key, key_len) }) else {
+        return invalid("db and key must not be null");
+    };
+    if value_out.is_null() || value_len_out.is_null() {
+        return invalid("value_out and value_len_out must not be null");
+    }
+    call(|| {
+        let Some(value) = db.db.get(key)? else {
+            return Ok(BitcaskStatus::NotFound);
+        };
+        let value = value.into_boxed_slice();
+        unsafe {
+            *value_len_out = value.len();
+            *value_out = Box::into_raw(value).cast();
+        }
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 释放 `bitcask_get` 返回的 value，len 要和返回的长度一致
+///
+/// # Safety
+/// data 和 len 是 `bitcask_get` 返回的，并且没有释放过；data 为空指针时什么都不做。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_free_buffer(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)) });
+    }
+}
+
+/// 删除 key，key 不存在时也返回 BITCASK_STATUS_OK
+///
+/// # Safety
+/// db 是打开的句柄，key 指向 key_len 个字节。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_delete(db: *mut Bitcask, key: *const u8, key_len: usize) -> BitcaskStatus {
+    let (Some(db), Some(key)) = (unsafe { db.as_mut() }, unsafe { bytes(key, key_len) }) else {
+        return invalid("db and key must not be null");
+    };
+    call(|| {
+        db.db.delete(key)?;
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 压缩日志
+///
+/// # Safety
+/// db 是打开的句柄。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_merge(db: *mut Bitcask) -> BitcaskStatus {
+    let Some(db) = (unsafe { db.as_mut() }) else {
+        return invalid("db must not be null");
+    };
+    call(|| {
+        db.db.merge()?;
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 按 key 的顺序遍历以 prefix 开头的 key，prefix_len 为 0 时遍历所有 key
+///
+/// 迭代器是创建时的快照，用 `bitcask_iter_free` 释放。创建时复制 prefix 范围内的索引项（key 和位置），
+/// 代价和范围里 key 的数量成正比，prefix 为空时是整个索引。
+///
+/// # Safety
+/// db 是打开的句柄，prefix 指向 prefix_len 个字节，out 可写。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter(
+    db: *mut Bitcask,
+    prefix: *const u8,
+    prefix_len: usize,
+    out: *mut *mut BitcaskIter,
+) -> BitcaskStatus {
+    let (Some(db), Some(prefix)) = (unsafe { db.as_mut() }, unsafe { bytes(prefix, prefix_len) }) else {
+        return invalid("db and prefix must not be null");
+    };
+    if out.is_null() {
+        return invalid("out must not be null");
+    }
+    call(|| {
+        let inner = db.db.snapshot_raw(prefix_range(&namespace::escape_key(prefix)), true)?.iter();
+        unsafe { *out = Box::into_raw(Box::new(BitcaskIter { inner, current: None })) };
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 取下一个 key 和 value，没有了返回 BITCASK_STATUS_NOT_FOUND
+///
+/// 返回的 key 和 value 归迭代器，下一次调用之前有效。
+///
+/// # Safety
+/// iter 是 `bitcask_iter` 返回的迭代器，四个输出参数都可写。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter_next(
+    iter: *mut BitcaskIter,
+    key_out: *mut *const u8,
+    key_len_out: *mut usize,
+    value_out: *mut *const u8,
+    value_len_out: *mut usize,
+) -> BitcaskStatus {
+    let Some(iter) = (unsafe { iter.as_mut() }) else {
+        return invalid("iter must not be null");
+    };
+    if key_out.is_null() || key_len_out.is_null() || value_out.is_null() || value_len_out.is_null() {
+        return invalid("output arguments must not be null");
+    }
+    call(|| {
+        iter.current = iter.inner.next().transpose()?;
+        let Some((key, value)) = &iter.current else {
+            return Ok(BitcaskStatus::NotFound);
+        };
+        unsafe {
+            *key_out = key.as_ptr();
+            *key_len_out = key.len();
+            *value_out = value.as_ptr();
+            *value_len_out = value.len();
+        }
+        Ok(BitcaskStatus::Ok)
+    })
+}
+
+/// 释放迭代器
+///
+/// # Safety
+/// iter 是 `bitcask_iter` 返回的迭代器并且没有释放过，或者空指针。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter_free(iter: *mut BitcaskIter) {
+    if !iter.is_null() {
+        drop(unsafe { Box::from_raw(iter) });
+    }
+}
+
+/// 当前线程上最近一次调用失败的原因，成功时返回空指针
+#[unsafe(no_mangle)]
+pub extern "C" fn bitcask_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::NAMESPACE_PREFIX;
+
+    #[test]
+    fn test_ffi() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let path = CString::new(tmp_dir.path().join("ffi.db").to_str().unwrap())?;
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(bitcask_open(path.as_ptr(), &mut db), BitcaskStatus::Ok);
+            assert!(bitcask_last_error().is_null());
+            assert_eq!(bitcask_put(db, b"a".as_ptr(), 1, b"1".as_ptr(), 1), BitcaskStatus::Ok);
+            assert_eq!(bitcask_put(db, b"b".as_ptr(), 1, ptr::null(), 0), BitcaskStatus::Ok);
+
+            let (mut value, mut len) = (ptr::null_mut(), 0);
+            assert_eq!(bitcask_get(db, b"a".as_ptr(), 1, &mut value, &mut len), BitcaskStatus::Ok);
+            assert_eq!(std::slice::from_raw_parts(value, len), b"1");
+            bitcask_free_buffer(value, len);
+            assert_eq!(bitcask_get(db, b"c".as_ptr(), 1, &mut value, &mut len), BitcaskStatus::NotFound);
+
+            assert_eq!(bitcask_put(db, ptr::null(), 1, ptr::null(), 0), BitcaskStatus::InvalidArgument);
+            let message = CStr::from_ptr(bitcask_last_error()).to_str()?;
+            assert!(message.contains("must not be null"));
+
+            // 以命名空间保留的前缀开头的 key 也能正常读写
+            let key = [NAMESPACE_PREFIX, b"a"].concat();
+            assert_eq!(bitcask_put(db, key.as_ptr(), key.len(), b"2".as_ptr(), 1), BitcaskStatus::Ok);
+            assert_eq!(bitcask_get(db, key.as_ptr(), key.len(), &mut value, &mut len), BitcaskStatus::Ok);
+            assert_eq!(std::slice::from_raw_parts(value, len), b"2");
+            bitcask_free_buffer(value, len);
+
+            // 同一个文件不能打开两次，错误信息从 anyhow 带过来
+            let mut other = ptr::null_mut();
+            assert_eq!(bitcask_open(path.as_ptr(), &mut other), BitcaskStatus::Error);
+            assert!(other.is_null());
+            assert!(!bitcask_last_error().is_null());
+            assert_eq!(bitcask_close(db), BitcaskStatus::Ok);
+        }
+        Ok(())
+    }
+}
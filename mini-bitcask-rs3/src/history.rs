@@ -0,0 +1,166 @@
+use crate::index::Position;
+use crate::log::{Log, FLAG_MERGE_MARK, FLAG_META, FLAG_OPERAND, VALUE_FLAGS};
+use crate::merge_operator::MergeOperator;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 开启历史版本之后，merge 保留哪些旧版本
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// 全部保留
+    #[default]
+    All,
+    /// 每个 key 最多保留最近的 n 个版本（包括当前的）
+    Versions(usize),
+    /// 保留这段时间内写入的版本，以及这段时间开始时的那个版本
+    Duration(Duration),
+}
+
+/// `get_as_of` 按 seq 还是按写入时间找
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    Seq(u64),
+    Time(SystemTime),
+}
+
+/// key 的一个历史版本，value 为 None 表示这个版本是删除
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub seq: u64,
+    /// 没有记录写入时间的旧记录是 None
+    pub timestamp: Option<SystemTime>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// 日志里的一条记录，value_pos 在同一个文件里是唯一的，用来标识记录
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub seq: u64,
+    pub timestamp: Option<u64>,
+    pub value_pos: u64,
+    /// None 表示删除
+    pub pos: Option<Position>,
+    pub operand: bool,
+}
+
+/// 每个 key 所有还在日志里的版本，按写入的顺序排列
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    pub keys: HashMap<Vec<u8>, Vec<Record>>,
+}
+
+impl History {
+    /// 扫一遍日志，重建所有 key 的版本
+    pub fn load(log: &mut Log) -> Result<Self> {
+        let mut history = Self::default();
+        for item in log.iter(false)? {
+            let (entry, _) = item?;
+            if entry.flags & (FLAG_META | FLAG_MERGE_MARK) != 0 {
+                continue;
+            }
+            let record = Record {
+                seq: entry.seq,
+                timestamp: entry.timestamp,
+                value_pos: entry.value_pos,
+                pos: entry.value_len.map(|len| (entry.value_pos, len, entry.flags & VALUE_FLAGS)),
+                operand: entry.flags & FLAG_OPERAND != 0,
+            };
+            history.push(&entry.key, record);
+        }
+        Ok(history)
+    }
+
+    pub fn push(&mut self, key: &[u8], record: Record) {
+        self.keys.entry(key.to_vec()).or_default().push(record);
+    }
+
+    pub fn records(&self, key: &[u8]) -> &[Record] {
+        self.keys.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    /// 按 retention 计算 merge 之后要留下的记录，now 是毫秒
+    pub fn retained(&self, retention: Retention, now: u64) -> HashSet<u64> {
+        let mut retained = HashSet::new();
+        for records in self.keys.values() {
+            let keep = match retention {
+                Retention::All => records.len(),
+                Retention::Versions(n) => n.max(1),
+                Retention::Duration(d) => {
+                    let cutoff = now.saturating_sub(d.as_millis() as u64);
+                    // 删除了很久的 key 整个丢掉
+                    if records.last().is_some_and(|r| r.pos.is_none() && r.timestamp.is_none_or(|t| t < cutoff)) {
+                        continue;
+                    }
+                    // 时间点之后写入的，再加上时间点上生效的那个版本
+                    let newer = records.iter().rev().take_while(|r| r.timestamp.is_some_and(|t| t >= cutoff)).count();
+                    newer + 1
+                }
+            };
+            retained.extend(records.iter().rev().take(keep).map(|r| r.value_pos));
+        }
+        retained
+    }
+}
+
+pub(crate) fn to_system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// records[i] 这个版本的值，operand 要和之前的版本合并
+pub(crate) fn value_at(
+    records: &[Record],
+    i: usize,
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    mut read: impl FnMut(Position) -> Result<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    if !records[i].operand {
+        return records[i].pos.map(read).transpose();
+    }
+    let Some(operator) = operator else {
+        bail!("key {:?} has merge operands but no merge operator is configured", key);
+    };
+    // 最近的完整版本，更早的已经被 merge 掉时从空值开始合并
+    let base = records[..i].iter().rposition(|r| !r.operand);
+    let existing = base.and_then(|b| records[b].pos).map(&mut read).transpose()?;
+    let first = base.map_or(0, |b| b + 1);
+    let operands = records[first..=i].iter().filter_map(|r| r.pos).map(read).collect::<Result<Vec<_>>>()?;
+    operator.full_merge(key, existing.as_deref(), &operands).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u64, timestamp: u64) -> Record {
+        Record {
+            seq,
+            timestamp: Some(timestamp),
+            value_pos: seq * 100,
+            pos: Some((seq * 100, 1, 0)),
+            operand: false,
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut history = History::default();
+        for seq in 1..=5 {
+            history.push(b"a", record(seq, seq * 1000));
+        }
+        history.push(b"b", record(6, 500));
+        history.push(b"c", Record { pos: None, ..record(7, 600) });
+
+        let mut kept = history.retained(Retention::Versions(2), 0).into_iter().collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, vec![400, 500, 600, 700]);
+        assert_eq!(history.retained(Retention::Versions(0), 0).len(), 3);
+        assert_eq!(history.retained(Retention::All, 0).len(), 7);
+
+        // 3500 时生效的是 seq 3，之后写的是 4、5；b 只有当前版本，c 很早就删掉了
+        let mut kept = history.retained(Retention::Duration(Duration::from_millis(1500)), 5000).into_iter().collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, vec![300, 400, 500, 600]);
+    }
+}
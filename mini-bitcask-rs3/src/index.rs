@@ -0,0 +1,577 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crossbeam_skiplist::SkipMap;
+
+use crate::disk_index::{index_dir, DiskIndex, DiskIndexOptions};
+
+/// value 在日志文件中的位置：(value_pos, value_len, flags)，flags 只留读 value 要用的几位
+pub type Position = (u64, u32, u8);
+
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// 以 prefix 开头的所有 key
+pub fn prefix_range(prefix: &[u8]) -> KeyRange {
+    // 去掉末尾的 0xff 再把最后一个字节加一，就是第一个不以 prefix 开头的 key
+    let end = match prefix.iter().rposition(|&b| b != 0xff) {
+        Some(i) => {
+            let mut end = prefix[..=i].to_vec();
+            end[i] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+pub type IndexIter<'a> = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Position)>> + 'a>;
+
+/// 索引（KeyDir）的抽象，key -> value 的位置
+///
+/// 内存实现不会出错，Result 是给磁盘索引用的。
+pub trait Indexer: Send {
+    /// 写入索引，返回旧的位置
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>>;
+    fn get(&self, key: &[u8]) -> Result<Option<Position>>;
+    /// 删除索引，返回旧的位置
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// 按 key 的顺序遍历一个范围
+    fn range(&self, range: KeyRange) -> IndexIter<'_>;
+    /// 索引占用的内存（估算值，单位字节）
+    fn memory_usage(&self) -> usize;
+    /// 打开时从磁盘恢复出来的 checkpoint（见 `Log::checkpoint`），内存索引总是 None
+    fn take_checkpoint(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+    /// 把索引连同 checkpoint 落盘，下次打开时只用从 checkpoint 接着读日志
+    fn persist(&mut self, _checkpoint: &[u8]) -> Result<()> {
+        Ok(())
+    }
+    /// 日志 rename 到 path 之后，索引的文件跟着挪过去
+    fn rename(&mut self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexType {
+    /// BTreeMap，默认实现
+    #[default]
+    BTree,
+    /// HashMap，只适合点查，scan 需要全量排序
+    Hash,
+    /// 跳表（crossbeam 的 SkipMap），和其他实现一起用来比较每个 key 的内存占用；
+    /// 通过 `&mut dyn Indexer` 访问，写入是串行的，没有并发上的好处
+    SkipList,
+    /// 前缀压缩的有序数组，省内存，写入较慢
+    Compact,
+    /// 磁盘上的分页索引，内存占用有上限，读写需要额外的 IO
+    Disk(DiskIndexOptions),
+}
+
+impl IndexType {
+    /// path 是日志文件的路径，磁盘索引的文件放在旁边的 `<path>.index` 目录，内存索引用不到
+    pub fn new_indexer(self, path: &Path) -> Result<Box<dyn Indexer>> {
+        Ok(match self {
+            IndexType::BTree => Box::new(BTreeIndex::default()),
+            IndexType::Hash => Box::new(HashIndex::default()),
+            IndexType::SkipList => Box::new(SkipListIndex::default()),
+            IndexType::Compact => Box::new(CompactIndex::default()),
+            IndexType::Disk(options) => Box::new(DiskIndex::new(&index_dir(path), options)?),
+        })
+    }
+
+    /// 和 new_indexer 一样，但是磁盘索引会打开上次 persist 的文件而不是清空
+    pub fn open_indexer(self, path: &Path) -> Result<Box<dyn Indexer>> {
+        match self {
+            IndexType::Disk(options) => Ok(Box::new(DiskIndex::open(&index_dir(path), options)?)),
+            _ => self.new_indexer(path),
+        }
+    }
+}
+
+const ENTRY_SIZE: usize = size_of::<Vec<u8>>() + size_of::<Position>();
+
+#[derive(Debug, Default)]
+pub struct BTreeIndex {
+    map: BTreeMap<Vec<u8>, Position>,
+    key_bytes: usize,
+}
+
+impl Indexer for BTreeIndex {
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>> {
+        let key_len = key.capacity();
+        let old = self.map.insert(key, pos);
+        if old.is_none() {
+            self.key_bytes += key_len;
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.get(key).copied())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.remove_entry(key).map(|(key, pos)| {
+            self.key_bytes -= key.capacity();
+            pos
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn range(&self, range: KeyRange) -> IndexIter<'_> {
+        Box::new(self.map.range(range).map(|(k, v)| Ok((k.clone(), *v))))
+    }
+
+    fn memory_usage(&self) -> usize {
+        // BTreeMap 的节点最多 11 个元素，平均装载率按 2/3 估算
+        self.key_bytes + self.map.len() * ENTRY_SIZE * 3 / 2
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    map: HashMap<Vec<u8>, Position>,
+    key_bytes: usize,
+}
+
+impl Indexer for HashIndex {
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>> {
+        let key_len = key.capacity();
+        let old = self.map.insert(key, pos);
+        if old.is_none() {
+            self.key_bytes += key_len;
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.get(key).copied())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.remove_entry(key).map(|(key, pos)| {
+            self.key_bytes -= key.capacity();
+            pos
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn range(&self, range: KeyRange) -> IndexIter<'_> {
+        // HashMap 无序，只能先过滤再排序
+        let mut items = self
+            .map
+            .iter()
+            .filter(|(k, _)| range.contains(*k))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect::<Vec<_>>();
+        items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Box::new(items.into_iter().map(Ok))
+    }
+
+    fn memory_usage(&self) -> usize {
+        // 每个槽位额外一个字节的控制位
+        self.key_bytes + self.map.capacity() * (ENTRY_SIZE + 1)
+    }
+}
+
+/// 跳表本身支持并发读写，但这里和其他实现一样只通过 `&mut self` 写，
+/// 只是作为一种可选的内存布局，性能上不比 BTree 好
+#[derive(Debug, Default)]
+pub struct SkipListIndex {
+    map: SkipMap<Vec<u8>, Position>,
+    key_bytes: usize,
+}
+
+impl Indexer for SkipListIndex {
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>> {
+        let old = self.get(&key)?;
+        if old.is_none() {
+            self.key_bytes += key.capacity();
+        }
+        self.map.insert(key, pos);
+        Ok(old)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.get(key).map(|e| *e.value()))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>> {
+        Ok(self.map.remove(key).map(|entry| {
+            self.key_bytes -= entry.key().capacity();
+            *entry.value()
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn range(&self, range: KeyRange) -> IndexIter<'_> {
+        Box::new(
+            self.map
+                .range(range)
+                .map(|e| Ok((e.key().clone(), *e.value()))),
+        )
+    }
+
+    fn memory_usage(&self) -> usize {
+        // 节点平均 2 层指针，外加引用计数和高度
+        let node = ENTRY_SIZE + 2 * size_of::<usize>() + 2 * size_of::<usize>();
+        self.key_bytes + self.map.len() * node
+    }
+}
+
+/// 每个块最多保存的 key 数量，超过就分裂
+const COMPACT_BLOCK_MAX: usize = 32;
+/// 每隔这么多个 key 保存一次完整的 key（restart point）
+const COMPACT_RESTART_INTERVAL: usize = 8;
+
+/// 前缀压缩的有序数组
+///
+/// key 按顺序分块存放，块内除 restart point 外，都只保存和前一个 key 不同的后缀：
+/// `shared(varint) unshared(varint) suffix`，类似 leveldb 的 data block。
+/// 点查先二分 restart point，再从最近的一个往后解码，最多解码 `COMPACT_RESTART_INTERVAL` 个 key。
+#[derive(Debug, Default)]
+pub struct CompactIndex {
+    blocks: Vec<CompactBlock>,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct CompactBlock {
+    data: Vec<u8>,
+    positions: Vec<Position>,
+    /// restart point 在 data 里的位置
+    restarts: Vec<u32>,
+}
+
+impl CompactBlock {
+    fn encode(entries: &[(Vec<u8>, Position)]) -> Self {
+        let mut block = CompactBlock {
+            data: Vec::new(),
+            positions: Vec::with_capacity(entries.len()),
+            restarts: Vec::with_capacity(entries.len().div_ceil(COMPACT_RESTART_INTERVAL)),
+        };
+        let mut prev: &[u8] = &[];
+        for (i, (key, pos)) in entries.iter().enumerate() {
+            if i % COMPACT_RESTART_INTERVAL == 0 {
+                block.restarts.push(block.data.len() as u32);
+                prev = &[];
+            }
+            let shared = prev.iter().zip(key).take_while(|(a, b)| a == b).count();
+            put_varint(&mut block.data, shared as u64);
+            put_varint(&mut block.data, (key.len() - shared) as u64);
+            block.data.extend_from_slice(&key[shared..]);
+            block.positions.push(*pos);
+            prev = key;
+        }
+        block.data.shrink_to_fit();
+        block
+    }
+
+    fn decode(&self) -> Vec<(Vec<u8>, Position)> {
+        let mut entries = Vec::with_capacity(self.positions.len());
+        let mut buf = self.data.as_slice();
+        let mut key: Vec<u8> = Vec::new();
+        for pos in &self.positions {
+            let shared = get_varint(&mut buf) as usize;
+            let unshared = get_varint(&mut buf) as usize;
+            key.truncate(shared);
+            key.extend_from_slice(&buf[..unshared]);
+            buf = &buf[unshared..];
+            entries.push((key.clone(), *pos));
+        }
+        entries
+    }
+
+    /// restart point 上的完整 key，offset 是它在 data 里的位置
+    fn restart_key(&self, offset: u32) -> &[u8] {
+        let mut buf = &self.data[offset as usize..];
+        let _shared = get_varint(&mut buf);
+        let unshared = get_varint(&mut buf) as usize;
+        &buf[..unshared]
+    }
+
+    fn first_key(&self) -> &[u8] {
+        self.restart_key(self.restarts[0])
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Position> {
+        // 最后一个不大于 key 的 restart point
+        let r = self.restarts.partition_point(|&offset| self.restart_key(offset) <= key).checked_sub(1)?;
+        let mut buf = &self.data[self.restarts[r] as usize..];
+        let mut current: Vec<u8> = Vec::new();
+        let start = r * COMPACT_RESTART_INTERVAL;
+        for pos in &self.positions[start..self.positions.len().min(start + COMPACT_RESTART_INTERVAL)] {
+            let shared = get_varint(&mut buf) as usize;
+            let unshared = get_varint(&mut buf) as usize;
+            current.truncate(shared);
+            current.extend_from_slice(&buf[..unshared]);
+            buf = &buf[unshared..];
+            match current.as_slice().cmp(key) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Some(*pos),
+                std::cmp::Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+}
+
+impl CompactIndex {
+    /// key 可能所在的块
+    fn block_of(&self, key: &[u8]) -> usize {
+        self.blocks
+            .partition_point(|b| b.first_key() <= key)
+            .saturating_sub(1)
+    }
+}
+
+impl Indexer for CompactIndex {
+    fn put(&mut self, key: Vec<u8>, pos: Position) -> Result<Option<Position>> {
+        if self.blocks.is_empty() {
+            self.blocks.push(CompactBlock::encode(&[(key, pos)]));
+            self.len += 1;
+            return Ok(None);
+        }
+        let idx = self.block_of(&key);
+        let mut entries = self.blocks[idx].decode();
+        let old = match entries.binary_search_by(|(k, _)| k.as_slice().cmp(&key)) {
+            Ok(i) => Some(std::mem::replace(&mut entries[i].1, pos)),
+            Err(i) => {
+                entries.insert(i, (key, pos));
+                self.len += 1;
+                None
+            }
+        };
+        if entries.len() > COMPACT_BLOCK_MAX {
+            let right = entries.split_off(entries.len() / 2);
+            self.blocks[idx] = CompactBlock::encode(&entries);
+            self.blocks.insert(idx + 1, CompactBlock::encode(&right));
+        } else {
+            self.blocks[idx] = CompactBlock::encode(&entries);
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Position>> {
+        if self.blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.blocks[self.block_of(key)].get(key))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<Position>> {
+        if self.blocks.is_empty() {
+            return Ok(None);
+        }
+        let idx = self.block_of(key);
+        let mut entries = self.blocks[idx].decode();
+        let Ok(i) = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) else {
+            return Ok(None);
+        };
+        let (_, old) = entries.remove(i);
+        self.len -= 1;
+        if entries.is_empty() {
+            self.blocks.remove(idx);
+        } else {
+            self.blocks[idx] = CompactBlock::encode(&entries);
+        }
+        Ok(Some(old))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn range(&self, range: KeyRange) -> IndexIter<'_> {
+        let lo = match &range.0 {
+            Bound::Included(k) | Bound::Excluded(k) => self.block_of(k),
+            Bound::Unbounded => 0,
+        };
+        let hi = match &range.1 {
+            Bound::Included(k) => self.blocks.partition_point(|b| b.first_key() <= k.as_slice()),
+            Bound::Excluded(k) => self.blocks.partition_point(|b| b.first_key() < k.as_slice()),
+            Bound::Unbounded => self.blocks.len(),
+        };
+        let blocks = self.blocks.get(lo..hi.max(lo)).unwrap_or_default();
+        Box::new(
+            blocks
+                .iter()
+                .flat_map(|b| b.decode())
+                .filter(move |(k, _)| range.contains(k))
+                .map(Ok),
+        )
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|b| {
+                size_of::<CompactBlock>()
+                    + b.data.capacity()
+                    + b.positions.capacity() * size_of::<Position>()
+                    + b.restarts.capacity() * size_of::<u32>()
+            })
+            .sum::<usize>()
+            + (self.blocks.capacity() - self.blocks.len()) * size_of::<CompactBlock>()
+    }
+}
+
+// LEB128 变长整数
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// 从 r 读一个 varint，返回 (值, 占用的字节数)；r 是 `&mut &[u8]` 时读完指向剩下的部分
+pub(crate) fn read_varint(r: &mut impl Read) -> Result<(u64, u64)> {
+    let mut v = 0u64;
+    let mut byte = [0; 1];
+    for i in 0..10 {
+        r.read_exact(&mut byte)?;
+        v |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] < 0x80 {
+            return Ok((v, i + 1));
+        }
+    }
+    bail!("varint too long")
+}
+
+/// 解码内存里自己编码的数据，不会不完整
+pub(crate) fn get_varint(buf: &mut &[u8]) -> u64 {
+    read_varint(buf).expect("truncated varint").0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 磁盘索引的页和 memtable 都设得很小，让测试覆盖到 flush 和多个页
+    const ALL: [IndexType; 5] = [
+        IndexType::BTree,
+        IndexType::Hash,
+        IndexType::SkipList,
+        IndexType::Compact,
+        IndexType::Disk(DiskIndexOptions {
+            page_size: 64,
+            cache_pages: 4,
+            memtable_keys: 16,
+            fanout: 2,
+        }),
+    ];
+
+    #[test]
+    fn test_indexer_point_ops() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        for index_type in ALL {
+            let mut index = index_type.new_indexer(&tmp_dir.path().join("test.db"))?;
+            assert!(index.is_empty());
+            assert_eq!(index.put(b"a".to_vec(), (1, 1, 0))?, None);
+            assert_eq!(index.put(b"b".to_vec(), (2, 2, 0))?, None);
+            assert_eq!(index.put(b"a".to_vec(), (3, 3, 0))?, Some((1, 1, 0)));
+            assert_eq!(index.get(b"a")?, Some((3, 3, 0)), "{:?}", index_type);
+            assert_eq!(index.get(b"c")?, None);
+            assert_eq!(index.len(), 2);
+
+            assert_eq!(index.delete(b"a")?, Some((3, 3, 0)));
+            assert_eq!(index.delete(b"a")?, None);
+            assert_eq!(index.get(b"a")?, None);
+            assert_eq!(index.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexer_range() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        for index_type in ALL {
+            let mut index = index_type.new_indexer(&tmp_dir.path().join("test.db"))?;
+            // 打乱顺序写入，足够让 Compact 分裂出多个块
+            for i in (0..200u32).rev().step_by(2).chain((0..200u32).step_by(2)) {
+                index.put(format!("key{:04}", i).into_bytes(), (i as u64, i, i as u8))?;
+            }
+            for i in (0..200u32).step_by(10) {
+                index.delete(format!("key{:04}", i).as_bytes())?;
+            }
+            assert_eq!(index.len(), 180);
+            // 点查要覆盖块内 restart point 之间的每个位置
+            for i in 0..200u32 {
+                let key = format!("key{:04}", i);
+                let expected = (i % 10 != 0).then_some((i as u64, i, i as u8));
+                assert_eq!(index.get(key.as_bytes())?, expected, "{:?} {}", index_type, key);
+                assert_eq!(index.get(format!("{}!", key).as_bytes())?, None);
+            }
+            assert_eq!(index.get(b"a")?, None);
+
+            let keys = index
+                .range((Bound::Included(b"key0010".to_vec()), Bound::Excluded(b"key0015".to_vec())))
+                .map(|r| r.map(|(k, _)| String::from_utf8(k).unwrap()))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(keys, ["key0011", "key0012", "key0013", "key0014"], "{:?}", index_type);
+
+            let last = index
+                .range((Bound::Excluded(b"key0150".to_vec()), Bound::Included(b"key0191".to_vec())))
+                .next_back()
+                .transpose()?;
+            assert_eq!(last, Some((b"key0191".to_vec(), (191, 191, 191))));
+
+            let all = index
+                .range((Bound::Unbounded, Bound::Unbounded))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(all.len(), 180);
+            assert!(all.windows(2).all(|w| w[0].0 < w[1].0), "{:?}", index_type);
+            // 两头一起迭代，在中间相遇
+            let mut iter = index.range((Bound::Unbounded, Bound::Unbounded));
+            let mut seen = 0;
+            while let Some(item) = if seen % 2 == 0 { iter.next() } else { iter.next_back() } {
+                item?;
+                seen += 1;
+            }
+            assert_eq!(seen, 180, "{:?}", index_type);
+
+            assert_eq!(index.range((Bound::Included(b"z".to_vec()), Bound::Unbounded)).count(), 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexer_memory_usage() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let mut usage = Vec::new();
+        for index_type in ALL {
+            let mut index = index_type.new_indexer(&tmp_dir.path().join("test.db"))?;
+            for i in 0..1000u32 {
+                index.put(format!("user:profile:{:08}", i).into_bytes(), (i as u64, i, i as u8))?;
+            }
+            let per_key = index.memory_usage() / index.len();
+            assert!(per_key > 0);
+            usage.push((index_type, per_key));
+        }
+        // 内存实现里 Compact 最省，磁盘索引只在内存里保留稀疏索引
+        let compact = usage[3].1;
+        assert!(usage[..3].iter().all(|(_, per_key)| compact <= *per_key), "{:?}", usage);
+        assert!(usage[4].1 < usage[0].1, "{:?}", usage);
+        Ok(())
+    }
+}
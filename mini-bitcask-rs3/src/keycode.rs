@@ -0,0 +1,249 @@
+use anyhow::{bail, Result};
+
+// 每个值前面一个字节的类型标记，不同类型之间按标记排序。
+// 0x00 留给字节串和元组的结束标记，这样短的总是排在以它开头的长的前面。
+const TAG_END: u8 = 0x00;
+const TAG_BYTES: u8 = 0x01;
+const TAG_STR: u8 = 0x02;
+const TAG_INT: u8 = 0x03;
+const TAG_UINT: u8 = 0x04;
+const TAG_FLOAT: u8 = 0x05;
+const TAG_TUPLE: u8 = 0x06;
+
+// 字节串里的 0x00 写成 0x00 0xff，结尾写 0x00 0x00
+const ESCAPE: u8 = 0xff;
+
+/// 组合 key 里的一个值
+///
+/// 编码之后按字节比较的顺序和按值比较的顺序一致（memcomparable），
+/// 可以直接用来做 MiniBitcask 的 key 和范围扫描。浮点数按 `f64::total_cmp` 的顺序。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bytes(Vec<u8>),
+    Str(String),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Tuple(Vec<Value>),
+}
+
+impl From<&[u8]> for Value {
+    fn from(v: &[u8]) -> Self {
+        Value::Bytes(v.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(v: Vec<u8>) -> Self {
+        Value::Bytes(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Str(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Str(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Uint(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(v: Vec<Value>) -> Self {
+        Value::Tuple(v)
+    }
+}
+
+/// 把一组值编码成 key，编码的结果是各个值编码的拼接
+pub fn encode(values: &[Value]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for value in values {
+        encode_value(&mut buf, value);
+    }
+    buf
+}
+
+/// 前几个值的编码，用来 `scan_prefix` 以这几个值开头的所有 key
+pub fn prefix(values: &[Value]) -> Vec<u8> {
+    encode(values)
+}
+
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        values.push(decode_value(&mut bytes)?);
+    }
+    Ok(values)
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Bytes(v) => {
+            buf.push(TAG_BYTES);
+            encode_bytes(buf, v);
+        }
+        Value::Str(v) => {
+            buf.push(TAG_STR);
+            encode_bytes(buf, v.as_bytes());
+        }
+        Value::Int(v) => {
+            buf.push(TAG_INT);
+            // 翻转符号位，负数就排在正数前面了
+            buf.extend_from_slice(&((*v as u64) ^ (1 << 63)).to_be_bytes());
+        }
+        Value::Uint(v) => {
+            buf.push(TAG_UINT);
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        Value::Float(v) => {
+            buf.push(TAG_FLOAT);
+            // 正数翻转符号位，负数翻转所有位
+            let bits = v.to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+            buf.extend_from_slice(&bits.to_be_bytes());
+        }
+        Value::Tuple(values) => {
+            buf.push(TAG_TUPLE);
+            for value in values {
+                encode_value(buf, value);
+            }
+            buf.push(TAG_END);
+        }
+    }
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        buf.push(b);
+        if b == 0 {
+            buf.push(ESCAPE);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if bytes.len() < n {
+        bail!("unexpected end of encoded key");
+    }
+    let (head, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(head)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(take(bytes, 8)?.try_into()?))
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        match take(bytes, 1)?[0] {
+            0 => match take(bytes, 1)?[0] {
+                0 => return Ok(out),
+                ESCAPE => out.push(0),
+                other => bail!("invalid escape byte {:#04x} in encoded key", other),
+            },
+            b => out.push(b),
+        }
+    }
+}
+
+fn decode_value(bytes: &mut &[u8]) -> Result<Value> {
+    Ok(match take(bytes, 1)?[0] {
+        TAG_BYTES => Value::Bytes(decode_bytes(bytes)?),
+        TAG_STR => Value::Str(String::from_utf8(decode_bytes(bytes)?)?),
+        TAG_INT => Value::Int((take_u64(bytes)? ^ (1 << 63)) as i64),
+        TAG_UINT => Value::Uint(take_u64(bytes)?),
+        TAG_FLOAT => {
+            let bits = take_u64(bytes)?;
+            let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+            Value::Float(f64::from_bits(bits))
+        }
+        TAG_TUPLE => {
+            let mut values = Vec::new();
+            while bytes.first() != Some(&TAG_END) {
+                values.push(decode_value(bytes)?);
+            }
+            take(bytes, 1)?;
+            Value::Tuple(values)
+        }
+        other => bail!("unknown type tag {:#04x} in encoded key", other),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keycode() -> Result<()> {
+        // 按值排好序的，编码之后也要是这个顺序
+        let sorted = vec![
+            vec![Value::from(&b""[..])],
+            vec![Value::from(&b"a"[..])],
+            vec![Value::from(&b"a\x00"[..])],
+            vec![Value::from(&b"a\x00\x00"[..])],
+            vec![Value::from(&b"a\x01"[..])],
+            vec![Value::from(&b"ab"[..])],
+            vec![Value::from("b")],
+            vec![Value::from("tenant"), Value::from(i64::MIN)],
+            vec![Value::from("tenant"), Value::from(-256i64)],
+            vec![Value::from("tenant"), Value::from(-1i64)],
+            vec![Value::from("tenant"), Value::from(0i64)],
+            vec![Value::from("tenant"), Value::from(255i64)],
+            vec![Value::from("tenant"), Value::from(256i64), Value::from(1u64)],
+            vec![Value::from("tenant"), Value::from(i64::MAX)],
+            vec![Value::from(0u64)],
+            vec![Value::from(u64::MAX)],
+            vec![Value::from(f64::NEG_INFINITY)],
+            vec![Value::from(-1.5f64)],
+            vec![Value::from(-0.0f64)],
+            vec![Value::from(0.0f64)],
+            vec![Value::from(0.25f64)],
+            vec![Value::from(f64::INFINITY)],
+            vec![Value::Tuple(vec![])],
+            vec![Value::from(vec![Value::from("a")])],
+            vec![Value::from(vec![Value::from("a")]), Value::from(0u64)],
+            vec![Value::from(vec![Value::from("a"), Value::from(0u64)])],
+            vec![Value::from(vec![Value::from("b")])],
+        ];
+        let encoded = sorted.iter().map(|values| encode(values)).collect::<Vec<_>>();
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", sorted[i], sorted[i + 1]);
+        }
+        for (values, encoded) in sorted.iter().zip(&encoded) {
+            assert_eq!(&decode(encoded)?, values);
+        }
+
+        // 前缀只匹配以这几个值开头的 key，"ab" 不是 "a" 的后续
+        let p = prefix(&[Value::from("a")]);
+        assert!(encode(&[Value::from("a"), Value::from(1u64)]).starts_with(&p));
+        assert!(!encode(&[Value::from("ab")]).starts_with(&p));
+
+        assert!(decode(&[TAG_UINT, 0, 0]).is_err());
+        assert!(decode(&[0x7f]).is_err());
+        Ok(())
+    }
+}
@@ -1,2 +1,19 @@
-mod log;
-mod bitcask;
\ No newline at end of file
+pub mod log;
+pub mod storage;
+pub mod crypto;
+pub mod compression;
+pub mod index;
+pub mod disk_index;
+pub mod lru;
+pub mod bitcask;
+pub mod watch;
+pub mod replication;
+pub mod snapshot;
+pub mod merge_operator;
+pub mod namespace;
+pub mod history;
+pub mod keycode;
+pub mod structures;
+pub mod typed;
+pub mod ffi;
+pub mod convert;
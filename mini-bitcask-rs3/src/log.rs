@@ -1,125 +1,1008 @@
-use anyhow::Result;
-use fs4::fs_std::FileExt;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use crate::compression::Compression;
+use crate::crypto::{Cipher, Encryption};
+use crate::index::{Indexer, Position};
+use crate::merge_operator::{push_operand, Pending, PendingOperands};
+use anyhow::{bail, Result};
+use log::debug;
+use crate::storage::{FsStorage, Storage, StorageFile, StorageReader};
+use std::borrow::Cow;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 const KEY_VAL_HEADER_LEN: u32 = 4;
 
-pub type KeyDir = std::collections::BTreeMap<Vec<u8>, (u64, u32)>;
+// key_len 的最高位表示后面跟着扩展头：seq(8) flags(1)
+// 没有这一位的是旧格式的记录，seq 按读到的顺序依次递增
+const EXTENDED_BIT: u32 = 1 << 31;
+const EXTENDED_HEADER_LEN: u32 = 9;
+
+/// merge 完成时写在新文件末尾的标记，seq 是被压缩掉的最大 seq，不进索引
+pub const FLAG_MERGE_MARK: u8 = 1;
+/// merge operator 的 operand，读的时候要合并到之前的值上
+pub const FLAG_OPERAND: u8 = 2;
+/// 元信息，明文，seq 为 0，不进索引：key 为空的是加密文件开头的加密信息，key 为 `CLEAN_KEY` 的是正常关闭的标记
+pub const FLAG_META: u8 = 4;
+/// value 后面跟着校验表：每 `CHECKSUM_CHUNK` 字节一个 CRC32（大端），流式读写的大 value 用
+pub const FLAG_CHECKSUM: u8 = 8;
+/// 记录最后跟着写入时间：UNIX 时间戳的毫秒数（8 字节大端），开启历史版本时写
+pub const FLAG_TIMESTAMP: u8 = 16;
+const TIMESTAMP_LEN: u64 = 8;
+/// value 是 LZ4 压缩过的（加密时先压缩再加密）
+pub const FLAG_LZ4: u8 = 32;
+/// value 是 Snappy 压缩过的
+pub const FLAG_SNAPPY: u8 = 64;
+const COMPRESSION_FLAGS: u8 = FLAG_LZ4 | FLAG_SNAPPY;
+/// 后面还有同一组的记录，见 `write_batch`；打开时没有等到最后一条的一组记录整个丢掉
+pub const FLAG_BATCH: u8 = 128;
+/// 读 value 需要知道的 flag，跟着位置一起放进索引
+pub const VALUE_FLAGS: u8 = FLAG_CHECKSUM | COMPRESSION_FLAGS;
+
+/// close 时写在日志末尾的标记的 key，打开时最后一条记录不是它就说明上次没有正常关闭
+const CLEAN_KEY: &[u8] = b"clean";
+
+/// checkpoint 记下位置之前这么多字节的 crc，用来确认打开的还是同一个日志
+const CHECKPOINT_WINDOW: u64 = 4096;
+
+/// 校验和流式读写的块大小
+pub const CHECKSUM_CHUNK: u32 = 64 * 1024;
+
+/// 当前时间，UNIX 时间戳的毫秒数
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn checksum_len(value_len: u32) -> u64 {
+    value_len.div_ceil(CHECKSUM_CHUNK) as u64 * 4
+}
+
+fn checksum_table(value: &[u8]) -> Vec<u8> {
+    value.chunks(CHECKSUM_CHUNK as usize).flat_map(|chunk| crc32fast::hash(chunk).to_be_bytes()).collect()
+}
+
+/// read_values 里两个 value 之间的空隙不超过这么多字节就合并成一次读，多读一点比多一次 I/O 划算
+const COALESCE_GAP: u64 = 4096;
+
+pub type KeyDir = Box<dyn Indexer>;
+
+/// 写入什么时候真正落到文件里
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// 先放在内存的缓冲区里，写满、需要读或者调用 sync 的时候才写到文件，进程崩溃会丢掉缓冲区里的数据
+    ///
+    /// 需要显式打开，只适合能接受丢掉最近几次写入的场景
+    Buffered,
+    /// 每条记录都直接写到文件，但不 fsync，进程崩溃不会丢数据
+    #[default]
+    WriteThrough,
+    /// 每条记录都写到文件并且 fsync
+    Always,
+}
 
 #[derive(Debug)]
 pub struct Log {
     pub path: PathBuf,
-    pub file: std::fs::File,
+    pub file: Arc<dyn StorageFile>,
+    /// 下一条记录的 seq
+    pub next_seq: u64,
+    /// 小于等于这个 seq 的历史已经被 merge 掉了
+    pub compacted_seq: u64,
+    /// 加密文件的加解密器，None 表示明文
+    pub cipher: Option<Cipher>,
+    pub sync_policy: SyncPolicy,
+    /// 缓冲区超过这个大小就写到文件
+    pub write_buffer_size: usize,
+    /// 日志的末尾，包括还在缓冲区里的记录
+    tail: u64,
+    /// [tail - buf.len(), tail) 之间还没写到文件的记录
+    buf: Vec<u8>,
+    /// 新写的记录是否带上写入时间
+    pub timestamps: bool,
+    /// 最后写入的一条记录的 seq 和时间
+    last_write: (u64, Option<u64>),
+    /// 新写的 value 用什么压缩
+    pub compression: Compression,
+    /// 小于这个大小的 value 不压缩
+    pub compression_threshold: usize,
+    /// 打开以来写入的 value 压缩前和压缩后的总字节数
+    value_bytes: (u64, u64),
+    /// 最后一条记录是正常关闭的标记
+    pub clean: bool,
+}
+
+/// 日志里的一条记录（不含 value）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub seq: u64,
+    pub flags: u8,
+    /// 记录的起始位置
+    pub offset: u64,
+    pub value_pos: u64,
+    /// None 表示删除（tombstone）
+    pub value_len: Option<u32>,
+    /// 写入时间（毫秒），没有记录时间的是 None
+    pub timestamp: Option<u64>,
+}
+
+impl Entry {
+    /// 下一条记录的起始位置
+    pub fn end(&self) -> u64 {
+        let value_len = self.value_len.unwrap_or(0);
+        let checksum = if self.flags & FLAG_CHECKSUM != 0 { checksum_len(value_len) } else { 0 };
+        let timestamp = if self.flags & FLAG_TIMESTAMP != 0 { TIMESTAMP_LEN } else { 0 };
+        self.value_pos + value_len as u64 + checksum + timestamp
+    }
 }
 
 impl Log {
     pub fn new(path: PathBuf) -> Result<Self> {
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+        Self::open(&FsStorage, path)
+    }
+
+    pub fn open(storage: &dyn Storage, path: PathBuf) -> Result<Self> {
+        let file = storage.open(&path)?;
+        let tail = file.len()?;
+        Ok(Self {
+            path,
+            file,
+            next_seq: 1,
+            compacted_seq: 0,
+            cipher: None,
+            sync_policy: SyncPolicy::default(),
+            write_buffer_size: 64 * 1024,
+            tail,
+            buf: Vec::new(),
+            timestamps: false,
+            last_write: (0, None),
+            compression: Compression::None,
+            compression_threshold: 0,
+            value_bytes: (0, 0),
+            clean: false,
+        })
+    }
+
+    /// 检查文件开头的加密元信息，要在 load_index 之前调用
+    ///
+    /// 空文件按 encryption 初始化；已有的文件是否加密、用什么方式加密以文件里记录的为准。
+    pub fn init_encryption(&mut self, encryption: Option<&Encryption>) -> Result<()> {
+        // 只看第一条记录的头，value 只有元信息才需要读
+        let first = match self.iter(false)?.next() {
+            // 第一条记录就没写完，当作空文件
+            Some(Err(e)) if is_eof(&e) => {
+                self.file.set_len(0)?;
+                self.tail = 0;
+                None
+            }
+            first => first.transpose()?,
+        };
+        self.cipher = match (first, encryption) {
+            (None, Some(encryption)) => {
+                let cipher = Cipher::generate(encryption)?;
+                self.write_raw(&[], Some(&cipher.encode_meta()?), 0, FLAG_META, None)?;
+                Some(cipher)
+            }
+            (None, None) => None,
+            (Some((entry, _)), Some(encryption)) if entry.flags & FLAG_META != 0 => {
+                let mut meta = vec![0; entry.value_len.unwrap_or(0) as usize];
+                self.file.read_exact_at(&mut meta, entry.value_pos)?;
+                Some(Cipher::decode_meta(encryption, &meta)?)
+            }
+            (Some((entry, _)), None) if entry.flags & FLAG_META != 0 => {
+                bail!("log file {} is encrypted, an encryption key is required", self.path.display())
+            }
+            (Some(_), Some(_)) => bail!(
+                "log file {} is not encrypted, open it without a key and merge with encryption to encrypt it",
+                self.path.display()
+            ),
+            (Some(_), None) => None,
+        };
+        Ok(())
+    }
+
+    /// 日志文件所在的目录
+    pub fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    pub fn read_value(&mut self, pos: Position) -> Result<Vec<u8>> {
+        let (value_pos, value_len, flags) = pos;
+        // 刚写的数据可能还在缓冲区里
+        let checksum = if flags & FLAG_CHECKSUM != 0 { checksum_len(value_len) } else { 0 };
+        if value_pos + value_len as u64 + checksum > self.flushed() {
+            self.flush()?;
+        }
+        read_stored(&*self.file, self.cipher.as_ref(), pos)
+    }
+
+    /// 把 value 里 [offset, offset + len) 这一段写到 writer，超出 value 末尾的部分忽略
+    ///
+    /// 按块读，每块读出来先校验再写出去，内存里最多一块；加密和压缩的 value 要整个读出来才能解开。
+    pub fn read_value_to(&mut self, pos: Position, offset: u64, len: u64, writer: &mut dyn Write) -> Result<u64> {
+        let (value_pos, value_len, flags) = pos;
+        if self.cipher.is_some() || flags & COMPRESSION_FLAGS != 0 {
+            let value = self.read_value(pos)?;
+            let start = offset.min(value.len() as u64);
+            let end = offset.saturating_add(len).min(value.len() as u64);
+            writer.write_all(&value[start as usize..end as usize])?;
+            return Ok(end - start);
+        }
+        let end = offset.saturating_add(len).min(value_len as u64);
+        if offset >= end {
+            return Ok(0);
+        }
+        if value_pos + value_len as u64 + checksum_len(value_len) > self.flushed() {
+            self.flush()?;
+        }
+        let checked = flags & FLAG_CHECKSUM != 0;
+        let chunk = CHECKSUM_CHUNK as u64;
+        let mut buf = Vec::with_capacity(CHECKSUM_CHUNK as usize);
+        // 有校验表时整块读，没有的时候只读需要的部分
+        let mut start = if checked { offset / chunk * chunk } else { offset };
+        while start < end {
+            let chunk_end = if checked { (start + chunk).min(value_len as u64) } else { (start + chunk).min(end) };
+            buf.resize((chunk_end - start) as usize, 0);
+            self.file.read_exact_at(&mut buf, value_pos + start)?;
+            if checked {
+                verify_chunks(&*self.file, pos, (start / chunk) as u32, &buf)?;
+            }
+            let from = offset.saturating_sub(start) as usize;
+            let to = (end.min(chunk_end) - start) as usize;
+            writer.write_all(&buf[from..to])?;
+            start = chunk_end;
+        }
+        Ok(end - offset)
+    }
+
+    /// 一次读出多个 value，结果和 positions 的顺序一致
+    ///
+    /// 按位置从小到大读，相邻的 value 之间空隙不超过 `COALESCE_GAP` 时合并成一次读。
+    pub fn read_values(&mut self, positions: &[Position]) -> Result<Vec<Vec<u8>>> {
+        let end = positions
+            .iter()
+            .map(|&(pos, len, flags)| pos + len as u64 + if flags & FLAG_CHECKSUM != 0 { checksum_len(len) } else { 0 })
+            .max()
+            .unwrap_or(0);
+        if end > self.flushed() {
+            self.flush()?;
+        }
+        let mut order = (0..positions.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| positions[i].0);
+
+        let mut values = vec![Vec::new(); positions.len()];
+        let mut rest = order.as_slice();
+        while let Some(&first) = rest.first() {
+            let start = positions[first].0;
+            let mut run_end = start;
+            let n = rest
+                .iter()
+                .take_while(|&&i| {
+                    let (pos, len, _) = positions[i];
+                    if pos > run_end + COALESCE_GAP {
+                        return false;
+                    }
+                    run_end = run_end.max(pos + len as u64);
+                    true
+                })
+                .count();
+            let mut buf = vec![0; (run_end - start) as usize];
+            self.file.read_exact_at(&mut buf, start)?;
+            for &i in &rest[..n] {
+                let (pos, len, flags) = positions[i];
+                let value = &buf[(pos - start) as usize..][..len as usize];
+                if flags & FLAG_CHECKSUM != 0 {
+                    verify_chunks(&*self.file, positions[i], 0, value)?;
+                }
+                let value = match &self.cipher {
+                    Some(cipher) => cipher.decrypt_value(pos, value)?,
+                    None => value.to_vec(),
+                };
+                values[i] = decompress(flags, value)?;
+            }
+            rest = &rest[n..];
+        }
+        Ok(values)
+    }
+
+    /// 返回 value 在文件里的位置和长度（加密时是密文的长度），可以直接放进索引
+    pub fn write_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<Position> {
+        let seq = self.next_seq;
+        self.write_entry_with(key, value, seq, 0)
+    }
+
+    /// 从 reader 里读 len 个字节作为 value，边读边写到文件，带校验表
+    ///
+    /// 加密的日志要整个 value 加密，只能先读到内存里再写。
+    pub fn write_entry_from_reader(&mut self, key: &[u8], reader: &mut dyn Read, len: u64) -> Result<Position> {
+        let Ok(value_len) = i32::try_from(len) else {
+            bail!("value of {} bytes is too large, the limit is {} bytes", len, i32::MAX);
+        };
+        let seq = self.next_seq;
+        if self.cipher.is_some() {
+            let mut value = vec![0; len as usize];
+            reader.read_exact(&mut value)?;
+            return self.write_entry_with(key, Some(&value), seq, FLAG_CHECKSUM);
+        }
+        // 大 value 不经过写缓冲区，直接写在缓冲区的记录后面
+        self.flush()?;
+        let offset = self.tail;
+        let timestamp = self.timestamps.then(now_millis);
+        let result = self.stream_value(key, reader, value_len as u32, seq, timestamp, offset);
+        if let Err(e) = result {
+            let _ = self.file.set_len(offset);
+            return Err(e);
+        }
+        if self.sync_policy == SyncPolicy::Always {
+            self.file.sync()?;
+        }
+        self.last_write = (seq, timestamp);
+        let value_pos = offset + (KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN) as u64 + key.len() as u64;
+        self.tail = value_pos + value_len as u64 + checksum_len(value_len as u32);
+        if self.timestamps {
+            self.tail += TIMESTAMP_LEN;
+        }
+        self.next_seq = self.next_seq.max(seq + 1);
+        self.clean = false;
+        self.value_bytes.0 += len;
+        self.value_bytes.1 += len;
+        debug!("write_entry_from_reader: offset: {}, key_len: {}, value_len: {}, seq: {}", offset, key.len(), value_len, seq);
+        Ok((value_pos, value_len as u32, FLAG_CHECKSUM))
+    }
+
+    fn stream_value(
+        &mut self,
+        key: &[u8],
+        reader: &mut dyn Read,
+        value_len: u32,
+        seq: u64,
+        timestamp: Option<u64>,
+        offset: u64,
+    ) -> Result<()> {
+        let mut header = Vec::with_capacity((KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN) as usize + key.len());
+        header.extend_from_slice(&(key.len() as u32 | EXTENDED_BIT).to_be_bytes());
+        header.extend_from_slice(&(value_len as i32).to_be_bytes());
+        header.extend_from_slice(&seq.to_be_bytes());
+        header.push(if timestamp.is_some() { FLAG_CHECKSUM | FLAG_TIMESTAMP } else { FLAG_CHECKSUM });
+        header.extend_from_slice(key);
+        self.file.write_all_at(&header, offset)?;
+
+        let mut pos = offset + header.len() as u64;
+        let mut table = Vec::with_capacity(checksum_len(value_len) as usize);
+        let mut buf = vec![0; CHECKSUM_CHUNK as usize];
+        let mut remaining = value_len;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(CHECKSUM_CHUNK) as usize];
+            reader.read_exact(chunk).map_err(|e| anyhow::anyhow!("failed to read value from reader: {}", e))?;
+            table.extend_from_slice(&crc32fast::hash(chunk).to_be_bytes());
+            self.file.write_all_at(chunk, pos)?;
+            pos += chunk.len() as u64;
+            remaining -= chunk.len() as u32;
+        }
+        if let Some(timestamp) = timestamp {
+            table.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        self.file.write_all_at(&table, pos)?;
+        Ok(())
+    }
+
+    // +-------------+-------------+--------+----------+-----+-------+
+    // | key len(4)  | val len(4)  | seq(8) | flags(1) | key | value |
+    // +-------------+-------------+--------+----------+-----+-------+
+    /// 用指定的 seq 和 flags 写一条记录，merge 的时候要保留原来的 seq
+    pub fn write_entry_with(&mut self, key: &[u8], value: Option<&[u8]>, seq: u64, flags: u8) -> Result<Position> {
+        let timestamp = self.timestamps.then(now_millis);
+        self.write_entry_at(key, value, seq, flags, timestamp)
+    }
+
+    /// 指定写入时间，merge 复制记录的时候保留原来的时间
+    ///
+    /// value 是原始的值，按当前的设置决定是否压缩，flags 里的压缩标记会被忽略。
+    pub fn write_entry_at(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        seq: u64,
+        flags: u8,
+        timestamp: Option<u64>,
+    ) -> Result<Position> {
+        let (stored, flags) = self.compress(value, flags & !COMPRESSION_FLAGS)?;
+        let stored_len = stored.as_ref().map_or(0, |v| v.len() as u64);
+        let pos = match &self.cipher {
+            None => self.write_raw(key, stored.as_deref(), seq, flags, timestamp)?,
+            Some(cipher) => {
+                let offset = self.tail;
+                let key = cipher.encrypt_key(offset, key)?;
+                let value_pos = offset + (KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN) as u64 + key.len() as u64;
+                let stored = stored.map(|v| cipher.encrypt_value(value_pos, &v)).transpose()?;
+                self.write_raw(&key, stored.as_deref(), seq, flags, timestamp)?
+            }
+        };
+        if let Some(value) = value {
+            self.value_bytes.0 += value.len() as u64;
+            self.value_bytes.1 += stored_len;
+        }
+        Ok(pos)
+    }
+
+    /// 超过阈值并且压缩之后确实变小了才压缩，元信息和带校验表的 value 不压缩
+    fn compress<'a>(&self, value: Option<&'a [u8]>, flags: u8) -> Result<(Option<Cow<'a, [u8]>>, u8)> {
+        let Some(value) = value else {
+            return Ok((None, flags));
+        };
+        if self.compression == Compression::None
+            || value.len() < self.compression_threshold
+            || flags & (FLAG_META | FLAG_CHECKSUM) != 0
+        {
+            return Ok((Some(Cow::Borrowed(value)), flags));
+        }
+        let compressed = self.compression.compress(value)?;
+        if compressed.len() >= value.len() {
+            return Ok((Some(Cow::Borrowed(value)), flags));
         }
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
-        file.try_lock_exclusive()?;
-        Ok(Self { path, file })
+        Ok((Some(Cow::Owned(compressed)), flags | self.compression.flag()))
     }
 
-    pub fn read_value(&mut self, valus_pos: u64, value_len: u32) -> Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(valus_pos))?;
-        let mut value = vec![0; value_len as usize];
-        self.file.read_exact(&mut value)?;
-        Ok(value)
+    /// 打开以来（merge 之后是 merge 以来）写入的 value 压缩前和压缩后的总字节数
+    pub fn value_bytes(&self) -> (u64, u64) {
+        self.value_bytes
     }
-    pub fn write_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
+
+    /// 原样写入，key 和 value 已经是要落盘的内容
+    fn write_raw(&mut self, key: &[u8], value: Option<&[u8]>, seq: u64, flags: u8, timestamp: Option<u64>) -> Result<Position> {
         let key_len = key.len() as u32;
         let value_len = value.map_or(0, |v| v.len() as u32);
         let value_len_or_tomestone = value.map_or(-1, |v| v.len() as i32);
-        let len = KEY_VAL_HEADER_LEN * 2 + key_len + value_len;
+        let checksum = match value {
+            Some(value) if flags & FLAG_CHECKSUM != 0 => checksum_table(value),
+            _ => Vec::new(),
+        };
+        let flags = match timestamp {
+            Some(_) => flags | FLAG_TIMESTAMP,
+            None => flags & !FLAG_TIMESTAMP,
+        };
+        let trailer = timestamp.map(u64::to_be_bytes);
+        let value_pos = self.tail + (KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN + key_len) as u64;
+        let len = KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN + key_len + value_len + checksum.len() as u32
+            + trailer.map_or(0, |t| t.len() as u32);
 
-        let offset = self.file.seek(SeekFrom::End(0))?;
-        let mut w = BufWriter::with_capacity(len as usize, &mut self.file);
-        w.write_all(&key_len.to_be_bytes())?;
-        w.write_all(&value_len_or_tomestone.to_be_bytes())?;
-        w.write_all(key)?;
-        if let Some(value) = value {
-            w.write_all(value)?;
+        let offset = self.tail;
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&(key_len | EXTENDED_BIT).to_be_bytes());
+        self.buf.extend_from_slice(&value_len_or_tomestone.to_be_bytes());
+        self.buf.extend_from_slice(&seq.to_be_bytes());
+        self.buf.push(flags);
+        self.buf.extend_from_slice(key);
+        self.buf.extend_from_slice(value.unwrap_or_default());
+        self.buf.extend_from_slice(&checksum);
+        self.buf.extend_from_slice(trailer.as_ref().map_or(&[][..], |t| &t[..]));
+        self.tail += len as u64;
+
+        let result = match self.sync_policy {
+            // 同一组的记录留在缓冲区里，等最后一条一起写
+            _ if flags & FLAG_BATCH != 0 => Ok(()),
+            SyncPolicy::Buffered if self.buf.len() < self.write_buffer_size => Ok(()),
+            SyncPolicy::Buffered | SyncPolicy::WriteThrough => self.flush(),
+            SyncPolicy::Always => self.sync(),
+        };
+        if let Err(e) = result {
+            // 这条记录算写入失败，之前缓冲区里的记录留着下次再写
+            self.buf.truncate(start);
+            self.tail = offset;
+            return Err(e);
         }
-        w.flush()?;
-        println!("write_entry: key: {:?}, value: {:?}", key, value);
-        println!("offset: {}, len: {}", offset, len);
-        println!("key_len: {}, value_len_or_tomestone: {:?}", key_len, value_len_or_tomestone);
+        self.next_seq = self.next_seq.max(seq + 1);
+        self.last_write = (seq, timestamp);
+        self.clean = false;
+        debug!("write_entry: offset: {}, len: {}, key_len: {}, value_len_or_tomestone: {}, seq: {}", offset, len, key_len, value_len_or_tomestone, seq);
 
-        Ok((offset, len))
+        Ok((value_pos, value_len, flags & VALUE_FLAGS))
     }
 
-    pub fn load_index(&mut self) -> Result<KeyDir> {
-        let mut index = KeyDir::new();
-        let mut len_buf = [0; 4];
+    /// 把几条记录作为一组写入，seq 依次递增，写入时间相同，返回每条的位置
+    ///
+    /// 除了最后一条都带上 FLAG_BATCH，整组在缓冲区里攒齐之后一起写到文件，中途失败时整组撤掉；
+    /// 崩溃留下的半组记录打开时会被截掉，所以一组记录要么全部生效要么全部丢掉。
+    pub fn write_batch(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<Vec<Position>> {
+        let (buf_len, tail, next_seq, last_write) = (self.buf.len(), self.tail, self.next_seq, self.last_write);
+        let timestamp = self.timestamps.then(now_millis);
+        let mut positions = Vec::with_capacity(entries.len());
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let flags = if i + 1 < entries.len() { FLAG_BATCH } else { 0 };
+            let seq = self.next_seq;
+            match self.write_entry_at(key, *value, seq, flags, timestamp) {
+                Ok(pos) => positions.push(pos),
+                Err(e) => {
+                    self.buf.truncate(buf_len);
+                    self.tail = tail;
+                    self.next_seq = next_seq;
+                    self.last_write = last_write;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(positions)
+    }
 
-        let file_size = self.file.metadata()?.len();
-        let mut r = BufReader::with_capacity(1024, &mut self.file);
-        let mut pos: u64 = r.seek(SeekFrom::Start(0))?;
-
-        while pos < file_size {
-            let read_one = || -> Result<(Vec<u8>, u64, Option<u32>)> {
-                r.read_exact(&mut len_buf)?;
-                let key_len = u32::from_be_bytes(len_buf);
-                r.read_exact(&mut len_buf)?;
-                let value_len_or_tomestone = match i32::from_be_bytes(len_buf) {
-                    v if v >= 0 => Some(v as u32),
-                    _ => None,
-                };
+    /// 最后写入的一条记录的 seq 和写入时间
+    pub fn last_write(&self) -> (u64, Option<u64>) {
+        self.last_write
+    }
 
-                let value_pos: u64 = pos + (KEY_VAL_HEADER_LEN * 2 + key_len) as u64;
+    /// 已经写到文件里的位置
+    fn flushed(&self) -> u64 {
+        self.tail - self.buf.len() as u64
+    }
 
-                println!("pos: {}, key_len: {}, value_len_or_tomestone: {:?}", pos, key_len, value_len_or_tomestone);
+    /// 把缓冲区写到文件里（不 fsync）
+    pub fn flush(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let offset = self.flushed();
+        if let Err(e) = self.file.write_all_at(&self.buf, offset) {
+            // 写了一半的记录会让后面追加的记录都读不出来，尽量截掉，缓冲区留着重试
+            let _ = self.file.set_len(offset);
+            return Err(e.into());
+        }
+        self.buf.clear();
+        Ok(())
+    }
 
-                let mut key = vec![0; key_len as usize];
-                r.read_exact(&mut key)?;
-                if let Some(value_len) = value_len_or_tomestone {
-                    r.seek_relative(value_len as i64)?;
-                }
-                Ok((key, value_pos, value_len_or_tomestone))
-            }();
-            match read_one {
-                Ok((key, value_pos, Some(value_len))) => {
-                    index.insert(key, (value_pos, value_len));
-                    pos = value_pos + value_len as u64;
+    /// 从头顺序读所有记录，read_value 为 true 时同时读出 value
+    pub fn iter(&mut self, read_value: bool) -> Result<LogIter<'_>> {
+        self.iter_from(0, 0, read_value)
+    }
+
+    /// 从 pos 开始读，pos 必须是一条记录的起始位置，last_seq 是前一条记录的 seq
+    pub fn iter_from(&mut self, pos: u64, last_seq: u64, read_value: bool) -> Result<LogIter<'_>> {
+        self.flush()?;
+        let end = self.tail;
+        let mut r = BufReader::with_capacity(1024, StorageReader { file: &*self.file, pos: 0 });
+        let pos = r.seek(SeekFrom::Start(pos))?;
+        let cipher = self.cipher.clone();
+        Ok(LogIter { r, pos, end, last_seq, read_value, cipher })
+    }
+
+    /// 清空日志，重新开始
+    ///
+    /// 快照共享同一个文件句柄，已经有快照的日志不要截断，换成新文件再 rename。
+    pub fn truncate(&mut self) -> Result<()> {
+        self.buf.clear();
+        self.file.set_len(0)?;
+        self.tail = 0;
+        self.clean = false;
+        self.value_bytes = (0, 0);
+        self.next_seq = 1;
+        self.compacted_seq = 0;
+        // 换一个 file_id，之前写过的密文挪到新文件里也解不开
+        if let Some(cipher) = &self.cipher {
+            let cipher = cipher.regenerate()?;
+            self.write_raw(&[], Some(&cipher.encode_meta()?), 0, FLAG_META, None)?;
+            self.cipher = Some(cipher);
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        Ok(self.file.sync()?)
+    }
+
+    /// 在末尾写上正常关闭的标记并落盘；空文件不写，下次打开时也没什么要检查的
+    pub fn close(&mut self) -> Result<()> {
+        if !self.clean && self.tail > 0 {
+            self.write_raw(CLEAN_KEY, None, 0, FLAG_META, None)?;
+            self.clean = true;
+        }
+        self.sync()
+    }
+
+    /// 读出所有的 value，检查校验表、认证标签和压缩的数据，上次没有正常关闭时在打开的时候调用
+    pub fn validate(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        let mut iter = self.iter(true)?;
+        loop {
+            let offset = iter.position();
+            match iter.next() {
+                None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.context(format!("log file {} is corrupted at {}", path.display(), offset))),
+            }
+        }
+    }
+
+    /// 重建索引；文件末尾写了一半的记录（崩溃或者写失败留下的）会被截掉
+    pub fn load_index(&mut self, index: &mut dyn Indexer, pending: &mut PendingOperands) -> Result<()> {
+        if let Some(offset) = self.scan_index(index, pending)? {
+            self.file.set_len(offset)?;
+        }
+        Ok(())
+    }
+
+    /// 和 load_index 一样重建索引，但是不修改文件：写了一半的尾部只是不再读，返回它的位置
+    pub fn scan_index(&mut self, index: &mut dyn Indexer, pending: &mut PendingOperands) -> Result<Option<u64>> {
+        self.scan_from(index, pending, None)
+    }
+
+    /// 持久化索引的恢复点：索引里已经有当前日志末尾之前所有记录的效果，pending 是这时的 operand
+    ///
+    /// 缓冲区会先写到文件。末尾之前一段数据的 crc 也记下来，打开时用来确认日志还是同一个文件。
+    pub fn checkpoint(&mut self, pending: &PendingOperands) -> Result<Vec<u8>> {
+        self.flush()?;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.tail.to_be_bytes());
+        buf.extend_from_slice(&self.tail_crc(self.tail)?.to_be_bytes());
+        buf.extend_from_slice(&self.next_seq.to_be_bytes());
+        buf.extend_from_slice(&self.compacted_seq.to_be_bytes());
+        buf.push(self.clean as u8);
+        buf.extend_from_slice(&(pending.len() as u32).to_be_bytes());
+        for (key, p) in pending {
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key);
+            match p.base {
+                Some(pos) => {
+                    buf.push(1);
+                    encode_position(&mut buf, pos);
                 }
-                Ok((key, value_pos, None)) => {
-                    index.remove(&key);
-                    pos = value_pos;
+                None => buf.push(0),
+            }
+            buf.extend_from_slice(&(p.operands.len() as u32).to_be_bytes());
+            for &pos in &p.operands {
+                encode_position(&mut buf, pos);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// 从 checkpoint 接着重建索引，只读它之后的记录
+    ///
+    /// checkpoint 和日志对不上（日志被 merge、截断或者换掉了）时什么都不改，返回 false，要从头重建。
+    pub fn resume_index(&mut self, index: &mut dyn Indexer, pending: &mut PendingOperands, checkpoint: &[u8]) -> Result<bool> {
+        let Ok(checkpoint) = Checkpoint::decode(checkpoint) else {
+            return Ok(false);
+        };
+        if checkpoint.offset > self.tail || self.tail_crc(checkpoint.offset)? != checkpoint.crc {
+            return Ok(false);
+        }
+        pending.clone_from(&checkpoint.pending);
+        if let Some(offset) = self.scan_from(index, pending, Some(&checkpoint))? {
+            self.file.set_len(offset)?;
+        }
+        Ok(true)
+    }
+
+    /// offset 之前最后 CHECKPOINT_WINDOW 个字节的 crc
+    fn tail_crc(&self, offset: u64) -> Result<u32> {
+        let start = offset.saturating_sub(CHECKPOINT_WINDOW);
+        let mut data = vec![0; (offset - start) as usize];
+        self.file.read_exact_at(&mut data, start)?;
+        Ok(crc32fast::hash(&data))
+    }
+
+    /// 从 checkpoint（None 表示文件开头）开始读记录放进索引
+    fn scan_from(&mut self, index: &mut dyn Indexer, pending: &mut PendingOperands, checkpoint: Option<&Checkpoint>) -> Result<Option<u64>> {
+        let (start, mut next_seq, mut compacted_seq, mut clean) =
+            checkpoint.map_or((0, 1, 0, false), |c| (c.offset, c.next_seq, c.compacted_seq, c.clean));
+        let mut torn = None;
+        // 同一组里还没等到最后一条的记录，以及这一组开始的位置
+        let mut group = Vec::new();
+        let mut group_start = 0;
+        let mut iter = self.iter_from(start, next_seq - 1, false)?;
+        loop {
+            let offset = iter.position();
+            let entry = match iter.next() {
+                None => break,
+                Some(Ok((entry, _))) => entry,
+                Some(Err(e)) if is_eof(&e) => {
+                    torn = Some(offset);
+                    break;
                 }
-                Err(e) => {
-                    return Err(e);
+                Some(Err(e)) => return Err(e),
+            };
+            debug!("pos: {}, key_len: {}, value_len_or_tomestone: {:?}, seq: {}", entry.offset, entry.key.len(), entry.value_len, entry.seq);
+            clean = entry.flags & FLAG_META != 0 && entry.key == CLEAN_KEY;
+            if entry.flags & FLAG_META != 0 {
+                continue;
+            }
+            if entry.flags & FLAG_MERGE_MARK != 0 {
+                next_seq = next_seq.max(entry.seq + 1);
+                compacted_seq = entry.seq;
+                continue;
+            }
+            if entry.flags & FLAG_BATCH != 0 {
+                if group.is_empty() {
+                    group_start = offset;
                 }
+                group.push(entry);
+                continue;
             }
+            for entry in group.drain(..).chain([entry]) {
+                next_seq = next_seq.max(entry.seq + 1);
+                apply_entry(index, pending, entry)?;
+            }
+        }
+        // 最后一组没有写完，和写了一半的记录一样截掉
+        if !group.is_empty() {
+            torn = Some(group_start);
+        }
+        if let Some(offset) = torn {
+            self.tail = offset;
+            clean = false;
+        }
+        self.next_seq = next_seq;
+        self.compacted_seq = compacted_seq;
+        self.clean = clean;
+        Ok(torn)
+    }
+}
+
+/// `Log::checkpoint` 写下的恢复点
+struct Checkpoint {
+    offset: u64,
+    crc: u32,
+    next_seq: u64,
+    compacted_seq: u64,
+    clean: bool,
+    pending: PendingOperands,
+}
+
+impl Checkpoint {
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let offset = read_u64(&mut buf)?;
+        let crc = read_u32(&mut buf)?;
+        let next_seq = read_u64(&mut buf)?;
+        let compacted_seq = read_u64(&mut buf)?;
+        let mut clean = [0; 1];
+        buf.read_exact(&mut clean)?;
+        let mut pending = PendingOperands::new();
+        for _ in 0..read_u32(&mut buf)? {
+            let mut key = vec![0; read_u32(&mut buf)? as usize];
+            buf.read_exact(&mut key)?;
+            let mut has_base = [0; 1];
+            buf.read_exact(&mut has_base)?;
+            let base = match has_base[0] {
+                0 => None,
+                _ => Some(decode_position(&mut buf)?),
+            };
+            let operands = (0..read_u32(&mut buf)?)
+                .map(|_| decode_position(&mut buf))
+                .collect::<Result<Vec<_>>>()?;
+            pending.insert(key, Pending { base, operands });
         }
-        Ok(index)
+        Ok(Self { offset, crc, next_seq, compacted_seq, clean: clean[0] != 0, pending })
     }
 }
 
-// #[cfg(unix)]
-// fn read_at_position(file: &std::fs::File, buffer: &mut [u8], pos: u64) -> std::io::Result<usize> {
-//     use std::os::unix::fs::FileExt;
-//     file.read_at(buffer, pos)  // Unix 使用 read_at
-// }
+fn encode_position(buf: &mut Vec<u8>, (value_pos, value_len, flags): Position) {
+    buf.extend_from_slice(&value_pos.to_be_bytes());
+    buf.extend_from_slice(&value_len.to_be_bytes());
+    buf.push(flags);
+}
 
-// #[cfg(windows)]
-// fn read_at_position(file: &std::fs::File, buffer: &mut [u8], pos: u64) -> std::io::Result<usize> {
-//     use std::os::windows::fs::FileExt;
-//     file.seek_read(buffer, pos)  // Windows 使用 seek_read
-// }
+fn decode_position(buf: &mut &[u8]) -> Result<Position> {
+    let value_pos = read_u64(buf)?;
+    let value_len = read_u32(buf)?;
+    let mut flags = [0; 1];
+    buf.read_exact(&mut flags)?;
+    Ok((value_pos, value_len, flags[0]))
+}
+
+fn read_u32(buf: &mut &[u8]) -> Result<u32> {
+    let mut b = [0; 4];
+    buf.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut b = [0; 8];
+    buf.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+/// 把一条记录放进索引
+fn apply_entry(index: &mut dyn Indexer, pending: &mut PendingOperands, entry: Entry) -> Result<()> {
+    match entry.value_len {
+        Some(value_len) if entry.flags & FLAG_OPERAND != 0 => {
+            let pos = (entry.value_pos, value_len, entry.flags & VALUE_FLAGS);
+            let prev = index.put(entry.key.clone(), pos)?;
+            push_operand(pending, &entry.key, prev, pos);
+        }
+        Some(value_len) => {
+            pending.remove(&entry.key);
+            index.put(entry.key, (entry.value_pos, value_len, entry.flags & VALUE_FLAGS))?;
+        }
+        None => {
+            pending.remove(&entry.key);
+            index.delete(&entry.key)?;
+        }
+    }
+    Ok(())
+}
+
+/// 从文件里读出 pos 上的 value：有校验表的先逐块校验，再解密、解压
+///
+/// Log 和快照读 value 都走这里，快照读的是创建时共享的文件句柄。
+pub(crate) fn read_stored(file: &dyn StorageFile, cipher: Option<&Cipher>, pos: Position) -> Result<Vec<u8>> {
+    let (value_pos, value_len, flags) = pos;
+    let mut value = vec![0; value_len as usize];
+    file.read_exact_at(&mut value, value_pos)?;
+    if flags & FLAG_CHECKSUM != 0 {
+        verify_chunks(file, pos, 0, &value)?;
+    }
+    let value = match cipher {
+        Some(cipher) => cipher.decrypt_value(value_pos, &value)?,
+        None => value,
+    };
+    decompress(flags, value)
+}
+
+/// 检查从第 first 块开始的 data（要按块对齐）
+fn verify_chunks(file: &dyn StorageFile, (value_pos, value_len, _): Position, first: u32, data: &[u8]) -> Result<()> {
+    let mut table = vec![0; data.len().div_ceil(CHECKSUM_CHUNK as usize) * 4];
+    file.read_exact_at(&mut table, value_pos + value_len as u64 + first as u64 * 4)?;
+    for (i, (chunk, crc)) in data.chunks(CHECKSUM_CHUNK as usize).zip(table.chunks(4)).enumerate() {
+        if crc32fast::hash(chunk).to_be_bytes() != crc {
+            bail!("checksum mismatch in value at {}, chunk {}", value_pos, first as usize + i);
+        }
+    }
+    Ok(())
+}
+
+/// 按 flags 里的压缩方式解压，没有压缩的原样返回
+fn decompress(flags: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+    match Compression::from_flags(flags) {
+        Compression::None => Ok(value),
+        codec => codec.decompress(&value),
+    }
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+}
+
+pub struct LogIter<'a> {
+    r: BufReader<StorageReader<'a>>,
+    pos: u64,
+    end: u64,
+    last_seq: u64,
+    read_value: bool,
+    cipher: Option<Cipher>,
+}
+
+impl LogIter<'_> {
+    /// 下一条要读的记录的位置
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    fn read_one(&mut self) -> Result<(Entry, Option<Vec<u8>>)> {
+        let mut len_buf = [0; 4];
+        self.r.read_exact(&mut len_buf)?;
+        let key_len = u32::from_be_bytes(len_buf);
+        self.r.read_exact(&mut len_buf)?;
+        let value_len = match i32::from_be_bytes(len_buf) {
+            v if v >= 0 => Some(v as u32),
+            _ => None,
+        };
+
+        let (key_len, seq, flags, header_len) = if key_len & EXTENDED_BIT != 0 {
+            let mut ext = [0; EXTENDED_HEADER_LEN as usize];
+            self.r.read_exact(&mut ext)?;
+            let seq = u64::from_be_bytes(ext[..8].try_into()?);
+            (key_len & !EXTENDED_BIT, seq, ext[8], KEY_VAL_HEADER_LEN * 2 + EXTENDED_HEADER_LEN)
+        } else {
+            (key_len, self.last_seq + 1, 0, KEY_VAL_HEADER_LEN * 2)
+        };
+
+        let mut key = vec![0; key_len as usize];
+        self.r.read_exact(&mut key)?;
+        let value_pos = self.pos + (header_len + key_len) as u64;
+        let checksum = match value_len {
+            Some(value_len) if flags & FLAG_CHECKSUM != 0 => checksum_len(value_len),
+            _ => 0,
+        };
+        let timestamp_len = if flags & FLAG_TIMESTAMP != 0 { TIMESTAMP_LEN } else { 0 };
+        // 跳过 value 的时候不会读到文件末尾，要单独检查记录是不是完整的
+        if value_pos + value_len.unwrap_or(0) as u64 + checksum + timestamp_len > self.end {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        // 元信息本身是明文
+        let cipher = self.cipher.as_ref().filter(|_| flags & FLAG_META == 0);
+        if let Some(cipher) = cipher {
+            key = cipher.decrypt_key(self.pos, key)?;
+        }
+        let value = match value_len {
+            Some(value_len) if self.read_value => {
+                let mut value = vec![0; value_len as usize];
+                self.r.read_exact(&mut value)?;
+                let mut table = vec![0; checksum as usize];
+                self.r.read_exact(&mut table)?;
+                if checksum > 0 && checksum_table(&value) != table {
+                    bail!("checksum mismatch in value at {}", value_pos);
+                }
+                let value = match cipher {
+                    Some(cipher) => cipher.decrypt_value(value_pos, &value)?,
+                    None => value,
+                };
+                match flags & COMPRESSION_FLAGS {
+                    0 => Some(value),
+                    _ => Some(Compression::from_flags(flags).decompress(&value)?),
+                }
+            }
+            Some(value_len) => {
+                self.r.seek_relative(value_len as i64 + checksum as i64)?;
+                None
+            }
+            None => None,
+        };
+        let timestamp = if timestamp_len > 0 {
+            let mut buf = [0; TIMESTAMP_LEN as usize];
+            self.r.read_exact(&mut buf)?;
+            Some(u64::from_be_bytes(buf))
+        } else {
+            None
+        };
+        let entry = Entry {
+            key,
+            seq,
+            flags,
+            offset: self.pos,
+            value_pos,
+            value_len,
+            timestamp,
+        };
+        Ok((entry, value))
+    }
+}
+
+impl Iterator for LogIter<'_> {
+    type Item = Result<(Entry, Option<Vec<u8>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+        match self.read_one() {
+            Ok((entry, value)) => {
+                self.pos = entry.end();
+                self.last_seq = entry.seq;
+                Some(Ok((entry, value)))
+            }
+            Err(e) => {
+                self.pos = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::index::IndexType;
+    use std::ops::Bound;
     use std::process::Command;
 
+    #[test]
+    fn test_read_values() -> Result<()> {
+        let mut log = Log::open(&crate::storage::MemoryStorage::new(), PathBuf::from("db/log"))?;
+        let a = log.write_entry(b"a", Some(b"val1"))?;
+        let b = log.write_entry(b"b", Some(b"val2"))?;
+        // 中间隔开一大段，分成两次读
+        log.write_entry(b"x", Some(&vec![0; 2 * COALESCE_GAP as usize]))?;
+        let c = log.write_entry(b"c", Some(b"val3"))?;
+
+        let values = log.read_values(&[c, a, b, a])?;
+        assert_eq!(values, vec![b"val3".to_vec(), b"val1".to_vec(), b"val2".to_vec(), b"val1".to_vec()]);
+        assert!(log.read_values(&[])?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_log() -> Result<()> {
         let tmp_dir = tempfile::TempDir::new_in(".")?;
@@ -139,13 +1022,50 @@ mod tests {
         // delete
         log.write_entry(b"c", None)?;
 
-        let key_dir = log.load_index()?;
+        let mut key_dir = IndexType::BTree.new_indexer(&log.path)?;
+        log.load_index(key_dir.as_mut(), &mut PendingOperands::new())?;
         assert_eq!(key_dir.len(), 2);
-        let mut keys = key_dir.keys().collect::<Vec<_>>();
-        keys.sort();
+        let keys = key_dir
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .map(|r| r.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
         assert_eq!(keys, &[b"a", b"b"]);
+        assert_eq!(log.next_seq, 6);
 
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_records() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let tmp_path = tmp_dir.path().join("test.db");
+
+        // 旧格式：key len(4) val len(4) key value，没有 seq
+        let mut legacy = Vec::new();
+        for (key, value) in [(&b"a"[..], Some(&b"v1"[..])), (b"b", Some(b"v2")), (b"a", None)] {
+            legacy.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            legacy.extend_from_slice(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+            legacy.extend_from_slice(key);
+            legacy.extend_from_slice(value.unwrap_or_default());
+        }
+        std::fs::write(&tmp_path, legacy)?;
+
+        let mut log = Log::new(tmp_path)?;
+        let mut key_dir = IndexType::BTree.new_indexer(&log.path)?;
+        log.load_index(key_dir.as_mut(), &mut PendingOperands::new())?;
+        assert_eq!(key_dir.len(), 1);
+        let pos = key_dir.get(b"b")?.unwrap();
+        assert_eq!(log.read_value(pos)?, b"v2");
 
+        // 新写入的记录接着旧记录的 seq
+        log.write_entry(b"c", Some(b"v3"))?;
+        let seqs = log
+            .iter(false)?
+            .map(|r| r.map(|(e, _)| e.seq))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(seqs, [1, 2, 3, 4]);
+        let (entry, value) = log.iter(true)?.last().unwrap()?;
+        assert_eq!((entry.key, value), (b"c".to_vec(), Some(b"v3".to_vec())));
         Ok(())
     }
 
@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// 按容量淘汰的 LRU 缓存，每个元素有自己的 charge（比如字节数）
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    usage: usize,
+    tick: u64,
+    map: HashMap<K, (V, usize, u64)>,
+    // tick -> key，tick 越小越久没有访问
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            usage: 0,
+            tick: 0,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let (value, _, tick) = self.map.get_mut(key)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V, charge: usize) {
+        self.remove(&key);
+        if charge > self.capacity {
+            return;
+        }
+        while self.usage + charge > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, c, _)) = self.map.remove(&oldest) {
+                self.usage -= c;
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.map.insert(key, (value, charge, self.tick));
+        self.usage += charge;
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, charge, tick) = self.map.remove(key)?;
+        self.order.remove(&tick);
+        self.usage -= charge;
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+        self.usage = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// 当前占用的 charge 总和
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_evict() {
+        let mut cache = LruCache::new(3);
+        cache.insert(1, "a", 1);
+        cache.insert(2, "b", 1);
+        cache.insert(3, "c", 1);
+        // 访问 1 之后，最久没访问的是 2
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.insert(4, "d", 1);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.len(), 3);
+
+        // 大的元素会挤掉多个
+        cache.insert(5, "e", 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.usage(), 3);
+        assert_eq!(cache.get(&4), Some("d"));
+
+        // 超过容量的不缓存
+        cache.insert(6, "f", 4);
+        assert_eq!(cache.get(&6), None);
+
+        assert_eq!(cache.remove(&4), Some("d"));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
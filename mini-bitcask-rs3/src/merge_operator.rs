@@ -0,0 +1,110 @@
+use crate::index::Position;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// 用户定义的合并函数，`merge_value` 只追加 operand，读的时候再把 operand 合并到原来的值上
+pub trait MergeOperator: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// existing 为 None 表示 key 不存在（或者已经被删除），operands 按写入的顺序排列
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>>;
+}
+
+/// 把 operand 依次拼接到原来的值后面
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        Ok(value)
+    }
+}
+
+/// 值和 operand 都是 8 字节大端的 i64，和 `MiniBitcask::increment` 的编码一致
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddOperator;
+
+fn decode_i64(key: &[u8], bytes: &[u8]) -> Result<i64> {
+    match <[u8; 8]>::try_from(bytes) {
+        Ok(bytes) => Ok(i64::from_be_bytes(bytes)),
+        Err(_) => bail!("value of key {:?} is not an i64 counter", key),
+    }
+}
+
+impl MergeOperator for AddOperator {
+    fn name(&self) -> &str {
+        "add"
+    }
+
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut sum = existing.map_or(Ok(0), |v| decode_i64(key, v))?;
+        for operand in operands {
+            sum = sum.wrapping_add(decode_i64(key, operand)?);
+        }
+        Ok(sum.to_be_bytes().to_vec())
+    }
+}
+
+/// 还没有被 compaction 合并掉的 operand，索引里存的是最后一个 operand 的位置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pending {
+    /// 第一个 operand 之前的完整值
+    pub base: Option<Position>,
+    pub operands: Vec<Position>,
+}
+
+pub type PendingOperands = HashMap<Vec<u8>, Pending>;
+
+/// 记下一个新写入的 operand，prev 是写入之前索引里的位置
+pub(crate) fn push_operand(pending: &mut PendingOperands, key: &[u8], prev: Option<Position>, pos: Position) {
+    pending
+        .entry(key.to_vec())
+        .or_insert_with(|| Pending { base: prev, operands: Vec::new() })
+        .operands
+        .push(pos);
+}
+
+/// 读出 key 当前的值，有 pending 的 operand 时合并之后返回
+pub(crate) fn resolve(
+    pending: Option<&Pending>,
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    pos: Position,
+    mut read: impl FnMut(Position) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let Some(pending) = pending else {
+        return read(pos);
+    };
+    let Some(operator) = operator else {
+        bail!("key {:?} has merge operands but no merge operator is configured", key);
+    };
+    let base = pending.base.map(&mut read).transpose()?;
+    let operands = pending.operands.iter().map(|&pos| read(pos)).collect::<Result<Vec<_>>>()?;
+    operator.full_merge(key, base.as_deref(), &operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators() -> Result<()> {
+        let ops = [b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(AppendOperator.full_merge(b"k", Some(b"a"), &ops)?, b"abc".to_vec());
+        assert_eq!(AppendOperator.full_merge(b"k", None, &ops)?, b"bc".to_vec());
+
+        let ops = [3i64.to_be_bytes().to_vec(), (-1i64).to_be_bytes().to_vec()];
+        assert_eq!(AddOperator.full_merge(b"k", Some(&10i64.to_be_bytes()), &ops)?, 12i64.to_be_bytes().to_vec());
+        assert_eq!(AddOperator.full_merge(b"k", None, &ops)?, 2i64.to_be_bytes().to_vec());
+        assert!(AddOperator.full_merge(b"k", Some(b"x"), &ops).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::bitcask::{MiniBitcask, ScanIter, Stats};
+use crate::index::KeyRange;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::ops::{Bound, RangeBounds};
+
+/// 保留给 namespace 的 key 前缀
+///
+/// namespace 里的 key 落盘时是 `NAMESPACE_PREFIX + id(4, 大端) + key`，
+/// 和默认 namespace 共用同一个日志、索引和写入路径。默认 namespace 里以这个前缀开头的 key
+/// 照样能用，落盘时转义到 `ESCAPED_ID` 下面，见 `escape_key`。
+pub const NAMESPACE_PREFIX: &[u8] = b"\xff\xff\xffns:";
+const NAMESPACE_PREFIX_END: &[u8] = b"\xff\xff\xffns;";
+
+/// id 0 是目录：`name -> id`，空的 name 存下一个可用的 id
+pub(crate) const CATALOG_ID: u32 = 0;
+
+/// 默认 namespace 里以保留前缀开头的 key 放在这个 id 下，u32::MAX 留给数据结构
+pub(crate) const ESCAPED_ID: u32 = u32::MAX - 1;
+
+pub(crate) fn key_prefix(id: u32) -> Vec<u8> {
+    let mut prefix = NAMESPACE_PREFIX.to_vec();
+    prefix.extend_from_slice(&id.to_be_bytes());
+    prefix
+}
+
+pub(crate) fn encode_key(id: u32, key: &[u8]) -> Vec<u8> {
+    let mut encoded = key_prefix(id);
+    encoded.extend_from_slice(key);
+    encoded
+}
+
+/// key 属于哪个 namespace，默认 namespace 返回 None
+pub(crate) fn namespace_id(key: &[u8]) -> Option<u32> {
+    let id = key.strip_prefix(NAMESPACE_PREFIX)?.get(..4)?;
+    Some(u32::from_be_bytes(id.try_into().ok()?))
+}
+
+/// 默认 namespace 的 key 落盘时的样子：以保留前缀开头的转义到 ESCAPED_ID 下，其他的原样
+///
+/// 转义之后的 key 还在保留前缀的范围里，和原来的 key 排序一致，范围查询可以直接转义两头。
+pub(crate) fn escape_key(key: &[u8]) -> Cow<'_, [u8]> {
+    if key.starts_with(NAMESPACE_PREFIX) {
+        Cow::Owned(encode_key(ESCAPED_ID, key))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+/// 落盘的 key 在默认 namespace 里的样子，其他 namespace 和数据结构的 key 返回 None
+pub(crate) fn unescape_key(key: &[u8]) -> Option<&[u8]> {
+    if !key.starts_with(NAMESPACE_PREFIX) {
+        return Some(key);
+    }
+    match namespace_id(key) {
+        Some(ESCAPED_ID) => Some(&key[NAMESPACE_PREFIX.len() + 4..]),
+        _ => None,
+    }
+}
+
+pub(crate) fn escape_range(range: impl RangeBounds<Vec<u8>>) -> KeyRange {
+    let escape = |bound: Bound<&Vec<u8>>| bound.map(|key| escape_key(key).into_owned());
+    (escape(range.start_bound()), escape(range.end_bound()))
+}
+
+/// 一个 namespace 的所有 key
+pub(crate) fn id_range(id: u32) -> KeyRange {
+    let end = match id.checked_add(1) {
+        Some(next) => Bound::Excluded(key_prefix(next)),
+        None => Bound::Excluded(NAMESPACE_PREFIX_END.to_vec()),
+    };
+    (Bound::Included(key_prefix(id)), end)
+}
+
+/// 所有 namespace（包括目录）的 key
+pub(crate) fn reserved_range() -> KeyRange {
+    (
+        Bound::Included(NAMESPACE_PREFIX.to_vec()),
+        Bound::Excluded(NAMESPACE_PREFIX_END.to_vec()),
+    )
+}
+
+/// `MiniBitcask::namespace` 返回的句柄，key 都在自己的空间里，互不影响
+pub struct Namespace<'a> {
+    pub(crate) db: &'a mut MiniBitcask,
+    pub(crate) name: String,
+    pub(crate) id: u32,
+}
+
+impl Namespace<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_raw(&encode_key(self.id, key))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.db.set_raw(&encode_key(self.id, key), value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.db.delete_raw(&encode_key(self.id, key))
+    }
+
+    pub fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> NamespaceIter<'_> {
+        let (start, end) = id_range(self.id);
+        let start = match range.start_bound() {
+            Bound::Included(k) => Bound::Included(encode_key(self.id, k)),
+            Bound::Excluded(k) => Bound::Excluded(encode_key(self.id, k)),
+            Bound::Unbounded => start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Included(encode_key(self.id, k)),
+            Bound::Excluded(k) => Bound::Excluded(encode_key(self.id, k)),
+            Bound::Unbounded => end,
+        };
+        NamespaceIter {
+            inner: self.db.scan_raw((start, end), false),
+            prefix_len: NAMESPACE_PREFIX.len() + 4,
+        }
+    }
+
+    /// key 的个数是准确的，索引内存按 key 的比例估算，读缓存是整个库共用的
+    pub fn stats(&self) -> Stats {
+        let keys = self.db.count_range(id_range(self.id));
+        let total = self.db.index_stats();
+        Stats {
+            keys,
+            index_bytes: (total.index_bytes * keys).checked_div(total.keys).unwrap_or(0),
+            ..total
+        }
+    }
+}
+
+pub struct NamespaceIter<'a> {
+    inner: ScanIter<'a>,
+    prefix_len: usize,
+}
+
+impl NamespaceIter<'_> {
+    fn strip(&self, item: Result<(Vec<u8>, Vec<u8>)>) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (key, value) = item?;
+        Ok((key[self.prefix_len..].to_vec(), value))
+    }
+}
+
+impl Iterator for NamespaceIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|item| self.strip(item))
+    }
+}
+
+impl DoubleEndedIterator for NamespaceIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|item| self.strip(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_encoding() {
+        let key = encode_key(7, b"abc");
+        assert_eq!(namespace_id(&key), Some(7));
+        assert_eq!(namespace_id(b"abc"), None);
+        assert_eq!(namespace_id(NAMESPACE_PREFIX), None);
+
+        assert!(id_range(7).contains(&key));
+        assert!(!id_range(8).contains(&key));
+        assert!(!id_range(7).contains(&b"abc".to_vec()));
+        assert!(reserved_range().contains(&encode_key(u32::MAX, b"")));
+        assert!(id_range(u32::MAX).contains(&encode_key(u32::MAX, b"\xff")));
+    }
+
+    #[test]
+    fn test_escape_key() {
+        let reserved = encode_key(3, b"a");
+        let escaped = escape_key(&reserved).into_owned();
+        assert_eq!(namespace_id(&escaped), Some(ESCAPED_ID));
+        assert_eq!(unescape_key(&escaped), Some(&reserved[..]));
+        assert_eq!(escape_key(b"abc"), Cow::Borrowed(&b"abc"[..]));
+        assert_eq!(unescape_key(b"abc"), Some(&b"abc"[..]));
+        assert_eq!(unescape_key(&reserved), None);
+
+        // 转义前后的顺序一致
+        let mut keys = vec![b"\xff".to_vec(), reserved.clone(), NAMESPACE_PREFIX.to_vec(), b"\xff\xff\xffz".to_vec(), b"a".to_vec()];
+        keys.sort();
+        let mut escaped = keys.iter().map(|k| escape_key(k).into_owned()).collect::<Vec<_>>();
+        escaped.sort();
+        assert_eq!(escaped.iter().map(|k| unescape_key(k).unwrap().to_vec()).collect::<Vec<_>>(), keys);
+    }
+}
@@ -0,0 +1,484 @@
+//! primary/follower 日志复制
+//!
+//! follower 连上之后先发送自己最后应用的 seq，primary 从这个 seq 之后开始推送修改。
+//! 如果这部分历史已经被 merge 掉（或者 follower 比 primary 还新），primary 先发一个全量快照，
+//! follower 清空本地数据后重新写入，再接着推送增量。
+//!
+//! ```text
+//! follower -> primary: last_seq(8)
+//! primary -> follower: type(1) body
+//!   SNAPSHOT_BEGIN  seq(8)
+//!   SNAPSHOT_ENTRY  key value
+//!   SNAPSHOT_END    seq(8)
+//!   PUT             seq(8) key value
+//!   DELETE          seq(8) key
+//! key 和 value 都是 len(4) + 内容
+//! ```
+use crate::bitcask::{Change, MiniBitcask, Options};
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MSG_SNAPSHOT_BEGIN: u8 = 1;
+const MSG_SNAPSHOT_ENTRY: u8 = 2;
+const MSG_SNAPSHOT_END: u8 = 3;
+const MSG_PUT: u8 = 4;
+const MSG_DELETE: u8 = 5;
+const MSG_MERGE: u8 = 6;
+
+// 每次持锁最多读出多少条修改，避免长时间阻塞写入
+const MAX_BATCH: usize = 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    SnapshotBegin { seq: u64 },
+    SnapshotEntry { key: Vec<u8>, value: Vec<u8> },
+    SnapshotEnd { seq: u64 },
+    Change(Change),
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len_buf = [0; 4];
+    r.read_exact(&mut len_buf)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len_buf) as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_message(w: &mut impl Write, msg: &Message) -> Result<()> {
+    match msg {
+        Message::SnapshotBegin { seq } => {
+            w.write_all(&[MSG_SNAPSHOT_BEGIN])?;
+            w.write_all(&seq.to_be_bytes())?;
+        }
+        Message::SnapshotEntry { key, value } => {
+            w.write_all(&[MSG_SNAPSHOT_ENTRY])?;
+            write_bytes(w, key)?;
+            write_bytes(w, value)?;
+        }
+        Message::SnapshotEnd { seq } => {
+            w.write_all(&[MSG_SNAPSHOT_END])?;
+            w.write_all(&seq.to_be_bytes())?;
+        }
+        Message::Change(Change::Put { seq, key, value }) => {
+            w.write_all(&[MSG_PUT])?;
+            w.write_all(&seq.to_be_bytes())?;
+            write_bytes(w, key)?;
+            write_bytes(w, value)?;
+        }
+        Message::Change(Change::Delete { seq, key }) => {
+            w.write_all(&[MSG_DELETE])?;
+            w.write_all(&seq.to_be_bytes())?;
+            write_bytes(w, key)?;
+        }
+        Message::Change(Change::Merge { seq, key, operand }) => {
+            w.write_all(&[MSG_MERGE])?;
+            w.write_all(&seq.to_be_bytes())?;
+            write_bytes(w, key)?;
+            write_bytes(w, operand)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_message(r: &mut impl Read) -> Result<Message> {
+    let mut kind = [0; 1];
+    r.read_exact(&mut kind)?;
+    Ok(match kind[0] {
+        MSG_SNAPSHOT_BEGIN => Message::SnapshotBegin { seq: read_u64(r)? },
+        MSG_SNAPSHOT_ENTRY => Message::SnapshotEntry {
+            key: read_bytes(r)?,
+            value: read_bytes(r)?,
+        },
+        MSG_SNAPSHOT_END => Message::SnapshotEnd { seq: read_u64(r)? },
+        MSG_PUT => Message::Change(Change::Put {
+            seq: read_u64(r)?,
+            key: read_bytes(r)?,
+            value: read_bytes(r)?,
+        }),
+        MSG_DELETE => Message::Change(Change::Delete {
+            seq: read_u64(r)?,
+            key: read_bytes(r)?,
+        }),
+        MSG_MERGE => Message::Change(Change::Merge {
+            seq: read_u64(r)?,
+            key: read_bytes(r)?,
+            operand: read_bytes(r)?,
+        }),
+        other => bail!("unknown replication message type {}", other),
+    })
+}
+
+fn lock(db: &Mutex<MiniBitcask>) -> Result<MutexGuard<'_, MiniBitcask>> {
+    db.lock().map_err(|_| anyhow!("bitcask lock poisoned"))
+}
+
+/// 把本地的修改推送给 follower
+pub struct Primary {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    pub fn start(db: Arc<Mutex<MiniBitcask>>, addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let flag = shutdown.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let db = db.clone();
+                let flag = flag.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_follower(&db, stream, &flag) {
+                        warn!("replication: follower disconnected: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            addr,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // 连一下自己，让 accept 返回
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve_follower(db: &Mutex<MiniBitcask>, stream: TcpStream, shutdown: &AtomicBool) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut w = BufWriter::new(stream);
+    let mut seq = read_u64(&mut r)?;
+    // 上次读到的日志位置，merge 之后失效
+    let mut pos = 0;
+    let (events, mut merges) = {
+        let mut db = lock(db)?;
+        (db.watch_writes(), db.merges)
+    };
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut batch = Vec::new();
+        let mut snapshot = None;
+        {
+            let mut db = lock(db)?;
+            if merges != db.merges {
+                merges = db.merges;
+                pos = 0;
+            }
+            if seq < db.compacted_seq() || seq > db.last_seq() {
+                // namespace 和数据结构的 key 也要发过去，增量里同样带着它们
+                snapshot = Some(db.snapshot_raw((Bound::Unbounded, Bound::Unbounded), false)?);
+            } else {
+                let mut changes = db.changes_from(seq, pos)?;
+                for change in changes.by_ref().take(MAX_BATCH) {
+                    batch.push(change?);
+                }
+                pos = changes.position();
+            }
+        }
+        // 快照按位置读共享的文件句柄，发送时不持有锁，慢的 follower 不会阻塞写入
+        if let Some(snapshot) = snapshot {
+            write_message(&mut w, &Message::SnapshotBegin { seq: snapshot.seq() })?;
+            for item in snapshot.iter() {
+                let (key, value) = item?;
+                write_message(&mut w, &Message::SnapshotEntry { key, value })?;
+            }
+            write_message(&mut w, &Message::SnapshotEnd { seq: snapshot.seq() })?;
+            w.flush()?;
+            seq = snapshot.seq();
+            pos = 0;
+            continue;
+        }
+        for change in batch.iter() {
+            write_message(&mut w, &Message::Change(change.clone()))?;
+        }
+        w.flush()?;
+        if let Some(change) = batch.last() {
+            seq = change.seq();
+            if batch.len() == MAX_BATCH {
+                continue;
+            }
+        }
+
+        // 等待新的写入
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(_) | Err(RecvTimeoutError::Timeout) => while events.try_recv().is_ok() {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// 从 primary 同步数据的只读副本
+pub struct Follower {
+    db: Arc<Mutex<MiniBitcask>>,
+    shutdown: Arc<AtomicBool>,
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    snapshots: Arc<AtomicU64>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// 打开本地的 path，从它最后应用的 seq 开始跟随 primary，断线后自动重连
+    pub fn start(path: PathBuf, options: Options, primary: SocketAddr) -> Result<Self> {
+        let db = Arc::new(Mutex::new(MiniBitcask::open(path, options)?));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stream: Arc<Mutex<Option<TcpStream>>> = Arc::new(Mutex::new(None));
+        let snapshots = Arc::new(AtomicU64::new(0));
+
+        let handle = {
+            let (db, shutdown, stream, snapshots) = (db.clone(), shutdown.clone(), stream.clone(), snapshots.clone());
+            thread::spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    let connected = TcpStream::connect(primary).and_then(|s| Ok((s.try_clone()?, s)));
+                    if let Ok((s, conn)) = connected {
+                        {
+                            let Ok(mut slot) = stream.lock() else {
+                                break;
+                            };
+                            if shutdown.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            *slot = Some(s);
+                        }
+                        if let Err(e) = follow(&db, conn, &snapshots)
+                            && !shutdown.load(Ordering::SeqCst)
+                        {
+                            warn!("replication: lost primary {}: {}", primary, e);
+                        }
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+            })
+        };
+        Ok(Self {
+            db,
+            shutdown,
+            stream,
+            snapshots,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        lock(&self.db)?.get(key)
+    }
+
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        lock(&self.db)?.scan(range).collect()
+    }
+
+    /// 已经应用到本地的最后一个 seq
+    pub fn applied_seq(&self) -> Result<u64> {
+        Ok(lock(&self.db)?.last_seq())
+    }
+
+    /// 从快照重新同步的次数
+    pub fn snapshots(&self) -> u64 {
+        self.snapshots.load(Ordering::SeqCst)
+    }
+
+    /// 等到 seq 被应用，超时返回错误
+    pub fn wait_for(&self, seq: u64, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.applied_seq()? < seq {
+            if Instant::now() >= deadline {
+                bail!("timed out waiting for seq {}, applied {}", seq, self.applied_seq()?);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Ok(mut slot) = self.stream.lock()
+            && let Some(s) = slot.take()
+        {
+            let _ = s.shutdown(Shutdown::Both);
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn follow(db: &Mutex<MiniBitcask>, stream: TcpStream, snapshots: &AtomicU64) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let last_seq = lock(db)?.last_seq();
+    (&stream).write_all(&last_seq.to_be_bytes())?;
+    let mut r = BufReader::new(stream);
+    loop {
+        match read_message(&mut r)? {
+            Message::SnapshotBegin { .. } => lock(db)?.truncate()?,
+            Message::SnapshotEntry { key, value } => {
+                lock(db)?.apply(&Change::Put { seq: 0, key, value })?;
+            }
+            Message::SnapshotEnd { seq } => {
+                lock(db)?.finish_snapshot(seq)?;
+                snapshots.fetch_add(1, Ordering::SeqCst);
+            }
+            Message::Change(change) => lock(db)?.apply(&change)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_message_codec() -> Result<()> {
+        let messages = [
+            Message::SnapshotBegin { seq: 7 },
+            Message::SnapshotEntry { key: b"k".to_vec(), value: vec![] },
+            Message::SnapshotEnd { seq: 7 },
+            Message::Change(Change::Put { seq: 8, key: b"a".to_vec(), value: b"1".to_vec() }),
+            Message::Change(Change::Delete { seq: 9, key: b"a".to_vec() }),
+            Message::Change(Change::Merge { seq: 10, key: b"a".to_vec(), operand: b"+".to_vec() }),
+        ];
+        let mut buf = Vec::new();
+        for msg in &messages {
+            write_message(&mut buf, msg)?;
+        }
+        let mut r = buf.as_slice();
+        for msg in messages {
+            assert_eq!(read_message(&mut r)?, msg);
+        }
+        assert!(r.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let db = Arc::new(Mutex::new(MiniBitcask::new(tmp_dir.path().join("primary.db"))?));
+        lock(&db)?.set(b"a", b"1".to_vec())?;
+        lock(&db)?.set(b"b", b"2".to_vec())?;
+        let primary = Primary::start(db.clone(), "127.0.0.1:0")?;
+
+        let follower_path = tmp_dir.path().join("follower.db");
+        let follower = Follower::start(follower_path.clone(), Options::default(), primary.local_addr())?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(follower.get(b"a")?, Some(b"1".to_vec()));
+
+        // 增量同步
+        lock(&db)?.set(b"c", b"3".to_vec())?;
+        lock(&db)?.delete(b"a")?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(
+            follower.scan(..)?,
+            [(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]
+        );
+        assert_eq!(follower.snapshots(), 0);
+        drop(follower);
+
+        // 重启之后从上次应用的 seq 接着同步
+        lock(&db)?.set(b"d", b"4".to_vec())?;
+        let follower = Follower::start(follower_path.clone(), Options::default(), primary.local_addr())?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(follower.get(b"d")?, Some(b"4".to_vec()));
+        assert_eq!(follower.snapshots(), 0);
+        drop(follower);
+
+        // follower 需要的历史被 merge 掉了，只能从快照重新同步
+        lock(&db)?.delete(b"b")?;
+        lock(&db)?.set(b"e", b"5".to_vec())?;
+        lock(&db)?.merge()?;
+        lock(&db)?.set(b"f", b"6".to_vec())?;
+        let follower = Follower::start(follower_path, Options::default(), primary.local_addr())?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(follower.snapshots(), 1);
+        let expected = lock(&db)?.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(follower.scan(..)?, expected);
+        assert_eq!(follower.get(b"b")?, None);
+
+        // 在线的 follower 跟着 merge 之后的日志继续同步
+        lock(&db)?.merge()?;
+        lock(&db)?.set(b"g", b"7".to_vec())?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(follower.get(b"g")?, Some(b"7".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication_snapshot_with_namespaces() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new_in(".")?;
+        let db = Arc::new(Mutex::new(MiniBitcask::new(tmp_dir.path().join("primary.db"))?));
+        {
+            let mut db = lock(&db)?;
+            db.set(b"a", b"1".to_vec())?;
+            db.namespace("users")?.set(b"alice", b"admin".to_vec())?;
+            db.hset(b"h", b"f", b"v".to_vec())?;
+            db.lpush(b"l", b"x".to_vec())?;
+            // 增量的历史被 merge 掉，follower 只能从快照同步
+            db.merge()?;
+        }
+        let primary = Primary::start(db.clone(), "127.0.0.1:0")?;
+        let follower = Follower::start(tmp_dir.path().join("follower.db"), Options::default(), primary.local_addr())?;
+        let seq = lock(&db)?.last_seq();
+        follower.wait_for(seq, TIMEOUT)?;
+        assert_eq!(follower.snapshots(), 1);
+
+        let mut replica = lock(&follower.db)?;
+        assert_eq!(replica.namespace_names(), ["users"]);
+        assert_eq!(replica.namespace("users")?.get(b"alice")?, Some(b"admin".to_vec()));
+        assert_eq!(replica.hget(b"h", b"f")?, Some(b"v".to_vec()));
+        assert_eq!(replica.lrange(b"l", 0, -1)?, [b"x".to_vec()]);
+        let expected = lock(&db)?.scan_raw((Bound::Unbounded, Bound::Unbounded), false).collect::<Result<Vec<_>>>()?;
+        assert_eq!(replica.scan_raw((Bound::Unbounded, Bound::Unbounded), false).collect::<Result<Vec<_>>>()?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use crate::crypto::Cipher;
+use crate::index::Position;
+use crate::log::read_stored;
+use crate::merge_operator::{resolve, MergeOperator, PendingOperands};
+use crate::storage::StorageFile;
+use anyhow::Result;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// 某个时刻的只读视图
+///
+/// 创建时复制一份索引，并共享日志文件的句柄，之后的读都是按位置读，
+/// 不需要借用 MiniBitcask，所以 set/delete 可以照常进行。日志只会追加，
+/// 快照里记录的位置一直有效；merge 会用新文件替换旧文件，但快照手里的句柄还指向旧文件。
+/// 句柄上有文件锁，快照没有释放之前不能在别处重新打开同一个文件。
+#[derive(Clone)]
+pub struct Snapshot {
+    file: Arc<dyn StorageFile>,
+    cipher: Option<Cipher>,
+    entries: Arc<Vec<(Vec<u8>, Position)>>,
+    pending: Arc<PendingOperands>,
+    operator: Option<Arc<dyn MergeOperator>>,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        file: Arc<dyn StorageFile>,
+        cipher: Option<Cipher>,
+        entries: Vec<(Vec<u8>, Position)>,
+        pending: PendingOperands,
+        operator: Option<Arc<dyn MergeOperator>>,
+        seq: u64,
+    ) -> Self {
+        Self {
+            file,
+            cipher,
+            entries: Arc::new(entries),
+            pending: Arc::new(pending),
+            operator,
+            seq,
+        }
+    }
+
+    /// 创建快照时最后一条写入的 seq
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(i) => Ok(Some(read_resolved(&*self.file, self.cipher.as_ref(), &self.pending, self.operator.as_deref(), key, self.entries[i].1)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn iter(&self) -> SnapshotIter {
+        self.scan(..)
+    }
+
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> SnapshotIter {
+        let front = match range.start_bound() {
+            Bound::Included(k) => self.entries.partition_point(|(key, _)| key < k),
+            Bound::Excluded(k) => self.entries.partition_point(|(key, _)| key <= k),
+            Bound::Unbounded => 0,
+        };
+        let back = match range.end_bound() {
+            Bound::Included(k) => self.entries.partition_point(|(key, _)| key <= k),
+            Bound::Excluded(k) => self.entries.partition_point(|(key, _)| key < k),
+            Bound::Unbounded => self.entries.len(),
+        };
+        SnapshotIter {
+            file: self.file.clone(),
+            cipher: self.cipher.clone(),
+            entries: self.entries.clone(),
+            pending: self.pending.clone(),
+            operator: self.operator.clone(),
+            front,
+            back: back.max(front),
+        }
+    }
+}
+
+fn read_resolved(
+    file: &dyn StorageFile,
+    cipher: Option<&Cipher>,
+    pending: &PendingOperands,
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    pos: Position,
+) -> Result<Vec<u8>> {
+    resolve(pending.get(key), operator, key, pos, |pos| read_stored(file, cipher, pos))
+}
+
+/// 快照上的迭代器，不借用任何东西，可以放到别的线程里慢慢读
+pub struct SnapshotIter {
+    file: Arc<dyn StorageFile>,
+    cipher: Option<Cipher>,
+    entries: Arc<Vec<(Vec<u8>, Position)>>,
+    pending: Arc<PendingOperands>,
+    operator: Option<Arc<dyn MergeOperator>>,
+    front: usize,
+    back: usize,
+}
+
+impl SnapshotIter {
+    fn read(&self, idx: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (key, pos) = &self.entries[idx];
+        let value = read_resolved(&*self.file, self.cipher.as_ref(), &self.pending, self.operator.as_deref(), key, *pos)?;
+        Ok((key.clone(), value))
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        self.front += 1;
+        Some(self.read(self.front - 1))
+    }
+}
+
+impl DoubleEndedIterator for SnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(self.read(self.back))
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{bail, Result};
+use fs4::fs_std::FileExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+/// 日志文件的存储后端
+///
+/// 文件的读写都是按位置进行的，不依赖文件的读写指针，所以可以在快照和写入之间共享同一个句柄。
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// 打开文件，不存在时创建；文件系统后端会加独占锁，同一个文件同时只能被打开一次
+    fn open(&self, path: &Path) -> Result<Arc<dyn StorageFile>>;
+
+    /// 原子地用 from 替换 to，已经打开的旧文件还能继续读
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// 把目录里文件的创建和 rename 落盘，没有目录的后端什么都不做
+    fn sync_dir(&self, _dir: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub trait StorageFile: std::fmt::Debug + Send + Sync {
+    /// 和 pread 一样，可能读不满
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize>;
+
+    /// 和 pwrite 一样，可能写不完
+    fn write_at(&self, buf: &[u8], pos: u64) -> io::Result<usize>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    fn sync(&self) -> io::Result<()>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, pos) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    pos += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, pos) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    pos += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 按位置顺序读，给 BufReader 用
+pub(crate) struct StorageReader<'a> {
+    pub file: &'a dyn StorageFile,
+    pub pos: u64,
+}
+
+impl io::Read for StorageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for StorageReader<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            io::SeekFrom::Start(pos) => Some(pos),
+            io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            io::SeekFrom::End(delta) => self.file.len()?.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
+
+/// 真实的文件系统
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStorage;
+
+impl Storage for FsStorage {
+    fn open(&self, path: &Path) -> Result<Arc<dyn StorageFile>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if !file.try_lock_exclusive()? {
+            bail!("log file {} is locked by another process", path.display());
+        }
+        Ok(Arc::new(FsFile(file)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        Ok(std::fs::rename(from, to)?)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        // Windows 上不能这样打开目录，NTFS 的元数据有日志保护
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = dir;
+        Ok(())
+    }
+}
+
+/// 只读地打开文件系统里已有的文件，加共享锁，写入都会失败；转换格式时读源文件用
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadOnlyStorage;
+
+impl Storage for ReadOnlyStorage {
+    fn open(&self, path: &Path) -> Result<Arc<dyn StorageFile>> {
+        let file = File::open(path)?;
+        if !FileExt::try_lock_shared(&file)? {
+            bail!("log file {} is locked by another process", path.display());
+        }
+        Ok(Arc::new(FsFile(file)))
+    }
+
+    fn rename(&self, from: &Path, _to: &Path) -> Result<()> {
+        bail!("cannot rename {}: storage is read-only", from.display())
+    }
+}
+
+#[derive(Debug)]
+struct FsFile(File);
+
+impl StorageFile for FsFile {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.0, buf, pos) // Unix 使用 read_at
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.0, buf, pos) // Windows 使用 seek_read
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, buf: &[u8], pos: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(&self.0, buf, pos)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, buf: &[u8], pos: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(&self.0, buf, pos)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+/// 全部放在内存里，clone 之后共享同一份数据，可以模拟进程重启
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<PathBuf, Arc<MemoryFile>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 文件当前的内容，测试里用来检查或者篡改数据
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files.get(path).map(|f| f.data.read().unwrap().clone())
+    }
+
+    pub fn set_contents(&self, path: &Path, data: Vec<u8>) {
+        let mut files = self.files.lock().unwrap();
+        let file = files.entry(path.to_path_buf()).or_default();
+        *file.data.write().unwrap() = data;
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path) -> Result<Arc<dyn StorageFile>> {
+        let mut files = self.files.lock().unwrap();
+        Ok(files.entry(path.to_path_buf()).or_default().clone())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let Some(file) = files.remove(from) else {
+            bail!("{} not found", from.display());
+        };
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+}
+
+impl StorageFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (pos as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], pos: u64) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let end = pos as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[pos as usize..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 测试里按脚本注入的故障
+#[derive(Debug, Default)]
+pub struct Faults {
+    /// 接下来的 n 次写入直接失败，什么都不写
+    pub fail_writes: usize,
+    /// 下一次写入只写前面这么多字节，然后返回错误
+    pub short_write: Option<usize>,
+    /// 接下来的 n 次 sync 失败
+    pub fail_syncs: usize,
+    /// 接下来的 n 次 rename 失败
+    pub fail_renames: usize,
+    /// 再写这么多字节之后“崩溃”：这次写入被截断，之后所有操作都失败，直到调用 `recover`
+    pub crash_after: Option<u64>,
+    pub crashed: bool,
+    /// 一共成功写入了多少字节
+    pub written: u64,
+}
+
+/// 包装另一个后端，按 `Faults` 注入写失败、短写、sync 失败和崩溃
+#[derive(Debug, Clone)]
+pub struct FaultyStorage {
+    inner: Arc<dyn Storage>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> Self {
+        Self {
+            inner,
+            faults: Arc::default(),
+        }
+    }
+
+    pub fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap()
+    }
+
+    /// 模拟重启：清掉所有还没触发的故障
+    pub fn recover(&self) {
+        *self.faults() = Faults::default();
+    }
+}
+
+fn injected(what: &str) -> io::Error {
+    io::Error::other(format!("injected {} failure", what))
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, path: &Path) -> Result<Arc<dyn StorageFile>> {
+        if self.faults().crashed {
+            return Err(injected("open").into());
+        }
+        Ok(Arc::new(FaultyFile {
+            inner: self.inner.open(path)?,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        {
+            let mut faults = self.faults();
+            if faults.crashed {
+                return Err(injected("rename").into());
+            }
+            if faults.fail_renames > 0 {
+                faults.fail_renames -= 1;
+                return Err(injected("rename").into());
+            }
+        }
+        self.inner.rename(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        if self.faults().crashed {
+            return Err(injected("sync_dir").into());
+        }
+        self.inner.sync_dir(dir)
+    }
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    inner: Arc<dyn StorageFile>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyFile {
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap()
+    }
+
+    fn check_crashed(&self, what: &str) -> io::Result<()> {
+        match self.faults().crashed {
+            true => Err(injected(what)),
+            false => Ok(()),
+        }
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.check_crashed("read")?;
+        self.inner.read_at(buf, pos)
+    }
+
+    fn write_at(&self, buf: &[u8], pos: u64) -> io::Result<usize> {
+        let mut faults = self.faults();
+        if faults.crashed {
+            return Err(injected("write"));
+        }
+        if faults.fail_writes > 0 {
+            faults.fail_writes -= 1;
+            return Err(injected("write"));
+        }
+        // 只写一部分然后失败
+        let keep = match (faults.short_write.take(), faults.crash_after) {
+            (Some(keep), _) => Some(keep),
+            (None, Some(left)) if left < buf.len() as u64 => {
+                faults.crashed = true;
+                Some(left as usize)
+            }
+            _ => None,
+        };
+        if let Some(left) = faults.crash_after.as_mut() {
+            *left = left.saturating_sub(buf.len() as u64);
+        }
+        let keep = keep.map(|keep| keep.min(buf.len()));
+        self.inner.write_all_at(&buf[..keep.unwrap_or(buf.len())], pos)?;
+        faults.written += keep.unwrap_or(buf.len()) as u64;
+        match keep {
+            Some(_) => Err(injected("write")),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.check_crashed("stat")?;
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.check_crashed("truncate")?;
+        self.inner.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut faults = self.faults();
+        if faults.crashed {
+            return Err(injected("sync"));
+        }
+        if faults.fail_syncs > 0 {
+            faults.fail_syncs -= 1;
+            return Err(injected("sync"));
+        }
+        drop(faults);
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_and_faulty_storage() -> Result<()> {
+        let memory = MemoryStorage::new();
+        let storage = FaultyStorage::new(Arc::new(memory.clone()));
+        let path = Path::new("db/log");
+        let file = storage.open(path)?;
+        file.write_all_at(b"hello", 0)?;
+
+        storage.faults().short_write = Some(2);
+        assert!(file.write_all_at(b"world", 5).is_err());
+        assert_eq!(memory.contents(path), Some(b"hellowo".to_vec()));
+
+        storage.faults().fail_syncs = 1;
+        assert!(file.sync().is_err());
+        file.sync()?;
+
+        storage.faults().crash_after = Some(3);
+        file.write_all_at(b"ab", 7)?;
+        assert!(file.write_all_at(b"cd", 9).is_err());
+        assert!(file.len().is_err());
+        assert!(storage.open(path).is_err());
+        assert_eq!(memory.contents(path), Some(b"hellowoabc".to_vec()));
+
+        storage.recover();
+        let mut buf = [0; 3];
+        storage.open(path)?.read_exact_at(&mut buf, 7)?;
+        assert_eq!(&buf, b"abc");
+
+        // rename 之后还打开着的旧文件依然能读
+        let old = memory.open(path)?;
+        memory.set_contents(Path::new("db/new"), b"new".to_vec());
+        memory.rename(Path::new("db/new"), path)?;
+        assert_eq!(old.len()?, 10);
+        assert_eq!(memory.contents(path), Some(b"new".to_vec()));
+        Ok(())
+    }
+}
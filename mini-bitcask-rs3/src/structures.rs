@@ -0,0 +1,418 @@
+use crate::bitcask::MiniBitcask;
+use crate::index::prefix_range;
+use crate::keycode::{self, Value};
+use crate::namespace::{self, NAMESPACE_PREFIX};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::ops::Bound;
+
+/// 数据结构用的 namespace id，和普通 namespace 一样对默认 namespace 不可见
+pub(crate) const STRUCTURES_ID: u32 = u32::MAX;
+
+// 元数据：'m' + key -> Meta
+// 成员：'d' + keycode(key, version, ...) -> 成员的值
+const META: u8 = b'm';
+const DATA: u8 = b'd';
+
+// 有序集合的两组成员：member -> score，以及按 score 排序的 (score, member)
+const ZSET_MEMBER: u64 = 0;
+const ZSET_SCORE: u64 = 1;
+
+/// list 第一次创建时 head 和 tail 的位置，两边都能放下足够多的元素
+const LIST_START: u64 = u64::MAX / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Hash = 1,
+    List = 2,
+    Set = 3,
+    SortedSet = 4,
+}
+
+impl DataType {
+    fn from_u8(b: u8) -> Result<Self> {
+        Ok(match b {
+            1 => DataType::Hash,
+            2 => DataType::List,
+            3 => DataType::Set,
+            4 => DataType::SortedSet,
+            other => bail!("unknown data type {}", other),
+        })
+    }
+}
+
+// +---------+------------+--------+---------+---------+
+// | type(1) | version(8) | len(8) | head(8) | tail(8) |
+// +---------+------------+--------+---------+---------+
+/// 每个数据结构一条元数据，version 在创建时分配，删除只需要删掉元数据，
+/// 旧 version 的成员读的时候看不到，merge 的时候丢掉
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Meta {
+    data_type: DataType,
+    version: u64,
+    len: u64,
+    /// list 的元素在 [head, tail) 里
+    head: u64,
+    tail: u64,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.data_type as u8];
+        for n in [self.version, self.len, self.head, self.tail] {
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != 33 {
+            bail!("corrupted data structure metadata");
+        }
+        let n = |i: usize| u64::from_be_bytes(buf[1 + i * 8..9 + i * 8].try_into().unwrap());
+        Ok(Self {
+            data_type: DataType::from_u8(buf[0])?,
+            version: n(0),
+            len: n(1),
+            head: n(2),
+            tail: n(3),
+        })
+    }
+}
+
+fn meta_key(key: &[u8]) -> Vec<u8> {
+    let mut k = vec![META];
+    k.extend_from_slice(key);
+    namespace::encode_key(STRUCTURES_ID, &k)
+}
+
+fn member_key(key: &[u8], version: u64, parts: &[Value]) -> Vec<u8> {
+    let mut k = vec![DATA];
+    k.extend(keycode::encode(&[Value::from(key), Value::from(version)]));
+    k.extend(keycode::encode(parts));
+    namespace::encode_key(STRUCTURES_ID, &k)
+}
+
+/// 所有数据结构当前的 version，merge 用来判断成员是不是已经删掉了
+pub(crate) fn live_versions(db: &mut MiniBitcask) -> Result<HashMap<Vec<u8>, u64>> {
+    let prefix = meta_key(b"");
+    let mut versions = HashMap::new();
+    for item in db.scan_raw(prefix_range(&prefix), false) {
+        let (key, value) = item?;
+        versions.insert(key[prefix.len()..].to_vec(), Meta::decode(&value)?.version);
+    }
+    Ok(versions)
+}
+
+/// key 是不是已经删掉的数据结构的成员
+pub(crate) fn is_stale(live: &HashMap<Vec<u8>, u64>, key: &[u8]) -> bool {
+    let Some(rest) = key.strip_prefix(NAMESPACE_PREFIX) else {
+        return false;
+    };
+    let Some(member) = rest.strip_prefix(&STRUCTURES_ID.to_be_bytes()[..]).and_then(|k| k.strip_prefix(&[DATA])) else {
+        return false;
+    };
+    match keycode::decode(member).as_deref() {
+        Ok([Value::Bytes(key), Value::Uint(version), ..]) => live.get(key) != Some(version),
+        _ => false,
+    }
+}
+
+/// 类似 Redis 的 hash、list、set 和有序集合，和普通的 key 互不影响
+impl MiniBitcask {
+    fn meta(&mut self, key: &[u8], data_type: DataType) -> Result<Option<Meta>> {
+        let Some(value) = self.get_raw(&meta_key(key))? else {
+            return Ok(None);
+        };
+        let meta = Meta::decode(&value)?;
+        if meta.data_type != data_type {
+            bail!("key {:?} holds a {:?}, not a {:?}", key, meta.data_type, data_type);
+        }
+        Ok(Some(meta))
+    }
+
+    /// 不存在时创建一个新的，version 用下一个 seq，不会和删掉的旧数据重复
+    fn meta_or_create(&mut self, key: &[u8], data_type: DataType) -> Result<Meta> {
+        Ok(self.meta(key, data_type)?.unwrap_or(Meta {
+            data_type,
+            version: self.last_seq() + 1,
+            len: 0,
+            head: LIST_START,
+            tail: LIST_START,
+        }))
+    }
+
+    fn scan_members(&mut self, key: &[u8], version: u64, parts: &[Value]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = member_key(key, version, parts);
+        self.scan_raw(prefix_range(&prefix), false)
+            .map(|item| item.map(|(k, v)| (k[prefix.len()..].to_vec(), v)))
+            .collect()
+    }
+
+    /// 删除整个数据结构，只写一条删除记录，返回之前是否存在
+    pub fn delete_structure(&mut self, key: &[u8]) -> Result<bool> {
+        let meta_key = meta_key(key);
+        if self.get_raw(&meta_key)?.is_none() {
+            return Ok(false);
+        }
+        self.delete_raw(&meta_key)?;
+        Ok(true)
+    }
+
+    /// 返回 field 是不是新加的
+    pub fn hset(&mut self, key: &[u8], field: &[u8], value: Vec<u8>) -> Result<bool> {
+        let mut meta = self.meta_or_create(key, DataType::Hash)?;
+        let member = member_key(key, meta.version, &[Value::from(field)]);
+        let is_new = self.get_raw(&member)?.is_none();
+        let mut batch = vec![(member, Some(value))];
+        if is_new {
+            meta.len += 1;
+            batch.push((meta_key(key), Some(meta.encode())));
+        }
+        self.write_batch(batch)?;
+        Ok(is_new)
+    }
+
+    pub fn hget(&mut self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(meta) = self.meta(key, DataType::Hash)? else {
+            return Ok(None);
+        };
+        self.get_raw(&member_key(key, meta.version, &[Value::from(field)]))
+    }
+
+    /// 所有的 (field, value)，按 field 排序
+    pub fn hgetall(&mut self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let Some(meta) = self.meta(key, DataType::Hash)? else {
+            return Ok(Vec::new());
+        };
+        self.scan_members(key, meta.version, &[])?
+            .into_iter()
+            .map(|(field, value)| match keycode::decode(&field)?.as_slice() {
+                [Value::Bytes(field)] => Ok((field.clone(), value)),
+                _ => bail!("corrupted hash field in key {:?}", key),
+            })
+            .collect()
+    }
+
+    /// 放到 list 的最左边，返回新的长度
+    pub fn lpush(&mut self, key: &[u8], value: Vec<u8>) -> Result<u64> {
+        let mut meta = self.meta_or_create(key, DataType::List)?;
+        meta.head -= 1;
+        meta.len += 1;
+        self.write_batch(vec![
+            (member_key(key, meta.version, &[Value::from(meta.head)]), Some(value)),
+            (meta_key(key), Some(meta.encode())),
+        ])?;
+        Ok(meta.len)
+    }
+
+    /// 取出 list 最右边的元素，list 空了之后整个删掉
+    pub fn rpop(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(mut meta) = self.meta(key, DataType::List)? else {
+            return Ok(None);
+        };
+        meta.tail -= 1;
+        meta.len -= 1;
+        let member = member_key(key, meta.version, &[Value::from(meta.tail)]);
+        let value = self.get_raw(&member)?;
+        let meta_value = (meta.len > 0).then(|| meta.encode());
+        self.write_batch(vec![(member, None), (meta_key(key), meta_value)])?;
+        Ok(value)
+    }
+
+    /// 下标从 0 开始，负数从末尾数，stop 也包含在内，和 Redis 的 LRANGE 一样
+    pub fn lrange(&mut self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>> {
+        let Some(meta) = self.meta(key, DataType::List)? else {
+            return Ok(Vec::new());
+        };
+        let len = meta.len as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        let from = member_key(key, meta.version, &[Value::from(meta.head + start as u64)]);
+        let to = member_key(key, meta.version, &[Value::from(meta.head + stop as u64)]);
+        self.scan_raw((Bound::Included(from), Bound::Included(to)), false)
+            .map(|item| item.map(|(_, v)| v))
+            .collect()
+    }
+
+    /// 返回 member 是不是新加的
+    pub fn sadd(&mut self, key: &[u8], member: &[u8]) -> Result<bool> {
+        let mut meta = self.meta_or_create(key, DataType::Set)?;
+        let member = member_key(key, meta.version, &[Value::from(member)]);
+        if self.get_raw(&member)?.is_some() {
+            return Ok(false);
+        }
+        meta.len += 1;
+        self.write_batch(vec![(member, Some(Vec::new())), (meta_key(key), Some(meta.encode()))])?;
+        Ok(true)
+    }
+
+    pub fn sismember(&mut self, key: &[u8], member: &[u8]) -> Result<bool> {
+        let Some(meta) = self.meta(key, DataType::Set)? else {
+            return Ok(false);
+        };
+        Ok(self.get_raw(&member_key(key, meta.version, &[Value::from(member)]))?.is_some())
+    }
+
+    /// 所有成员，按字节序排序
+    pub fn smembers(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let Some(meta) = self.meta(key, DataType::Set)? else {
+            return Ok(Vec::new());
+        };
+        self.scan_members(key, meta.version, &[])?
+            .into_iter()
+            .map(|(member, _)| match keycode::decode(&member)?.as_slice() {
+                [Value::Bytes(member)] => Ok(member.clone()),
+                _ => bail!("corrupted set member in key {:?}", key),
+            })
+            .collect()
+    }
+
+    /// 加入或者更新 member 的 score，返回 member 是不是新加的
+    pub fn zadd(&mut self, key: &[u8], score: f64, member: &[u8]) -> Result<bool> {
+        let mut meta = self.meta_or_create(key, DataType::SortedSet)?;
+        let score_key = |score: f64| member_key(key, meta.version, &[Value::from(ZSET_SCORE), Value::from(score), Value::from(member)]);
+        let member_key = member_key(key, meta.version, &[Value::from(ZSET_MEMBER), Value::from(member)]);
+        let old = self.get_raw(&member_key)?;
+        let mut batch = Vec::new();
+        if let Some(old) = &old {
+            let old = f64::from_be_bytes(old.as_slice().try_into()?);
+            batch.push((score_key(old), None));
+        }
+        batch.push((member_key, Some(score.to_be_bytes().to_vec())));
+        batch.push((score_key(score), Some(Vec::new())));
+        if old.is_none() {
+            meta.len += 1;
+            batch.push((meta_key(key), Some(meta.encode())));
+        }
+        self.write_batch(batch)?;
+        Ok(old.is_none())
+    }
+
+    /// score 在 [min, max] 之间的 (member, score)，按 score 从小到大
+    pub fn zrangebyscore(&mut self, key: &[u8], min: f64, max: f64) -> Result<Vec<(Vec<u8>, f64)>> {
+        let Some(meta) = self.meta(key, DataType::SortedSet)? else {
+            return Ok(Vec::new());
+        };
+        let prefix = member_key(key, meta.version, &[Value::from(ZSET_SCORE)]);
+        let from = member_key(key, meta.version, &[Value::from(ZSET_SCORE), Value::from(min)]);
+        let (_, to) = prefix_range(&member_key(key, meta.version, &[Value::from(ZSET_SCORE), Value::from(max)]));
+        self.scan_raw((Bound::Included(from), to), false)
+            .map(|item| {
+                let (k, _) = item?;
+                match keycode::decode(&k[prefix.len()..])?.as_slice() {
+                    [Value::Float(score), Value::Bytes(member)] => Ok((member.clone(), *score)),
+                    _ => bail!("corrupted sorted set member in key {:?}", key),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::Options;
+    use crate::storage::MemoryStorage;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_structures() -> Result<()> {
+        let options = Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() };
+        let mut db = MiniBitcask::open(PathBuf::from("db/log"), options)?;
+
+        assert!(db.hset(b"h", b"f1", b"1".to_vec())?);
+        assert!(db.hset(b"h", b"f2", b"2".to_vec())?);
+        assert!(!db.hset(b"h", b"f1", b"3".to_vec())?);
+        assert_eq!(db.hget(b"h", b"f1")?, Some(b"3".to_vec()));
+        assert_eq!(db.hgetall(b"h")?, vec![(b"f1".to_vec(), b"3".to_vec()), (b"f2".to_vec(), b"2".to_vec())]);
+
+        for v in ["a", "b", "c"] {
+            db.lpush(b"l", v.as_bytes().to_vec())?;
+        }
+        assert_eq!(db.lrange(b"l", 0, -1)?, vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(db.lrange(b"l", -2, 10)?, vec![b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(db.rpop(b"l")?, Some(b"a".to_vec()));
+        assert_eq!(db.lrange(b"l", 1, 0)?, Vec::<Vec<u8>>::new());
+
+        assert!(db.sadd(b"s", b"x")?);
+        assert!(!db.sadd(b"s", b"x")?);
+        assert!(db.sadd(b"s", b"\x00")?);
+        assert!(db.sismember(b"s", b"x")?);
+        assert!(!db.sismember(b"s", b"y")?);
+        assert_eq!(db.smembers(b"s")?, vec![b"\x00".to_vec(), b"x".to_vec()]);
+
+        db.zadd(b"z", 2.0, b"two")?;
+        db.zadd(b"z", -1.0, b"neg")?;
+        db.zadd(b"z", 10.0, b"ten")?;
+        assert!(!db.zadd(b"z", 3.0, b"two")?);
+        assert_eq!(db.zrangebyscore(b"z", -5.0, 3.0)?, vec![(b"neg".to_vec(), -1.0), (b"two".to_vec(), 3.0)]);
+        assert_eq!(db.zrangebyscore(b"z", 3.0, 3.0)?, vec![(b"two".to_vec(), 3.0)]);
+
+        // 类型不对、和普通 key 互不影响
+        assert!(db.lpush(b"h", b"x".to_vec()).is_err());
+        assert_eq!(db.get(b"h")?, None);
+        assert_eq!(db.stats().keys, 0);
+
+        // 删除只写一条记录，重建之后看不到旧的成员，merge 之后旧成员也没了
+        let keys = db.index_stats().keys;
+        let seq = db.last_seq();
+        assert!(db.delete_structure(b"h")?);
+        assert_eq!(db.last_seq(), seq + 1);
+        assert!(!db.delete_structure(b"h")?);
+        assert_eq!(db.hget(b"h", b"f1")?, None);
+        db.hset(b"h", b"f3", b"4".to_vec())?;
+        assert_eq!(db.hgetall(b"h")?, vec![(b"f3".to_vec(), b"4".to_vec())]);
+        db.merge()?;
+        assert_eq!(db.index_stats().keys, keys - 2 + 1);
+        assert_eq!(db.hgetall(b"h")?, vec![(b"f3".to_vec(), b"4".to_vec())]);
+        assert_eq!(db.lrange(b"l", 0, -1)?, vec![b"c".to_vec(), b"b".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_structures_reopen() -> Result<()> {
+        let path = PathBuf::from("db/log");
+        let storage = MemoryStorage::new();
+        let options = Options { storage: Arc::new(storage.clone()), ..Default::default() };
+        let mut db = MiniBitcask::open(path.clone(), options.clone())?;
+        db.hset(b"h", b"f1", b"1".to_vec())?;
+        db.lpush(b"l", b"a".to_vec())?;
+        db.lpush(b"l", b"b".to_vec())?;
+        db.rpop(b"l")?;
+        db.sadd(b"s", b"x")?;
+        db.zadd(b"z", 1.0, b"one")?;
+        db.zadd(b"z", 2.0, b"one")?;
+        db.sync()?;
+        let synced = storage.contents(&path).unwrap().len();
+
+        // 成员写完了，元数据只写了一半，重新打开时整组丢掉
+        db.hset(b"h", b"f2", b"2".to_vec())?;
+        db.sync()?;
+        let mut torn = storage.contents(&path).unwrap();
+        torn.truncate(torn.len() - 1);
+        drop(db);
+        storage.set_contents(&path, torn);
+
+        let mut db = MiniBitcask::open(path.clone(), options.clone())?;
+        assert_eq!(storage.contents(&path).unwrap().len(), synced);
+        assert_eq!(db.hgetall(b"h")?, vec![(b"f1".to_vec(), b"1".to_vec())]);
+        assert!(db.hset(b"h", b"f2", b"2".to_vec())?);
+        assert_eq!(db.lrange(b"l", 0, -1)?, vec![b"b".to_vec()]);
+        assert!(!db.sadd(b"s", b"x")?);
+        assert_eq!(db.zrangebyscore(b"z", 0.0, 5.0)?, vec![(b"one".to_vec(), 2.0)]);
+        db.close()?;
+
+        // 正常关闭之后元数据和成员一致，list 弹空之后整个删掉
+        let mut db = MiniBitcask::open(path, options)?;
+        assert_eq!(db.hgetall(b"h")?.len(), 2);
+        assert_eq!(db.rpop(b"l")?, Some(b"b".to_vec()));
+        assert_eq!(db.rpop(b"l")?, None);
+        assert!(!db.delete_structure(b"l")?);
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use crate::bitcask::{MiniBitcask, ScanIter};
+use crate::keycode::{self, Value};
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// 把 T 编码成字节
+pub trait Codec<T> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// 编码之后按字节比较的顺序和 T 的顺序一致，只有这样的 codec 才能用来编码 key
+pub trait OrderedCodec<T>: Codec<T> {}
+
+/// 原样保存字节
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(&self, value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl OrderedCodec<Vec<u8>> for Raw {}
+
+/// UTF-8 字符串，字节序就是 code point 的顺序
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8;
+
+impl Codec<String> for Utf8 {
+    fn encode(&self, value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<String> {
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+impl OrderedCodec<String> for Utf8 {}
+
+/// 整数按大端存，有符号数翻转符号位，这样负数排在前面
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BigEndian;
+
+impl Codec<u64> for BigEndian {
+    fn encode(&self, value: &u64) -> Result<Vec<u8>> {
+        Ok(value.to_be_bytes().to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<u64> {
+        match <[u8; 8]>::try_from(bytes) {
+            Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+            Err(_) => bail!("expected 8 bytes for an integer, got {}", bytes.len()),
+        }
+    }
+}
+
+impl OrderedCodec<u64> for BigEndian {}
+
+impl Codec<i64> for BigEndian {
+    fn encode(&self, value: &i64) -> Result<Vec<u8>> {
+        self.encode(&((*value as u64) ^ (1 << 63)))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<i64> {
+        let n: u64 = self.decode(bytes)?;
+        Ok((n ^ (1 << 63)) as i64)
+    }
+}
+
+impl OrderedCodec<i64> for BigEndian {}
+
+/// 组合 key，用 `keycode` 编码
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tuple;
+
+impl Codec<Vec<Value>> for Tuple {
+    fn encode(&self, value: &Vec<Value>) -> Result<Vec<u8>> {
+        Ok(keycode::encode(value))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<Value>> {
+        keycode::decode(bytes)
+    }
+}
+
+impl OrderedCodec<Vec<Value>> for Tuple {}
+
+/// bincode 编码，紧凑但是不保持顺序，只能用来编码 value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// JSON 编码，方便别的语言直接读，不保持顺序，只能用来编码 value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// 在 MiniBitcask 上按类型读写，key 和 value 分别用 KC 和 VC 编码
+pub struct TypedStore<'a, K, V, KC, VC> {
+    db: &'a mut MiniBitcask,
+    key_codec: KC,
+    value_codec: VC,
+    _marker: PhantomData<fn(K, V)>,
+}
+
+impl<'a, K, V, KC: OrderedCodec<K>, VC: Codec<V>> TypedStore<'a, K, V, KC, VC> {
+    pub fn new(db: &'a mut MiniBitcask, key_codec: KC, value_codec: VC) -> Self {
+        Self {
+            db,
+            key_codec,
+            value_codec,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.key_codec.encode(key)?;
+        self.db.get(&key)?.map(|value| self.value_codec.decode(&value)).transpose()
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = self.key_codec.encode(key)?;
+        self.db.set(&key, self.value_codec.encode(value)?)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.db.delete(&self.key_codec.encode(key)?)
+    }
+
+    pub fn iter(&mut self) -> TypedIter<'_, K, V, KC, VC> {
+        TypedIter {
+            inner: self.db.scan(..),
+            key_codec: &self.key_codec,
+            value_codec: &self.value_codec,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 按 K 的顺序扫描，range 的端点编码失败时返回错误
+    pub fn scan(&mut self, range: impl RangeBounds<K>) -> Result<TypedIter<'_, K, V, KC, VC>> {
+        let encode = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(k) => Bound::Included(self.key_codec.encode(k)?),
+                Bound::Excluded(k) => Bound::Excluded(self.key_codec.encode(k)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let range = (encode(range.start_bound())?, encode(range.end_bound())?);
+        Ok(TypedIter {
+            inner: self.db.scan(range),
+            key_codec: &self.key_codec,
+            value_codec: &self.value_codec,
+            _marker: PhantomData,
+        })
+    }
+}
+
+pub struct TypedIter<'a, K, V, KC, VC> {
+    inner: ScanIter<'a>,
+    key_codec: &'a KC,
+    value_codec: &'a VC,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> TypedIter<'_, K, V, KC, VC> {
+    fn decode(&self, item: Result<(Vec<u8>, Vec<u8>)>) -> Result<(K, V)> {
+        let (key, value) = item?;
+        Ok((self.key_codec.decode(&key)?, self.value_codec.decode(&value)?))
+    }
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> Iterator for TypedIter<'_, K, V, KC, VC> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|item| self.decode(item))
+    }
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> DoubleEndedIterator for TypedIter<'_, K, V, KC, VC> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|item| self.decode(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::Options;
+    use crate::storage::MemoryStorage;
+    use serde::Deserialize;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_typed_store() -> Result<()> {
+        let options = Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() };
+        let mut db = MiniBitcask::open(PathBuf::from("db/log"), options)?;
+        let user = |name: &str| User { name: name.to_string(), tags: vec!["t".to_string()] };
+
+        let mut users = TypedStore::new(&mut db, BigEndian, Bincode);
+        for id in [300i64, -2, 7, 256] {
+            users.set(&id, &user(&format!("u{}", id)))?;
+        }
+        assert_eq!(users.get(&7)?, Some(user("u7")));
+        assert_eq!(users.get(&8)?, None);
+        users.delete(&7)?;
+        // 负数和多字节的整数都按数值排序
+        let ids = users.scan(-10..=300)?.map(|item| Ok(item?.0)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, vec![-2, 256, 300]);
+        assert_eq!(users.iter().next_back().transpose()?, Some((300, user("u300"))));
+
+        let mut db = MiniBitcask::open(PathBuf::from("db/log2"), Options { storage: Arc::new(MemoryStorage::new()), ..Default::default() })?;
+        let mut names = TypedStore::new(&mut db, Utf8, Json);
+        names.set(&"b".to_string(), &user("b"))?;
+        names.set(&"a".to_string(), &user("a"))?;
+        let all = names.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(all, vec![("a".to_string(), user("a")), ("b".to_string(), user("b"))]);
+        assert_eq!(db.get(b"a")?, Some(br#"{"name":"a","tags":["t"]}"#.to_vec()));
+
+        // 解不开的值返回错误
+        db.set(b"c", b"not json".to_vec())?;
+        let mut names: TypedStore<'_, String, User, _, _> = TypedStore::new(&mut db, Utf8, Json);
+        assert!(names.get(&"c".to_string()).is_err());
+        let mut raw = TypedStore::new(&mut db, Raw, Raw);
+        assert_eq!(raw.get(&b"c".to_vec())?, Some(b"not json".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    /// merge_value 写入的 operand，不是合并之后的值
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+    /// 订阅者消费太慢，缓冲区满了，中间丢掉了 dropped 个事件
+    Overflow { dropped: u64 },
+}
+
+struct Subscriber {
+    prefix: Vec<u8>,
+    tx: SyncSender<Event>,
+    dropped: u64,
+}
+
+/// 写入成功之后通知订阅者
+///
+/// 每个订阅者一个有界的 channel，写入方只用 try_send，不会被慢的订阅者卡住。
+/// 缓冲区满了就丢弃事件并计数，等有空位了先补发一个 Overflow。
+pub struct Watchers {
+    buffer: usize,
+    subscribers: Vec<Subscriber>,
+    // 只关心“有新写入”的订阅者，不带 key 和 value
+    wakers: Vec<SyncSender<()>>,
+}
+
+impl Watchers {
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            buffer,
+            subscribers: Vec::new(),
+            wakers: Vec::new(),
+        }
+    }
+
+    /// 每次写入之后收到一个 ()，没来得及取的会合并成一个
+    pub fn subscribe_wakeup(&mut self) -> Receiver<()> {
+        let (tx, rx) = sync_channel(1);
+        self.wakers.push(tx);
+        rx
+    }
+
+    pub(crate) fn wake(&mut self) {
+        self.wakers
+            .retain(|tx| !matches!(tx.try_send(()), Err(TrySendError::Disconnected(_))));
+    }
+
+    pub fn subscribe(&mut self, prefix: &[u8]) -> Receiver<Event> {
+        let (tx, rx) = sync_channel(self.buffer);
+        self.subscribers.push(Subscriber {
+            prefix: prefix.to_vec(),
+            tx,
+            dropped: 0,
+        });
+        rx
+    }
+
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        self.subscribers.iter().any(|s| key.starts_with(&s.prefix))
+    }
+
+    pub fn notify_put(&mut self, key: &[u8], value: &[u8]) {
+        self.wake();
+        if self.is_watched(key) {
+            self.notify(Event::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            });
+        }
+    }
+
+    pub fn notify_delete(&mut self, key: &[u8]) {
+        self.wake();
+        if self.is_watched(key) {
+            self.notify(Event::Delete { key: key.to_vec() });
+        }
+    }
+
+    pub fn notify_merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.wake();
+        if self.is_watched(key) {
+            self.notify(Event::Merge {
+                key: key.to_vec(),
+                operand: operand.to_vec(),
+            });
+        }
+    }
+
+    fn notify(&mut self, event: Event) {
+        let key = match &event {
+            Event::Put { key, .. } | Event::Delete { key } | Event::Merge { key, .. } => key,
+            Event::Overflow { .. } => return,
+        };
+        // 接收端已经 drop 的订阅者直接移除
+        self.subscribers.retain_mut(|s| {
+            if !key.starts_with(&s.prefix) {
+                return true;
+            }
+            if s.dropped > 0 {
+                match s.tx.try_send(Event::Overflow { dropped: s.dropped }) {
+                    Ok(()) => s.dropped = 0,
+                    Err(TrySendError::Full(_)) => {
+                        s.dropped += 1;
+                        return true;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            match s.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    s.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_overflow() {
+        let mut watchers = Watchers::new(2);
+        let rx = watchers.subscribe(b"cfg/");
+        for i in 0..5u8 {
+            watchers.notify_put(b"cfg/a", &[i]);
+        }
+        // 前两个进了缓冲区，后面三个被丢掉
+        assert_eq!(rx.try_recv(), Ok(Event::Put { key: b"cfg/a".to_vec(), value: vec![0] }));
+        assert_eq!(rx.try_recv(), Ok(Event::Put { key: b"cfg/a".to_vec(), value: vec![1] }));
+        assert!(rx.try_recv().is_err());
+
+        watchers.notify_delete(b"cfg/a");
+        assert_eq!(rx.try_recv(), Ok(Event::Overflow { dropped: 3 }));
+        assert_eq!(rx.try_recv(), Ok(Event::Delete { key: b"cfg/a".to_vec() }));
+
+        drop(rx);
+        watchers.notify_delete(b"cfg/a");
+        assert!(watchers.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_wakeup_coalesce() {
+        let mut watchers = Watchers::new(2);
+        let rx = watchers.subscribe_wakeup();
+        watchers.notify_put(b"a", b"1");
+        watchers.notify_delete(b"b");
+        // 两次写入只留下一个通知
+        assert_eq!(rx.try_recv(), Ok(()));
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        watchers.notify_delete(b"a");
+        assert!(watchers.wakers.is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+/* 通过 C 接口读写、遍历、merge，由 tests/ffi.rs 编译运行 */
+#include "bitcask.h"
+
+#include <stdio.h>
+#include <string.h>
+
+#define CHECK(cond)                                                                  \
+    do {                                                                             \
+        if (!(cond)) {                                                               \
+            const char *err = bitcask_last_error();                                  \
+            fprintf(stderr, "%s:%d: check failed: %s (%s)\n", __FILE__, __LINE__, #cond, \
+                    err ? err : "no error");                                         \
+            return 1;                                                                \
+        }                                                                            \
+    } while (0)
+
+#define STR(s) (const uint8_t *)(s), strlen(s)
+
+static int put(Bitcask *db, const char *key, const char *value) {
+    return bitcask_put(db, STR(key), STR(value)) == BITCASK_STATUS_OK;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s <path>\n", argv[0]);
+        return 2;
+    }
+
+    Bitcask *db = NULL;
+    CHECK(bitcask_open(argv[1], &db) == BITCASK_STATUS_OK);
+    CHECK(put(db, "user:1", "alice"));
+    CHECK(put(db, "user:2", "bob"));
+    CHECK(put(db, "user:3", "carol"));
+    CHECK(put(db, "config", "x"));
+    CHECK(put(db, "user:1", "alice2"));
+    CHECK(bitcask_delete(db, STR("user:2")) == BITCASK_STATUS_OK);
+
+    uint8_t *value = NULL;
+    size_t len = 0;
+    CHECK(bitcask_get(db, STR("user:1"), &value, &len) == BITCASK_STATUS_OK);
+    CHECK(len == 6 && memcmp(value, "alice2", len) == 0);
+    bitcask_free_buffer(value, len);
+    CHECK(bitcask_get(db, STR("user:2"), &value, &len) == BITCASK_STATUS_NOT_FOUND);
+
+    /* 参数错误带错误信息 */
+    CHECK(bitcask_put(NULL, STR("k"), STR("v")) == BITCASK_STATUS_INVALID_ARGUMENT);
+    CHECK(bitcask_last_error() != NULL);
+
+    CHECK(bitcask_merge(db) == BITCASK_STATUS_OK);
+
+    /* 迭代器是快照，之后的写入看不到 */
+    BitcaskIter *iter = NULL;
+    CHECK(bitcask_iter(db, STR("user:"), &iter) == BITCASK_STATUS_OK);
+    CHECK(put(db, "user:4", "dave"));
+    const char *expected[][2] = {{"user:1", "alice2"}, {"user:3", "carol"}};
+    const uint8_t *k, *v;
+    size_t klen, vlen;
+    for (int i = 0; i < 2; i++) {
+        CHECK(bitcask_iter_next(iter, &k, &klen, &v, &vlen) == BITCASK_STATUS_OK);
+        CHECK(klen == strlen(expected[i][0]) && memcmp(k, expected[i][0], klen) == 0);
+        CHECK(vlen == strlen(expected[i][1]) && memcmp(v, expected[i][1], vlen) == 0);
+    }
+    CHECK(bitcask_iter_next(iter, &k, &klen, &v, &vlen) == BITCASK_STATUS_NOT_FOUND);
+    bitcask_iter_free(iter);
+    CHECK(bitcask_close(db) == BITCASK_STATUS_OK);
+
+    /* 重新打开之后数据还在 */
+    CHECK(bitcask_open(argv[1], &db) == BITCASK_STATUS_OK);
+    CHECK(bitcask_iter(db, NULL, 0, &iter) == BITCASK_STATUS_OK);
+    int count = 0;
+    while (bitcask_iter_next(iter, &k, &klen, &v, &vlen) == BITCASK_STATUS_OK) {
+        count++;
+    }
+    CHECK(count == 4);
+    bitcask_iter_free(iter);
+    CHECK(bitcask_close(db) == BITCASK_STATUS_OK);
+    return 0;
+}
@@ -0,0 +1,120 @@
+// 用 SIGKILL 杀掉 crash_writer，重新打开之后检查数据
+#![cfg(unix)]
+
+use anyhow::Result;
+use mini_bitcask_rs3::bitcask::MiniBitcask;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+type State = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn apply(op: &str, state: &mut State) {
+    let mut parts = op.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("set"), Some(key), Some(value)) => {
+            state.insert(key.as_bytes().to_vec(), value.as_bytes().to_vec());
+        }
+        (Some("del"), Some(key), None) => {
+            state.remove(key.as_bytes());
+        }
+        (Some("merge"), None, None) => {}
+        _ => panic!("unknown op {:?}", op),
+    }
+}
+
+/// 等到 crash_writer 开始 merge
+fn wait_for_merge(ack_path: &Path) -> Result<()> {
+    let marker = b"begin merge\n";
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Ok(file) = std::fs::File::open(ack_path) {
+            let len = file.metadata()?.len();
+            if len >= marker.len() as u64 {
+                let mut tail = vec![0; marker.len()];
+                file.read_exact_at(&mut tail, len - marker.len() as u64)?;
+                if tail == marker {
+                    return Ok(());
+                }
+            }
+        }
+        std::thread::yield_now();
+    }
+    anyhow::bail!("crash_writer never started a merge")
+}
+
+fn next(state: &mut u64) -> u64 {
+    // xorshift64
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[test]
+fn test_crash_recovery() -> Result<()> {
+    let dir = tempfile::TempDir::new()?;
+    let path = dir.path().join("log");
+    let mut state = State::new();
+    let mut rng = 0x2545_f491_4f6c_dd1d;
+    let mut merge_kills = 0;
+
+    for round in 0..12u64 {
+        let ack_path = dir.path().join(format!("ack{}", round));
+        let mut child = Command::new(env!("CARGO_BIN_EXE_crash_writer"))
+            .arg(&path)
+            .arg(&ack_path)
+            .arg((round + 1).to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // 一半在随机的时间点杀掉，一半在 merge 的过程中杀掉
+        if round % 2 == 0 {
+            sleep(Duration::from_millis(next(&mut rng) % 200 + 10));
+        } else {
+            wait_for_merge(&ack_path)?;
+        }
+        child.kill()?;
+        let status = child.wait()?;
+        if status.signal() != Some(9) {
+            let mut stderr = String::new();
+            child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+            panic!("crash_writer exited with {}: {}", status, stderr);
+        }
+
+        // 按 ack 文件重放：有 ok 的是已经 sync 的写入，最后一个没有 ok 的可能生效也可能没有
+        let acks = std::fs::read_to_string(&ack_path)?;
+        let mut in_flight = None;
+        for line in acks.lines() {
+            match line.strip_prefix("begin ") {
+                Some(op) => in_flight = Some(op),
+                None if line == "ok" => apply(in_flight.take().unwrap(), &mut state),
+                None => panic!("unexpected ack line {:?}", line),
+            }
+        }
+        if in_flight == Some("merge") {
+            merge_kills += 1;
+        }
+        let mut applied = state.clone();
+        if let Some(op) = in_flight {
+            apply(op, &mut applied);
+        }
+
+        let mut db = MiniBitcask::new(path.clone())?;
+        let actual = db.scan(..).collect::<Result<State>>()?;
+        assert!(
+            actual == state || actual == applied,
+            "round {}: recovered data doesn't match the acknowledged writes (in flight: {:?})",
+            round,
+            in_flight.map(|op| op.chars().take(16).collect::<String>())
+        );
+        state = actual;
+    }
+    println!("killed {} times during merge", merge_kills);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+// 用系统的 C 编译器编译 tests/c/ffi_test.c，链接 cdylib 之后运行
+#![cfg(unix)]
+
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// cargo test 时 cdylib 和测试程序都在 target/<profile>/deps 下
+fn cdylib_dir() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let deps = exe.parent().unwrap();
+    for dir in [deps, deps.parent().unwrap()] {
+        if dir.join("libmini_bitcask_rs3.so").exists() || dir.join("libmini_bitcask_rs3.dylib").exists() {
+            return Ok(dir.to_path_buf());
+        }
+    }
+    bail!("cdylib not found next to {}", exe.display())
+}
+
+/// include/bitcask.h 是提交的，改了 src/ffi.rs 之后要用生成的头文件覆盖它
+#[test]
+fn test_header_up_to_date() -> Result<()> {
+    let generated = Path::new(env!("OUT_DIR")).join("bitcask.h");
+    let committed = Path::new(env!("CARGO_MANIFEST_DIR")).join("include/bitcask.h");
+    if std::fs::read_to_string(&generated)? != std::fs::read_to_string(&committed)? {
+        bail!("{} is out of date, copy {} over it", committed.display(), generated.display());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_c_program() -> Result<()> {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = cdylib_dir()?;
+    let tmp_dir = tempfile::TempDir::new()?;
+    let exe = tmp_dir.path().join("ffi_test");
+
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let output = Command::new(cc)
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest.join("include"))
+        .arg(manifest.join("tests/c/ffi_test.c"))
+        .arg("-o")
+        .arg(&exe)
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lmini_bitcask_rs3")
+        .output()?;
+    if !output.status.success() {
+        bail!("cc failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    let output = Command::new(&exe).arg(tmp_dir.path().join("c.db")).output()?;
+    if !output.status.success() {
+        bail!("ffi_test failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(())
+}
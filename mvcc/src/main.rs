@@ -1,5 +1,4 @@
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     sync::{
@@ -43,20 +42,50 @@ impl MVCC {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 struct Key {
     raw_key: Vec<u8>,
     version: u64,
 }
 
 impl Key {
+    // raw_key 里的 0x00 转义成 0x00 0xff，以 0x00 0x00 结尾，后面跟大端的 version，
+    // 这样按字节排序就是先按 raw_key、再按 version 排序，不会被长度前缀和小端打乱
     fn encode(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+        let mut buf = Vec::with_capacity(self.raw_key.len() + 10);
+        for &b in &self.raw_key {
+            buf.push(b);
+            if b == 0 {
+                buf.push(0xff);
+            }
+        }
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf
     }
 }
 
-fn decode_key(b: &Vec<u8>) -> Key {
-    bincode::deserialize(&b).unwrap()
+// 解码 Key::encode 的结果，转义不对或者长度不对时返回 None
+fn decode_key(b: &[u8]) -> Option<Key> {
+    let mut raw_key = Vec::new();
+    let mut i = 0;
+    loop {
+        match (*b.get(i)?, b.get(i + 1)) {
+            (0, Some(0)) => break,
+            // 转义过的 0x00 占两个字节
+            (0, Some(0xff)) => {
+                raw_key.push(0);
+                i += 2;
+            }
+            (0, _) => return None,
+            (byte, _) => {
+                raw_key.push(byte);
+                i += 1;
+            }
+        }
+    }
+    let version = u64::from_be_bytes(b.get(i + 2..)?.try_into().ok()?);
+    Some(Key { raw_key, version })
 }
 
 // MVCC 事务
@@ -105,7 +134,9 @@ impl Transaction {
         // key 是按照 key-version 排序的，所以只需要判断最近的一个 key 即可
         let mut kvengine = self.kv.lock().unwrap();
         for (enc_key, _) in kvengine.iter().rev() {
-            let key_version = decode_key(enc_key);
+            let Some(key_version) = decode_key(enc_key) else {
+                continue;
+            };
             if key_version.raw_key.eq(key) {
                 if !self.is_visible(key_version.version) {
                     panic!("serialization error, try again.");
@@ -133,7 +164,9 @@ impl Transaction {
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         let kvengine = self.kv.lock().unwrap();
         for (k, v) in kvengine.iter().rev() {
-            let key_version = decode_key(k);
+            let Some(key_version) = decode_key(k) else {
+                continue;
+            };
             if key_version.raw_key.eq(key) && self.is_visible(key_version.version) {
                 return v.clone();
             }
@@ -146,7 +179,9 @@ impl Transaction {
         let mut records = BTreeMap::new();
         let kvengine = self.kv.lock().unwrap();
         for (k, v) in kvengine.iter() {
-            let key_version = decode_key(k);
+            let Some(key_version) = decode_key(k) else {
+                continue;
+            };
             if self.is_visible(key_version.version) {
                 records.insert(key_version.raw_key.to_vec(), v.clone());
             }